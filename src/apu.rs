@@ -0,0 +1,455 @@
+use crate::audio::AudioSink;
+use crate::cpu::CLOCK_HZ;
+use std::io;
+
+/// Start of the APU registers: `CHANNELS` channels, `CHANNEL_SIZE` bytes each. Channels 0 and 1
+/// are square waves, channel 2 is a triangle wave and channel 3 is noise.
+pub const APU_ADDR: u16 = 0x0110;
+/// How many channels there are
+pub const CHANNELS: usize = 4;
+/// How many registers each channel has
+pub const CHANNEL_SIZE: u16 = 4;
+
+/// Offset of a channel's frequency in Hz, as Big Endian. For the noise channel, this is how
+/// often a new random value is picked.
+pub const FREQUENCY: u16 = 0;
+/// Offset of a channel's volume: the low nibble is the starting volume, and the high nibble is
+/// how many 1/64ths of a second it takes for the volume to decay by 1 (0 keeps it constant)
+pub const VOLUME: u16 = 2;
+/// Offset of a channel's control flags, see the `CONTROL_*` constants
+pub const CONTROL: u16 = 3;
+
+/// Restarts the channel's envelope and waveform. Cleared by the APU once it has done so.
+pub const CONTROL_TRIGGER: u8 = 0b1000_0000;
+/// Makes the channel audible
+pub const CONTROL_ENABLE: u8 = 0b0100_0000;
+/// Selects the duty cycle of a square channel, see `DUTY_CYCLES`
+pub const CONTROL_DUTY: u8 = 0b0000_0011;
+
+/// How long a square wave stays high for each duty setting, in eighths of a period
+pub const DUTY_CYCLES: [u32; 4] = [1, 2, 4, 6];
+
+/// How many samples are generated every second
+pub const SAMPLE_RATE: u32 = 44_100;
+/// How many times a second envelopes are updated
+pub const ENVELOPE_HZ: u32 = 64;
+/// How many samples are collected before they are handed to the sink
+pub const SINK_BATCH_SIZE: usize = 1024;
+/// Without a sink, how many samples are kept around before the oldest ones are dropped
+pub const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Channel {
+    /// How far through the current period we are, out of `SAMPLE_RATE`
+    phase: u32,
+    /// The current volume, after the envelope has been applied
+    volume: u8,
+    /// How many envelope ticks are left until the volume decays
+    envelope_ticks: u8,
+    /// The noise channel's linear-feedback shift register
+    lfsr: u16,
+}
+
+/// The parts of the APU that change as it runs, for snapshots
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApuState {
+    channels: [Channel; CHANNELS],
+    sample_clock: u64,
+    envelope_clock: u32,
+}
+
+#[derive(Debug)]
+pub struct APU {
+    channels: [Channel; CHANNELS],
+    /// Cycles not yet turned into a sample, multiplied by `SAMPLE_RATE`
+    sample_clock: u64,
+    /// Samples not yet turned into an envelope tick, multiplied by `ENVELOPE_HZ`
+    envelope_clock: u32,
+    /// Generated samples that haven't been taken or handed to the sink yet
+    pub samples: Vec<i16>,
+    /// Where samples go once generated, if anywhere
+    pub sink: Option<Box<dyn AudioSink>>,
+}
+
+impl ApuState {
+    /// How many bytes the state takes up in a save state
+    pub const SIZE: usize = 12 + CHANNELS * 8;
+
+    /// The state as Big Endian bytes, for save states
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.sample_clock.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.envelope_clock.to_be_bytes());
+
+        for (channel, bytes) in self.channels.iter().zip(bytes[12..].chunks_mut(8)) {
+            bytes[0..4].copy_from_slice(&channel.phase.to_be_bytes());
+            bytes[4] = channel.volume;
+            bytes[5] = channel.envelope_ticks;
+            bytes[6..8].copy_from_slice(&channel.lfsr.to_be_bytes());
+        }
+
+        bytes
+    }
+
+    /// Reads the state back from `to_bytes`
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut channels = [Channel::default(); CHANNELS];
+        for (channel, bytes) in channels.iter_mut().zip(bytes[12..].chunks(8)) {
+            *channel = Channel {
+                phase: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                volume: bytes[4],
+                envelope_ticks: bytes[5],
+                lfsr: u16::from_be_bytes([bytes[6], bytes[7]]),
+            };
+        }
+
+        let mut sample_clock = [0; 8];
+        sample_clock.copy_from_slice(&bytes[0..8]);
+
+        Self {
+            channels,
+            sample_clock: u64::from_be_bytes(sample_clock),
+            envelope_clock: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        }
+    }
+}
+
+impl APU {
+    pub fn new() -> Self {
+        Self {
+            channels: [Channel {
+                lfsr: 1,
+                ..Channel::default()
+            }; CHANNELS],
+            sample_clock: 0,
+            envelope_clock: 0,
+            samples: Vec::new(),
+            sink: None,
+        }
+    }
+
+    pub fn state(&self) -> ApuState {
+        ApuState {
+            channels: self.channels,
+            sample_clock: self.sample_clock,
+            envelope_clock: self.envelope_clock,
+        }
+    }
+
+    /// Puts the APU back into `state`. Samples already generated are left alone.
+    pub fn restore(&mut self, state: &ApuState) {
+        self.channels = state.channels;
+        self.sample_clock = state.sample_clock;
+        self.envelope_clock = state.envelope_clock;
+    }
+
+    /// Advances the APU by `cycles` CPU cycles, generating however many samples fit in them
+    pub fn step(&mut self, memory: &mut [u8; 0xFFFF], cycles: u32) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            let control = &mut memory[register(i, CONTROL)];
+
+            if *control & CONTROL_TRIGGER != 0 {
+                *control &= !CONTROL_TRIGGER;
+
+                let volume = memory[register(i, VOLUME)];
+                channel.phase = 0;
+                channel.volume = volume & 0x0F;
+                channel.envelope_ticks = volume >> 4;
+                channel.lfsr = 1;
+            }
+        }
+
+        self.sample_clock += cycles as u64 * SAMPLE_RATE as u64;
+        while self.sample_clock >= CLOCK_HZ as u64 {
+            self.sample_clock -= CLOCK_HZ as u64;
+
+            self.envelope_clock += ENVELOPE_HZ;
+            if self.envelope_clock >= SAMPLE_RATE {
+                self.envelope_clock -= SAMPLE_RATE;
+                self.tick_envelopes(memory);
+            }
+
+            let sample = self.sample(memory);
+            self.samples.push(sample);
+        }
+
+        if self.sink.is_some() {
+            if self.samples.len() >= SINK_BATCH_SIZE {
+                self.flush_sink();
+            }
+        } else if self.samples.len() > MAX_BUFFERED_SAMPLES {
+            let excess = self.samples.len() - MAX_BUFFERED_SAMPLES;
+            self.samples.drain(..excess);
+        }
+    }
+
+    /// Takes every sample generated so far
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Hands every sample generated so far to the sink. If that fails, the sink is dropped.
+    fn flush_sink(&mut self) {
+        if let Some(sink) = &mut self.sink {
+            if let Err(e) = sink.write(&self.samples) {
+                eprintln!("Could not write audio, dropping the audio output: {}", e);
+                self.sink = None;
+            }
+        }
+
+        self.samples.clear();
+    }
+
+    /// Hands any remaining samples to the sink and finishes it off
+    pub fn close_sink(&mut self) -> io::Result<()> {
+        match self.sink.take() {
+            Some(mut sink) => {
+                sink.write(&self.take_samples())?;
+                sink.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn tick_envelopes(&mut self, memory: &[u8; 0xFFFF]) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            let volume = memory[register(i, VOLUME)];
+            let period = volume >> 4;
+
+            if period == 0 {
+                channel.volume = volume & 0x0F;
+            } else if channel.envelope_ticks > 1 {
+                channel.envelope_ticks -= 1;
+            } else {
+                channel.envelope_ticks = period;
+                channel.volume = channel.volume.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Generates the next sample by mixing every channel together
+    fn sample(&mut self, memory: &[u8; 0xFFFF]) -> i16 {
+        let mut mix = 0;
+
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            let frequency = u16::from_be_bytes([
+                memory[register(i, FREQUENCY)],
+                memory[register(i, FREQUENCY) + 1],
+            ]) as u32;
+            let control = memory[register(i, CONTROL)];
+
+            if control & CONTROL_ENABLE == 0 {
+                continue;
+            }
+
+            // Between -15 and 15, before the volume is applied
+            let level = match i {
+                0 | 1 => {
+                    let duty = DUTY_CYCLES[(control & CONTROL_DUTY) as usize];
+                    if channel.phase < SAMPLE_RATE / 8 * duty {
+                        15
+                    } else {
+                        -15
+                    }
+                }
+                2 => {
+                    let step = (channel.phase as u64 * 32 / SAMPLE_RATE as u64) as i32;
+                    let height = if step < 16 { step } else { 31 - step };
+                    height * 2 - 15
+                }
+                _ => {
+                    if channel.lfsr & 1 == 1 {
+                        15
+                    } else {
+                        -15
+                    }
+                }
+            };
+            mix += level * channel.volume as i32 / 15;
+
+            channel.phase += frequency;
+            while channel.phase >= SAMPLE_RATE {
+                channel.phase -= SAMPLE_RATE;
+
+                // The same 15-bit shift register the NES uses
+                let feedback = (channel.lfsr ^ (channel.lfsr >> 1)) & 1;
+                channel.lfsr = (channel.lfsr >> 1) | (feedback << 14);
+            }
+        }
+
+        (mix * i16::MAX as i32 / (15 * CHANNELS as i32)) as i16
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where register `offset` of channel `channel` is in memory
+fn register(channel: usize, offset: u16) -> usize {
+    (APU_ADDR + channel as u16 * CHANNEL_SIZE + offset) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::WavSink;
+    use crate::cpu::CPU;
+    use crate::golden::assert_bytes_match;
+    use std::env;
+    use std::fs;
+
+    /// How many cycles it takes to generate `samples` samples
+    fn cycles_for(samples: u32) -> u32 {
+        (samples as u64 * CLOCK_HZ as u64 / SAMPLE_RATE as u64) as u32 + 1
+    }
+
+    fn set_channel(
+        memory: &mut [u8; 0xFFFF],
+        channel: usize,
+        frequency: u16,
+        volume: u8,
+        control: u8,
+    ) {
+        memory[register(channel, FREQUENCY)] = (frequency >> 8) as u8;
+        memory[register(channel, FREQUENCY) + 1] = frequency as u8;
+        memory[register(channel, VOLUME)] = volume;
+        memory[register(channel, CONTROL)] = control;
+    }
+
+    #[test]
+    fn test_silent_when_disabled() {
+        let mut apu = APU::new();
+        let mut memory = [0; 0xFFFF];
+
+        set_channel(&mut memory, 0, 441, 0x0F, CONTROL_TRIGGER);
+        apu.step(&mut memory, cycles_for(100));
+
+        assert!(apu.samples.len() >= 100);
+        assert!(apu.samples.iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn test_trigger_is_cleared() {
+        let mut apu = APU::new();
+        let mut memory = [0; 0xFFFF];
+
+        set_channel(&mut memory, 0, 441, 0x0F, CONTROL_TRIGGER | CONTROL_ENABLE);
+        apu.step(&mut memory, 1);
+
+        assert_eq!(memory[register(0, CONTROL)], CONTROL_ENABLE);
+    }
+
+    #[test]
+    fn test_square_wave() {
+        let mut apu = APU::new();
+        let mut memory = [0; 0xFFFF];
+
+        // 441Hz is exactly 100 samples per period, and duty 2 keeps it high for half of that
+        set_channel(
+            &mut memory,
+            0,
+            441,
+            0x0F,
+            CONTROL_TRIGGER | CONTROL_ENABLE | 2,
+        );
+        apu.step(&mut memory, cycles_for(200));
+
+        let samples = apu.take_samples();
+        assert!(samples[0..50].iter().all(|&sample| sample > 0));
+        assert!(samples[50..100].iter().all(|&sample| sample < 0));
+        assert_eq!(samples[0..100], samples[100..200]);
+        assert!(apu.samples.is_empty());
+    }
+
+    #[test]
+    fn test_triangle_wave() {
+        let mut apu = APU::new();
+        let mut memory = [0; 0xFFFF];
+
+        set_channel(&mut memory, 2, 441, 0x0F, CONTROL_TRIGGER | CONTROL_ENABLE);
+        apu.step(&mut memory, cycles_for(100));
+
+        // It rises over the first half of the period and falls over the second
+        let samples = apu.take_samples();
+        assert!(samples[0..50].windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(samples[50..100].windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(samples[0] < 0 && samples[49] > 0);
+    }
+
+    #[test]
+    fn test_noise_is_deterministic() {
+        let mut first = APU::new();
+        let mut second = APU::new();
+        let mut memory = [0; 0xFFFF];
+
+        set_channel(&mut memory, 3, 4000, 0x0F, CONTROL_TRIGGER | CONTROL_ENABLE);
+        let mut other_memory = memory;
+
+        first.step(&mut memory, cycles_for(1000));
+        second.step(&mut other_memory, cycles_for(1000));
+
+        assert_eq!(first.samples, second.samples);
+        assert!(first.samples.iter().any(|&sample| sample > 0));
+        assert!(first.samples.iter().any(|&sample| sample < 0));
+    }
+
+    #[test]
+    fn test_envelope_decays() {
+        let mut apu = APU::new();
+        let mut memory = [0; 0xFFFF];
+
+        // Decays by 1 every 64th of a second, so it's silent after 15/64ths of a second
+        set_channel(
+            &mut memory,
+            0,
+            441,
+            0x1F,
+            CONTROL_TRIGGER | CONTROL_ENABLE | 2,
+        );
+        apu.step(&mut memory, CLOCK_HZ / 4);
+
+        let samples = apu.take_samples();
+        assert!(samples[0] > samples[SAMPLE_RATE as usize / 8]);
+        assert!(samples[samples.len() - 100..]
+            .iter()
+            .all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn golden_wav() {
+        let mut cpu = CPU::headless();
+        let program = vec![
+            0x10, 0x00, 0x01, // Play a 440Hz square wave on channel 0...
+            0x20, 0x01, 0x10, 0x00, //
+            0x10, 0x00, 0xB8, //
+            0x20, 0x01, 0x11, 0x00, //
+            0x10, 0x00, 0x2C, // ...that fades out...
+            0x20, 0x01, 0x12, 0x00, //
+            0x10, 0x00, 0xC1, // ...with a 25% duty cycle
+            0x20, 0x01, 0x13, 0x00, //
+            0x10, 0x00, 0x10, // And 4096Hz noise on channel 3
+            0x20, 0x01, 0x1C, 0x00, //
+            0x10, 0x00, 0x08, //
+            0x20, 0x01, 0x1E, 0x00, //
+            0x10, 0x00, 0xC0, //
+            0x20, 0x01, 0x1F, 0x00, //
+            0x10, 0x03, 0x01, // Then spin forever
+            0x40, 0x03, 0x80, 0x34,
+        ];
+
+        let path = env::temp_dir().join(format!("maxemu-golden-{}.wav", std::process::id()));
+        cpu.apu.sink = Some(Box::new(WavSink::create(&path).unwrap()));
+
+        cpu.load(program);
+        cpu.run_frames(10);
+        cpu.apu.close_sink().unwrap();
+
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_bytes_match("square_and_noise.wav", &wav);
+    }
+}
@@ -0,0 +1,1363 @@
+use crate::expr::{self, Expr};
+use crate::isa::{InstructionInfo, OperandKind, INSTRUCTIONS};
+use crate::lines::SourceLine;
+use crate::link::{link, Image, MemoryMap};
+use crate::object::{LineEntry, Object, Relocation, RelocationKind, Section, Symbol, Target};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Where assembled programs start, matching where `CPU::load` puts them
+pub const ORIGIN: u16 = 0x8000;
+
+/// How many constants can refer to each other in a chain before we give up
+const MAX_SYMBOL_DEPTH: usize = 64;
+
+/// How deeply includes and macros can nest before we assume one is including or using itself
+const MAX_NESTING: usize = 32;
+
+/// Something wrong with the source, and where it is
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    /// The file the error is in, or `None` for source passed straight to `assemble` and for
+    /// errors found while linking that don't come from any one line
+    pub file: Option<PathBuf>,
+    /// The line the error is on, starting at 1, or 0 if it isn't on any line
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) if self.line == 0 => write!(f, "{}: {}", file.display(), self.message),
+            Some(file) => write!(f, "{}:{}: {}", file.display(), self.line, self.message),
+            None if self.line == 0 => write!(f, "{}", self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+/// Where a line of source came from
+#[derive(Debug, Clone)]
+struct Location {
+    file: Option<Rc<Path>>,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            file: self.file.as_ref().map(|file| file.to_path_buf()),
+            line: self.line,
+            message,
+        }
+    }
+
+    /// Finds `path` relative to the file this line is in
+    fn relative(&self, path: &str) -> PathBuf {
+        match self.file.as_ref().and_then(|file| file.parent()) {
+            Some(directory) => directory.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Reg(u8),
+    Value(Expr),
+    Mem(Expr),
+}
+
+impl Operand {
+    /// Whether the operand can be used where `kind` is expected. An address can also be written
+    /// in brackets, so `STORE [0x0200], A` works as well as `STORE 0x0200, A`.
+    fn matches(&self, kind: OperandKind) -> bool {
+        matches!(
+            (self, kind),
+            (Operand::Reg(_), OperandKind::Reg)
+                | (Operand::Value(_), OperandKind::Imm)
+                | (Operand::Value(_), OperandKind::Addr)
+                | (Operand::Mem(_), OperandKind::Addr)
+                | (Operand::Mem(_), OperandKind::Mem)
+        )
+    }
+}
+
+/// Something waiting for the second pass to encode it
+enum Item {
+    Instruction(&'static InstructionInfo, Vec<Operand>),
+    /// `.byte`
+    Bytes(Vec<Expr>),
+    /// `.word`, stored as Big Endian
+    Words(Vec<Expr>),
+    /// `.ascii` and `.incbin`, which are already bytes
+    Data(Vec<u8>),
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(Location, String)>,
+}
+
+/// A `.if` we're inside of
+struct Condition {
+    location: Location,
+    /// Whether everything around the `.if` is being assembled
+    enclosing: bool,
+    /// Whether the condition was true
+    taken: bool,
+    in_else: bool,
+}
+
+impl Condition {
+    fn active(&self) -> bool {
+        self.enclosing && self.taken != self.in_else
+    }
+}
+
+/// What a name stands for
+enum Definition {
+    /// A label, as a section and an offset into it
+    Label(usize, usize),
+    Constant(Expr),
+}
+
+/// What an expression works out to, before linking
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Absolute(i64),
+    /// An offset from the start of a section
+    Relative(usize, i64),
+    /// An offset from a symbol that isn't defined here, which the linker will look for
+    External(String, i64),
+}
+
+struct SectionState {
+    name: String,
+    /// Where the section starts, if it's known before linking
+    address: Option<u16>,
+    size: usize,
+}
+
+#[derive(Default)]
+struct Assembler {
+    symbols: HashMap<String, Definition>,
+    /// Names made visible to other objects by `.global`
+    globals: Vec<(String, Location)>,
+    macros: HashMap<String, Macro>,
+    /// The macro being defined, from its `.macro` until its `.endm`
+    recording: Option<(String, Location, Macro)>,
+    conditions: Vec<Condition>,
+    sections: Vec<SectionState>,
+    /// The section being assembled into
+    section: usize,
+    /// Everything to encode, with the section and offset it goes at
+    items: Vec<(Location, usize, usize, Item)>,
+    /// How many includes and macros we're inside of
+    depth: usize,
+    /// How many macros have been used, to give each use its own `\@`
+    expansions: usize,
+}
+
+/// Assembles `source` into a program to be loaded at `ORIGIN`.
+///
+/// Each line holds an optional `label:`, then either an instruction like `LOAD A, 0xFF`, a
+/// constant like `SCREEN = 0x0200`, a directive or a macro. Anything after a `;` is a comment.
+/// Memory operands are written in brackets, as in `LOAD A, [0x0100]`, to tell them apart from
+/// immediate values. Operands can be expressions, as described in `expr`.
+///
+/// The directives are:
+///
+/// - `.include "file"` assembles another file in place
+/// - `.macro name param, ...` up to `.endm` defines a macro, used as `name arg, ...`. Its body
+///   refers to parameters as `\param`, and `\@` is a number unique to each use, for labels.
+/// - `.if expr`, `.else` and `.endif` assemble lines only if `expr` isn't 0
+/// - `.org address` moves to a later address, filling the gap with zeros
+/// - `.byte expr, ...` and `.word expr, ...` write 8- and 16-bit (Big Endian) values
+/// - `.ascii "text", ...` writes strings, without any terminator
+/// - `.incbin "file"` writes the contents of a file
+///
+/// Files are found relative to the file that names them, or the current directory for
+/// `source` itself.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    Ok(assemble_image(source)?.data)
+}
+
+/// Assembles the file at `path`, as `assemble` does
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    Ok(assemble_image_file(path)?.data)
+}
+
+/// Assembles `source` as `assemble` does, keeping the symbol and line maps along with the program
+pub fn assemble_image(source: &str) -> Result<Image, AsmError> {
+    let mut assembler = Assembler::new(Some(ORIGIN));
+    assembler.source(source, None)?;
+
+    link(&[assembler.finish()?], &MemoryMap::default())
+}
+
+/// Assembles the file at `path`, as `assemble_image` does
+pub fn assemble_image_file(path: &Path) -> Result<Image, AsmError> {
+    let mut assembler = Assembler::new(Some(ORIGIN));
+    assembler.source(&read_source(path)?, Some(Rc::from(path)))?;
+
+    link(&[assembler.finish()?], &MemoryMap::default())
+}
+
+/// Assembles `source` into an object, to be linked with others by `link::link`.
+///
+/// This works like `assemble`, except that nothing is at a fixed address, so `.org` can't be
+/// used. Instead, `.section name` switches to assembling into another section (everything starts
+/// in `text`), and the linker places each section according to its memory map. Symbols that
+/// aren't defined are looked for in other objects, which can make their symbols visible with
+/// `.global name, ...`.
+pub fn assemble_object(source: &str) -> Result<Object, AsmError> {
+    let mut assembler = Assembler::new(None);
+    assembler.source(source, None)?;
+    assembler.finish()
+}
+
+/// Assembles the file at `path`, as `assemble_object` does
+pub fn assemble_object_file(path: &Path) -> Result<Object, AsmError> {
+    let mut assembler = Assembler::new(None);
+    assembler.source(&read_source(path)?, Some(Rc::from(path)))?;
+    assembler.finish()
+}
+
+fn read_source(path: &Path) -> Result<String, AsmError> {
+    fs::read_to_string(path).map_err(|e| AsmError {
+        file: Some(path.to_path_buf()),
+        line: 0,
+        message: e.to_string(),
+    })
+}
+
+impl Assembler {
+    /// Makes an assembler that starts in `text`, which is at `address` if that's known
+    fn new(address: Option<u16>) -> Self {
+        Self {
+            sections: vec![SectionState {
+                name: "text".to_string(),
+                address,
+                size: 0,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// First pass: works out where every label is, which only needs the size of everything
+    fn source(&mut self, source: &str, file: Option<Rc<Path>>) -> Result<(), AsmError> {
+        let conditions = self.conditions.len();
+
+        for (i, text) in source.lines().enumerate() {
+            let location = Location {
+                file: file.clone(),
+                line: i + 1,
+            };
+
+            self.line(&location, text)?;
+        }
+
+        if let Some((name, location, _)) = &self.recording {
+            return Err(location.error(format!("`{}` is missing its `.endm`", name)));
+        }
+        if self.conditions.len() > conditions {
+            let condition = self.conditions.pop().unwrap();
+            return Err(condition
+                .location
+                .error("`.if` is missing its `.endif`".to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn line(&mut self, location: &Location, text: &str) -> Result<(), AsmError> {
+        let error = |message: String| location.error(message);
+
+        let mut text = strip_comment(text).trim();
+        let (directive, rest) = split_first_word(text);
+        let directive = directive.to_lowercase();
+
+        if let Some((_, _, definition)) = &mut self.recording {
+            match directive.as_str() {
+                ".endm" => {
+                    let (name, _, definition) = self.recording.take().unwrap();
+                    self.macros.insert(name, definition);
+                }
+                ".macro" => return Err(error("macros can't be defined inside macros".to_string())),
+                _ => definition.body.push((location.clone(), text.to_string())),
+            }
+
+            return Ok(());
+        }
+
+        // Conditionals have to be followed even when we're skipping lines, to find where the
+        // skipping ends
+        let active = !matches!(self.conditions.last(), Some(condition) if !condition.active());
+        match directive.as_str() {
+            ".if" => {
+                let taken = active && self.constant(rest).map_err(error)? != 0;
+                self.conditions.push(Condition {
+                    location: location.clone(),
+                    enclosing: active,
+                    taken,
+                    in_else: false,
+                });
+                return Ok(());
+            }
+            ".else" => {
+                return match self.conditions.last_mut() {
+                    Some(condition) if !condition.in_else => {
+                        condition.in_else = true;
+                        Ok(())
+                    }
+                    _ => Err(error("`.else` without `.if`".to_string())),
+                };
+            }
+            ".endif" => {
+                return match self.conditions.pop() {
+                    Some(_) => Ok(()),
+                    None => Err(error("`.endif` without `.if`".to_string())),
+                };
+            }
+            _ if !active => return Ok(()),
+            _ => (),
+        }
+
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_identifier(label) {
+                break;
+            }
+
+            let offset = self.sections[self.section].size;
+            self.define(label, Definition::Label(self.section, offset))
+                .map_err(error)?;
+            text = text[colon + 1..].trim();
+        }
+
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let (word, rest) = split_first_word(text);
+        if word.starts_with('.') {
+            return self.directive(location, &word.to_lowercase(), rest);
+        }
+
+        if let Some(equals) = text.find('=') {
+            let name = text[..equals].trim();
+
+            // Anything else with an `=` in it could be an instruction comparing things
+            if is_identifier(name) && !text[equals + 1..].starts_with('=') {
+                let value = expr::parse(text[equals + 1..].trim()).map_err(error)?;
+                return self
+                    .define(name, Definition::Constant(value))
+                    .map_err(error);
+            }
+        }
+
+        let args = split_args(rest);
+
+        if self.macros.contains_key(word) {
+            return self.expand(location, word, &args);
+        }
+
+        let mnemonic = word.to_uppercase();
+        let operands = args
+            .iter()
+            .map(|operand| parse_operand(operand))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(error)?;
+
+        let info = INSTRUCTIONS
+            .iter()
+            .find(|info| {
+                info.mnemonic == mnemonic
+                    && info.operands.len() == operands.len()
+                    && operands
+                        .iter()
+                        .zip(info.operands.iter())
+                        .all(|(o, &k)| o.matches(k))
+            })
+            .ok_or_else(|| {
+                if INSTRUCTIONS.iter().any(|info| info.mnemonic == mnemonic) {
+                    error(format!("invalid operands for {}", mnemonic))
+                } else {
+                    error(format!("unknown instruction `{}`", mnemonic))
+                }
+            })?;
+
+        self.emit(location, info.size(), Item::Instruction(info, operands))
+    }
+
+    fn directive(&mut self, location: &Location, name: &str, rest: &str) -> Result<(), AsmError> {
+        let error = |message: String| location.error(message);
+        let args = split_args(rest);
+
+        match name {
+            ".include" => {
+                let path = location.relative(&parse_path(rest).map_err(error)?);
+                let source = fs::read_to_string(&path)
+                    .map_err(|e| error(format!("can't read `{}`: {}", path.display(), e)))?;
+
+                self.nest(location)?;
+                self.source(&source, Some(Rc::from(path)))?;
+                self.depth -= 1;
+
+                Ok(())
+            }
+            ".macro" => {
+                let (name, params) = split_first_word(rest);
+                let name = name.trim_end_matches(',');
+                let params = split_args(params);
+
+                if !is_identifier(name) {
+                    return Err(error("`.macro` needs a name".to_string()));
+                }
+                if let Some(param) = params.iter().find(|param| !is_identifier(param)) {
+                    return Err(error(format!("invalid parameter name `{}`", param)));
+                }
+                if self.macros.contains_key(name) {
+                    return Err(error(format!("`{}` is already defined", name)));
+                }
+
+                let definition = Macro {
+                    params: params.iter().map(|param| param.to_string()).collect(),
+                    body: Vec::new(),
+                };
+                self.recording = Some((name.to_string(), location.clone(), definition));
+
+                Ok(())
+            }
+            ".endm" => Err(error("`.endm` without `.macro`".to_string())),
+            ".org" => {
+                let address = self.constant(rest).map_err(error)?;
+                let section = &mut self.sections[self.section];
+                let start =
+                    match section.address {
+                        Some(start) => start as i64,
+                        None => return Err(error(
+                            "`.org` can't be used in an object; place sections with a memory map"
+                                .to_string(),
+                        )),
+                    };
+
+                if address < start + section.size as i64 {
+                    return Err(error(format!(
+                        "`.org` can't move back from {:#06x} to {:#06x}",
+                        start + section.size as i64,
+                        address
+                    )));
+                }
+                if address > 0xFFFF {
+                    return Err(error(format!("{:#x} is not a valid address", address)));
+                }
+
+                section.size = (address - start) as usize;
+                Ok(())
+            }
+            ".section" => {
+                if !is_identifier(rest) {
+                    return Err(error("`.section` needs a name".to_string()));
+                }
+
+                self.section = match self.sections.iter().position(|s| s.name == rest) {
+                    Some(section) => section,
+                    None => {
+                        self.sections.push(SectionState {
+                            name: rest.to_string(),
+                            address: None,
+                            size: 0,
+                        });
+                        self.sections.len() - 1
+                    }
+                };
+                Ok(())
+            }
+            ".global" => {
+                for name in args {
+                    if !is_identifier(name) {
+                        return Err(error(format!("invalid symbol name `{}`", name)));
+                    }
+
+                    self.globals.push((name.to_string(), location.clone()));
+                }
+                Ok(())
+            }
+            ".byte" | ".word" => {
+                let values = args
+                    .iter()
+                    .map(|arg| expr::parse(arg))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(error)?;
+
+                if name == ".byte" {
+                    self.emit(location, values.len(), Item::Bytes(values))
+                } else {
+                    self.emit(location, values.len() * 2, Item::Words(values))
+                }
+            }
+            ".ascii" => {
+                let mut data = Vec::new();
+                for arg in args {
+                    data.extend(parse_string(arg).map_err(error)?);
+                }
+
+                self.emit(location, data.len(), Item::Data(data))
+            }
+            ".incbin" => {
+                let path = location.relative(&parse_path(rest).map_err(error)?);
+                let data = fs::read(&path)
+                    .map_err(|e| error(format!("can't read `{}`: {}", path.display(), e)))?;
+
+                self.emit(location, data.len(), Item::Data(data))
+            }
+            _ => Err(error(format!("unknown directive `{}`", name))),
+        }
+    }
+
+    /// Assembles the body of the macro `name` in place, with `args` for its parameters
+    fn expand(&mut self, location: &Location, name: &str, args: &[&str]) -> Result<(), AsmError> {
+        let definition = &self.macros[name];
+        if args.len() != definition.params.len() {
+            return Err(location.error(format!(
+                "`{}` takes {} arguments, not {}",
+                name,
+                definition.params.len(),
+                args.len()
+            )));
+        }
+
+        self.expansions += 1;
+        let unique = self.expansions.to_string();
+        let body = definition
+            .body
+            .iter()
+            .map(|(location, text)| {
+                let text = substitute(text, |param| {
+                    if param == "@" {
+                        return Some(unique.as_str());
+                    }
+
+                    let index = definition.params.iter().position(|p| p == param)?;
+                    Some(args[index])
+                });
+
+                (location.clone(), text)
+            })
+            .collect::<Vec<_>>();
+
+        self.nest(location)?;
+        let conditions = self.conditions.len();
+        for (location, text) in body {
+            self.line(&location, &text)?;
+        }
+        if self.conditions.len() > conditions {
+            let condition = self.conditions.pop().unwrap();
+            return Err(condition
+                .location
+                .error("`.if` is missing its `.endif`".to_string()));
+        }
+        self.depth -= 1;
+
+        Ok(())
+    }
+
+    /// Goes one include or macro deeper
+    fn nest(&mut self, location: &Location) -> Result<(), AsmError> {
+        self.depth += 1;
+
+        if self.depth > MAX_NESTING {
+            return Err(location.error("includes or macros are nested too deeply".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Adds `item`, which takes up `size` bytes, at the current address
+    fn emit(&mut self, location: &Location, size: usize, item: Item) -> Result<(), AsmError> {
+        let section = &mut self.sections[self.section];
+        self.items
+            .push((location.clone(), self.section, section.size, item));
+
+        section.size += size;
+        if section.address.unwrap_or(0) as usize + section.size > 0xFFFF {
+            return Err(location.error("program does not fit in memory".to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, definition: Definition) -> Result<(), String> {
+        if register_index(name).is_some() {
+            return Err(format!("`{}` is a register", name));
+        }
+
+        if self.symbols.contains_key(name) || self.macros.contains_key(name) {
+            return Err(format!("`{}` is already defined", name));
+        }
+
+        self.symbols.insert(name.to_string(), definition);
+        Ok(())
+    }
+
+    /// Evaluates an expression that has to be known during the first pass, as in `.if` and `.org`
+    fn constant(&self, text: &str) -> Result<i64, String> {
+        match self.resolve(&expr::parse(text)?, 0)? {
+            Value::Absolute(value) => Ok(value),
+            Value::Relative(..) => Err("value isn't known until link time".to_string()),
+            Value::External(name, _) => Err(format!("undefined symbol `{}`", name)),
+        }
+    }
+
+    /// Works out as much of `value` as we can before linking. Labels in sections that haven't
+    /// been placed yet can only be added to or subtracted from.
+    fn resolve(&self, value: &Expr, depth: usize) -> Result<Value, String> {
+        let absolute = |value: Value| match value {
+            Value::Absolute(value) => Ok(value),
+            _ => Err("expression can't be worked out until link time".to_string()),
+        };
+        let evaluate = |expr: Expr| expr.eval(&mut |_| unreachable!());
+
+        Ok(match value {
+            Expr::Number(number) => Value::Absolute(*number),
+            Expr::Symbol(name) => {
+                if depth > MAX_SYMBOL_DEPTH {
+                    return Err(format!("`{}` is defined in terms of itself", name));
+                }
+
+                match self.symbols.get(name) {
+                    Some(Definition::Label(section, offset)) => {
+                        match self.sections[*section].address {
+                            Some(address) => Value::Absolute(address as i64 + *offset as i64),
+                            None => Value::Relative(*section, *offset as i64),
+                        }
+                    }
+                    Some(Definition::Constant(value)) => self.resolve(value, depth + 1)?,
+                    None => Value::External(name.clone(), 0),
+                }
+            }
+            Expr::Unary(operator, operand) => {
+                let operand = absolute(self.resolve(operand, depth)?)?;
+                Value::Absolute(evaluate(Expr::Unary(
+                    operator,
+                    Box::new(Expr::Number(operand)),
+                ))?)
+            }
+            Expr::Binary(operator, lhs, rhs) => {
+                let lhs = self.resolve(lhs, depth)?;
+                let rhs = self.resolve(rhs, depth)?;
+
+                match (*operator, lhs, rhs) {
+                    (_, Value::Absolute(lhs), Value::Absolute(rhs)) => {
+                        Value::Absolute(evaluate(Expr::Binary(
+                            operator,
+                            Box::new(Expr::Number(lhs)),
+                            Box::new(Expr::Number(rhs)),
+                        ))?)
+                    }
+                    ("+", Value::Relative(section, lhs), Value::Absolute(rhs))
+                    | ("+", Value::Absolute(rhs), Value::Relative(section, lhs)) => {
+                        Value::Relative(section, lhs + rhs)
+                    }
+                    ("-", Value::Relative(section, lhs), Value::Absolute(rhs)) => {
+                        Value::Relative(section, lhs - rhs)
+                    }
+                    ("+", Value::External(name, lhs), Value::Absolute(rhs))
+                    | ("+", Value::Absolute(rhs), Value::External(name, lhs)) => {
+                        Value::External(name, lhs + rhs)
+                    }
+                    ("-", Value::External(name, lhs), Value::Absolute(rhs)) => {
+                        Value::External(name, lhs - rhs)
+                    }
+                    // The distance between two labels in the same section is always the same
+                    ("-", Value::Relative(a, lhs), Value::Relative(b, rhs)) if a == b => {
+                        Value::Absolute(lhs - rhs)
+                    }
+                    _ => return Err("expression can't be worked out until link time".to_string()),
+                }
+            }
+        })
+    }
+
+    /// Second pass: now that every label is known, encodes everything. Anything that depends on
+    /// where sections end up is left as zeros, with a relocation to fill it in.
+    fn finish(self) -> Result<Object, AsmError> {
+        let mut object = Object {
+            sections: self
+                .sections
+                .iter()
+                .map(|section| Section {
+                    name: section.name.clone(),
+                    address: section.address,
+                    data: Vec::with_capacity(section.size),
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        for (location, section, offset, item) in &self.items {
+            let error = |message: String| location.error(message);
+            let data = &mut object.sections[*section].data;
+            let relocations = &mut object.relocations;
+
+            // Anything skipped over by `.org` is left as zeros
+            data.resize(*offset, 0);
+
+            // Writes `value` as `kind`, or leaves a relocation for it
+            let mut write = |data: &mut Vec<u8>, value: &Expr, kind| -> Result<(), AsmError> {
+                let (target, addend) = match self.resolve(value, 0).map_err(error)? {
+                    Value::Absolute(number) => {
+                        match kind {
+                            RelocationKind::Byte if (-128..=0xFF).contains(&number) => {
+                                data.push(number as u8)
+                            }
+                            RelocationKind::Byte => {
+                                return Err(error(format!("{} does not fit in 8 bits", number)))
+                            }
+                            RelocationKind::Word if (-0x8000..=0xFFFF).contains(&number) => {
+                                data.extend_from_slice(&(number as u16).to_be_bytes())
+                            }
+                            RelocationKind::Word => {
+                                return Err(error(format!("{} does not fit in 16 bits", number)))
+                            }
+                            // Addresses are Big Endian, as `mem_read_u16_be()` expects
+                            RelocationKind::Address if (0..=0xFFFF).contains(&number) => {
+                                data.extend_from_slice(&(number as u16).to_be_bytes())
+                            }
+                            RelocationKind::Address => {
+                                return Err(error(format!("{:#x} is not a valid address", number)))
+                            }
+                        }
+                        return Ok(());
+                    }
+                    Value::Relative(section, offset) => (Target::Section(section), offset),
+                    Value::External(name, offset) => (Target::Symbol(name), offset),
+                };
+
+                relocations.push(Relocation {
+                    section: *section,
+                    offset: data.len(),
+                    kind,
+                    target,
+                    addend,
+                    file: location.file.as_ref().map(|file| file.to_path_buf()),
+                    line: location.line,
+                });
+                data.resize(data.len() + kind.size(), 0);
+
+                Ok(())
+            };
+
+            match item {
+                Item::Instruction(info, operands) => {
+                    object.lines.push(LineEntry {
+                        section: *section,
+                        offset: *offset,
+                        source: SourceLine {
+                            file: location.file.as_ref().map(|file| file.to_path_buf()),
+                            line: location.line,
+                        },
+                    });
+                    data.push(info.opcode);
+
+                    for (operand, kind) in operands.iter().zip(info.operands) {
+                        match operand {
+                            Operand::Reg(index) => data.push(*index),
+                            Operand::Value(value) | Operand::Mem(value) => {
+                                let kind = match kind {
+                                    OperandKind::Imm => RelocationKind::Byte,
+                                    _ => RelocationKind::Address,
+                                };
+                                write(data, value, kind)?;
+                            }
+                        }
+                    }
+                }
+                Item::Bytes(values) => {
+                    for value in values {
+                        write(data, value, RelocationKind::Byte)?;
+                    }
+                }
+                Item::Words(values) => {
+                    for value in values {
+                        write(data, value, RelocationKind::Word)?;
+                    }
+                }
+                Item::Data(bytes) => data.extend_from_slice(bytes),
+            }
+        }
+
+        // A trailing `.org` still reserves the space before it
+        for (section, state) in object.sections.iter_mut().zip(&self.sections) {
+            section.data.resize(state.size, 0);
+        }
+
+        // Every label goes in the symbol table so it can be shown in the symbol map, but
+        // constants only need to be there if other objects can see them
+        let mut names = self.symbols.keys().collect::<Vec<_>>();
+        names.sort();
+
+        for name in names {
+            let global = self.globals.iter().any(|(global, _)| global == name);
+
+            let (section, value) = match &self.symbols[name] {
+                Definition::Label(section, offset) => (Some(*section), *offset as i64),
+                Definition::Constant(_) if !global => continue,
+                Definition::Constant(value) => {
+                    let location = &self.globals.iter().find(|(g, _)| g == name).unwrap().1;
+
+                    match self.resolve(value, 0) {
+                        Ok(Value::Absolute(value)) => (None, value),
+                        Ok(Value::Relative(section, offset)) => (Some(section), offset),
+                        _ => {
+                            return Err(location.error(format!("`{}` can't be made global", name)));
+                        }
+                    }
+                }
+            };
+
+            object.symbols.push(Symbol {
+                name: name.clone(),
+                section,
+                value,
+                global,
+            });
+        }
+
+        if let Some((name, location)) = self
+            .globals
+            .iter()
+            .find(|(name, _)| !self.symbols.contains_key(name))
+        {
+            return Err(location.error(format!("`{}` is global but never defined", name)));
+        }
+
+        Ok(object)
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn register_index(text: &str) -> Option<u8> {
+    match text.to_uppercase().as_str() {
+        "A" => Some(0),
+        "B" => Some(1),
+        "C" => Some(2),
+        "D" => Some(3),
+        _ => None,
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if let Some(index) = register_index(text) {
+        return Ok(Operand::Reg(index));
+    }
+
+    let invalid = |_| format!("invalid operand `{}`", text);
+
+    if text.starts_with('[') && text.ends_with(']') {
+        return Ok(Operand::Mem(
+            expr::parse(text[1..text.len() - 1].trim()).map_err(invalid)?,
+        ));
+    }
+
+    Ok(Operand::Value(expr::parse(text).map_err(invalid)?))
+}
+
+/// Parses a string in double quotes, with the same escapes as character literals
+fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid string `{}`", text);
+
+    let mut rest = text
+        .trim()
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+
+    let mut string = Vec::new();
+    while !rest.is_empty() {
+        let (c, size) = expr::parse_char(rest).ok_or_else(invalid)?;
+        if c == b'"' && size == 1 {
+            return Err(invalid());
+        }
+
+        string.push(c);
+        rest = &rest[size..];
+    }
+
+    Ok(string)
+}
+
+/// Parses a file name, given as a string
+fn parse_path(text: &str) -> Result<String, String> {
+    String::from_utf8(parse_string(text)?).map_err(|_| format!("invalid file name {}", text))
+}
+
+/// Splits off the first word of `text`, and trims what's left
+fn split_first_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(space) => (&text[..space], text[space..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// Calls `f` with each character of `text` and its index, along with whether it's inside a
+/// string or character literal
+fn scan(text: &str, mut f: impl FnMut(usize, char, bool) -> bool) {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        let quoted = quote.is_some();
+
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if quote == Some(c) {
+            quote = None;
+        } else if !quoted && (c == '"' || c == '\'') {
+            quote = Some(c);
+        }
+
+        if !f(i, c, quoted) {
+            break;
+        }
+    }
+}
+
+/// Removes a `;` comment from the end of a line
+fn strip_comment(text: &str) -> &str {
+    let mut end = text.len();
+
+    scan(text, |i, c, quoted| {
+        if c == ';' && !quoted {
+            end = i;
+            return false;
+        }
+        true
+    });
+
+    &text[..end]
+}
+
+/// Splits operands or arguments at commas, except those in strings or brackets
+fn split_args(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut args = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+
+    scan(text, |i, c, quoted| {
+        match c {
+            _ if quoted => (),
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+        true
+    });
+    args.push(text[start..].trim());
+
+    args
+}
+
+/// Replaces each `\name` in `text` with whatever `f` gives for it, if anything
+fn substitute<'a>(text: &str, f: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(backslash) = rest.find('\\') {
+        result.push_str(&rest[..backslash]);
+        rest = &rest[backslash + 1..];
+
+        let end = if rest.starts_with('@') {
+            1
+        } else {
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len())
+        };
+
+        match f(&rest[..end]) {
+            Some(replacement) => result.push_str(replacement),
+            None => {
+                result.push('\\');
+                result.push_str(&rest[..end]);
+            }
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_main_program() {
+        let source = "
+            LOAD A, 0xFF      ; write 0xFF to register A
+            NOP
+            STORE 0x0200, A
+            NOP
+            STORE 0x05FF, A
+            LOAD B, 1
+            JT B, 0x8000
+            NOP
+            HALT
+        ";
+
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![
+                0x10, 0x00, 0xFF, 0xFF, 0x20, 0x02, 0x00, 0x00, 0xFF, 0x20, 0x05, 0xFF, 0x00, 0x10,
+                0x01, 0x01, 0x40, 0x01, 0x80, 0x00, 0xFF, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_every_instruction() {
+        let source = "
+            HALT
+            NOP
+            LOAD A, 0xFF
+            LOAD B, C
+            LOAD C, [0x00AB]
+            STORE 0x00AB, D
+            STORE [0x00AB], D
+            EQ A, B, C
+            EQ A, 5, C
+            GT A, B, C
+            LT A, B, C
+            JT A, 0x8005
+            RETI
+            INC B
+            DEC C
+            ADD A, B, C
+            SUB A, B, C
+            ADD A, 10, B
+            SUB A, 10, B
+        ";
+
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![
+                0x00, 0xFF, 0x10, 0x00, 0xFF, 0x11, 0x01, 0x02, 0x12, 0x02, 0x00, 0xAB, 0x20, 0x00,
+                0xAB, 0x03, 0x20, 0x00, 0xAB, 0x03, 0x30, 0x00, 0x01, 0x02, 0x31, 0x00, 0x05, 0x02,
+                0x32, 0x00, 0x01, 0x02, 0x33, 0x00, 0x01, 0x02, 0x40, 0x00, 0x80, 0x05, 0x41, 0x50,
+                0x01, 0x51, 0x02, 0x52, 0x00, 0x01, 0x02, 0x53, 0x00, 0x01, 0x02, 0x54, 0x00, 0x0A,
+                0x01, 0x55, 0x00, 0x0A, 0x01,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_constants() {
+        let source = "
+            KEYS = 0x0100
+            UP = 1
+
+        loop:
+            LOAD A, [KEYS]
+            EQ A, UP, B
+            JT B, done        ; a forward reference
+            LOAD C, 1
+            JT C, loop
+        done: HALT
+        ";
+
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![
+                0x12, 0x00, 0x01, 0x00, // 8000
+                0x31, 0x00, 0x01, 0x01, // 8004
+                0x40, 0x01, 0x80, 0x13, // 8008
+                0x10, 0x02, 0x01, // 800C
+                0x40, 0x02, 0x80, 0x00, // 800F
+                0x00, // 8013
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let source = r#"
+            .byte 1, -1, 'A', SIZE * 2
+            .word 0x1234, end
+            .ascii "hi\n", "\x7F;"
+            .org 0x8010
+        end: HALT
+            SIZE = 3
+        "#;
+
+        let mut expected = vec![
+            1, 0xFF, b'A', 6, 0x12, 0x34, 0x80, 0x10, b'h', b'i', 10, 0x7F,
+        ];
+        expected.extend(b";");
+        expected.resize(0x10, 0);
+        expected.push(0x00);
+
+        assert_eq!(assemble(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = "
+            SCREEN = 0x0200
+            WIDTH = 32
+            STORE SCREEN + WIDTH * 2 + 1, A
+            LOAD B, [SCREEN | 0x10]
+            EQ A, (WIDTH >> 1) == 16, C
+        ";
+
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![0x20, 0x02, 0x41, 0x00, 0x12, 0x01, 0x02, 0x10, 0x31, 0x00, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn test_macros_and_conditionals() {
+        let source = "
+            DEBUG = 0
+
+            .macro wait reg, count
+                LOAD \\reg, \\count
+            loop\\@:
+                DEC \\reg
+                EQ \\reg, 0, D
+                .if DEBUG
+                    NOP
+                .else
+                    LOAD C, 1      ; still counting
+                .endif
+                EQ D, 0, D
+                JT D, loop\\@
+            .endm
+
+            wait A, 3
+            wait B, 1
+        ";
+        let program = assemble(source).unwrap();
+
+        assert_eq!(
+            crate::disasm::disassemble(&program, ORIGIN)
+                .into_iter()
+                .map(|line| line.text)
+                .collect::<Vec<_>>(),
+            vec![
+                "LOAD A, 0x03",
+                "DEC A",
+                "EQ A, 0x00, D",
+                "LOAD C, 0x01",
+                "EQ D, 0x00, D",
+                "JT D, 0x8003",
+                "LOAD B, 0x01",
+                "DEC B",
+                "EQ B, 0x00, D",
+                "LOAD C, 0x01",
+                "EQ D, 0x00, D",
+                "JT D, 0x8017",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include_and_incbin() {
+        let directory =
+            std::env::temp_dir().join(format!("maxemu-asm-include-{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(
+            directory.join("lib").join("sprites.s"),
+            "SPRITE_SIZE = 2\nsprite: .incbin \"sprite.bin\"\n",
+        )
+        .unwrap();
+        fs::write(directory.join("lib").join("sprite.bin"), [0xAB, 0xCD]).unwrap();
+        fs::write(
+            directory.join("main.s"),
+            "LOAD A, [sprite + SPRITE_SIZE - 1]\nHALT\n.include \"lib/sprites.s\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            assemble_file(&directory.join("main.s")).unwrap(),
+            vec![0x12, 0x00, 0x80, 0x06, 0x00, 0xAB, 0xCD]
+        );
+
+        fs::write(directory.join("loop.s"), ".include \"loop.s\"\n").unwrap();
+        let error = assemble_file(&directory.join("loop.s")).unwrap_err();
+        assert_eq!(error.file, Some(directory.join("loop.s")));
+        assert_eq!(error.line, 1);
+        assert_eq!(error.message, "includes or macros are nested too deeply");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_line_map() {
+        let directory =
+            std::env::temp_dir().join(format!("maxemu-asm-lines-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("lib.s"), "; helpers\nhelper: INC A\nRETI\n").unwrap();
+        fs::write(
+            directory.join("main.s"),
+            "main: LOAD A, 1\n.byte 0xFF\n\nJT A, helper\n.include \"lib.s\"\n",
+        )
+        .unwrap();
+
+        let image = assemble_image_file(&directory.join("main.s")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let lines = image
+            .lines
+            .iter()
+            .map(|(address, source)| format!("{:04X} {}", address, source))
+            .collect::<Vec<_>>();
+
+        // Data isn't an instruction, so it isn't in the map
+        assert_eq!(
+            lines,
+            [
+                format!("8000 {}", directory.join("main.s").display()) + ":1",
+                format!("8004 {}", directory.join("main.s").display()) + ":4",
+                format!("8008 {}", directory.join("lib.s").display()) + ":2",
+                format!("800A {}", directory.join("lib.s").display()) + ":3",
+            ]
+        );
+        assert_eq!(image.symbols.get("helper"), Some(0x8008));
+    }
+
+    #[test]
+    fn test_objects() {
+        let source = "
+            .global start, SIZE
+            SIZE = end - table
+
+        start:
+            LOAD A, [table + 1]
+            JT A, elsewhere
+            .section data
+        table: .byte SIZE, 0
+        end:
+        ";
+        let object = assemble_object(source).unwrap();
+
+        assert_eq!(object.sections.len(), 2);
+        assert_eq!(
+            object.sections[0].data,
+            vec![0x12, 0x00, 0, 0, 0x40, 0x00, 0, 0]
+        );
+        assert_eq!(object.sections[1].data, vec![2, 0]);
+        assert_eq!(
+            object
+                .relocations
+                .iter()
+                .map(|r| (r.section, r.offset, r.target.clone(), r.addend, r.line))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 2, Target::Section(1), 1, 6),
+                (0, 6, Target::Symbol("elsewhere".to_string()), 0, 7),
+            ]
+        );
+        assert!(object.symbols.contains(&Symbol {
+            name: "SIZE".to_string(),
+            section: None,
+            value: 2,
+            global: true,
+        }));
+
+        let error = |source| assemble_object(source).unwrap_err().message;
+        assert_eq!(
+            error(".org 0x9000"),
+            "`.org` can't be used in an object; place sections with a memory map"
+        );
+        assert_eq!(
+            error(".global nothing"),
+            "`nothing` is global but never defined"
+        );
+        assert_eq!(
+            error("x: .byte x >> 8"),
+            "expression can't be worked out until link time"
+        );
+    }
+
+    #[test]
+    fn test_number_formats() {
+        assert_eq!(
+            assemble("LOAD A, 10\nLOAD A, 0b101\nLOAD A, 0x1f\nLOAD A, -1").unwrap(),
+            vec![0x10, 0x00, 10, 0x10, 0x00, 5, 0x10, 0x00, 0x1F, 0x10, 0x00, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source).unwrap_err();
+
+        assert_eq!(
+            error("NOP\nFOO A"),
+            AsmError {
+                file: None,
+                line: 2,
+                message: "unknown instruction `FOO`".to_string()
+            }
+        );
+        assert_eq!(error("LOAD 5, A").message, "invalid operands for LOAD");
+        assert_eq!(error("JT A, nowhere").message, "undefined symbol `nowhere`");
+        assert_eq!(error("LOAD A, 256").message, "256 does not fit in 8 bits");
+        assert_eq!(error("x: NOP\nx: NOP").message, "`x` is already defined");
+        assert_eq!(error("B = 5").message, "`B` is a register");
+        assert_eq!(
+            error("X = X\nJT A, X").message,
+            "`X` is defined in terms of itself"
+        );
+        assert_eq!(error(".if 1\nNOP").message, "`.if` is missing its `.endif`");
+        assert_eq!(error(".else").message, "`.else` without `.if`");
+        assert_eq!(error(".macro m\nNOP").message, "`m` is missing its `.endm`");
+        assert_eq!(
+            error(".macro m, x\n.endm\nm 1, 2").message,
+            "`m` takes 1 arguments, not 2"
+        );
+        assert_eq!(
+            error("NOP\n.org 0x8000").message,
+            "`.org` can't move back from 0x8001 to 0x8000"
+        );
+        assert_eq!(
+            error(".word 0x10000").message,
+            "65536 does not fit in 16 bits"
+        );
+        assert_eq!(error(".foo").message, "unknown directive `.foo`");
+        assert_eq!(
+            error(".if LATER\n.endif\nLATER = 1").message,
+            "undefined symbol `LATER`"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::apu::SAMPLE_RATE;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Somewhere for the APU's samples to go
+pub trait AudioSink: fmt::Debug {
+    /// Receives the next batch of samples: mono, signed 16-bit, at `SAMPLE_RATE`
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+
+    /// Called once there are no more samples coming
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes samples to a WAV file
+#[derive(Debug)]
+pub struct WavSink<W: Write + Seek + fmt::Debug> {
+    out: W,
+    /// How many bytes of samples have been written so far
+    data_size: u32,
+}
+
+/// How big the header in front of the samples is
+const WAV_HEADER_SIZE: u32 = 44;
+
+impl WavSink<BufWriter<File>> {
+    /// Creates a WAV file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek + fmt::Debug> WavSink<W> {
+    pub fn new(out: W) -> io::Result<Self> {
+        let mut sink = Self { out, data_size: 0 };
+
+        // The sizes get filled in properly by `finish()`
+        sink.write_header()?;
+
+        Ok(sink)
+    }
+
+    /// Gives back the writer, which only holds a complete file once `finish()` has been called
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let channels: u16 = 1;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+
+        self.out.write_all(b"RIFF")?;
+        self.out
+            .write_all(&(WAV_HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.out.write_all(b"WAVE")?;
+
+        self.out.write_all(b"fmt ")?;
+        self.out.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        self.out.write_all(&1u16.to_le_bytes())?;
+        self.out.write_all(&channels.to_le_bytes())?;
+        self.out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        self.out
+            .write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        self.out.write_all(&block_align.to_le_bytes())?;
+        self.out.write_all(&bits_per_sample.to_le_bytes())?;
+
+        self.out.write_all(b"data")?;
+        self.out.write_all(&self.data_size.to_le_bytes())
+    }
+}
+
+impl<W: Write + Seek + fmt::Debug> AudioSink for WavSink<W> {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_header() {
+        let mut sink = WavSink::new(Cursor::new(Vec::new())).unwrap();
+
+        sink.write(&[1, -1, i16::MAX]).unwrap();
+        sink.finish().unwrap();
+
+        let bytes = sink.into_inner().into_inner();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(bytes[4..8], (36u32 + 6).to_le_bytes());
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(bytes[24..28], SAMPLE_RATE.to_le_bytes());
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(bytes[40..44], 6u32.to_le_bytes());
+        assert_eq!(bytes[44..], [0x01, 0x00, 0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+}
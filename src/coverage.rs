@@ -0,0 +1,411 @@
+//! Code coverage: which instructions have been executed, and which ways each conditional branch
+//! has gone.
+//!
+//! Coverage can be written out as a disassembly with the number of times each instruction ran
+//! next to it (`#####` for ones that never did), or, through the line map the assembler wrote, as
+//! an lcov tracefile of the source lines the program was assembled from, for `genhtml` and other
+//! coverage tools. Either way, a branch has two directions: taken and not taken.
+
+use crate::disasm::disassemble_one;
+use crate::instruction::decode;
+use crate::isa::{Effect, InstructionInfo, OperandKind};
+use crate::lines::LineMap;
+use crate::symbols::SymbolMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// How many times a conditional branch went each way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl Branch {
+    /// How many of the two directions have been gone in
+    fn directions(self) -> usize {
+        (self.taken > 0) as usize + (self.not_taken > 0) as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    /// How many times the instruction at each address was executed
+    executions: Vec<u64>,
+    /// Every conditional branch that's been executed, by address
+    branches: BTreeMap<u16, Branch>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            executions: vec![0; 0x10000],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    /// Counts the instruction at `pc`, which has just been executed. If it's a conditional
+    /// branch, `taken` is whether it jumped.
+    pub fn record(&mut self, pc: u16, taken: Option<bool>) {
+        self.executions[pc as usize] += 1;
+
+        if let Some(taken) = taken {
+            let branch = self.branches.entry(pc).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    /// How many times the instruction at `address` was executed
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executions[address as usize]
+    }
+
+    /// Which ways the branch at `address` has gone, if it's been executed
+    pub fn branch(&self, address: u16) -> Option<Branch> {
+        self.branches.get(&address).copied()
+    }
+
+    /// Disassembles `memory` from `start` up to `end`, with how many times each instruction was
+    /// executed and which ways each branch went. Labels from `symbols` and source lines from
+    /// `lines` are shown too; with a line map, only what's in it counts as code.
+    pub fn annotate(
+        &self,
+        memory: &[u8],
+        start: u16,
+        end: u16,
+        symbols: &SymbolMap,
+        lines: &LineMap,
+    ) -> String {
+        let mut listing = Vec::new();
+        let (mut instructions, mut executed) = (0, 0);
+        let (mut directions, mut taken) = (0, 0);
+
+        let mut address = start as usize;
+        while address < (end as usize).min(memory.len()) {
+            let line = disassemble_one(&memory[address..], address as u16);
+            let pc = line.address;
+            address += line.bytes.len();
+
+            for (name, _) in symbols.iter().filter(|&(_, symbol)| symbol == pc) {
+                listing.push(format!("{}:", name));
+            }
+
+            let code = if lines.is_empty() {
+                !line.text.starts_with(".byte")
+            } else {
+                lines.get(pc).is_some()
+            };
+            let mut comments = Vec::new();
+            if let Some(source) = lines.get(pc) {
+                comments.push(source.to_string());
+            }
+
+            let count = match self.executions(pc) {
+                _ if !code => String::new(),
+                0 => "#####".to_string(),
+                count => count.to_string(),
+            };
+            if code {
+                instructions += 1;
+                executed += (self.executions(pc) > 0) as usize;
+
+                if branch_at(memory, pc) {
+                    directions += 2;
+                    if let Some(branch) = self.branch(pc) {
+                        taken += branch.directions();
+                        comments.push(format!(
+                            "taken {}, not taken {}",
+                            branch.taken, branch.not_taken
+                        ));
+                    }
+                }
+            }
+
+            let text = format!(
+                "{:>9}  {:<36} ; {}",
+                count,
+                line.to_string(),
+                comments.join(", ")
+            );
+            listing.push(text.trim_end_matches([' ', ';']).to_string());
+        }
+
+        format!(
+            "; {} of {} instructions executed, and {} of {} branch directions taken\n{}\n",
+            executed,
+            instructions,
+            taken,
+            directions,
+            listing.join("\n")
+        )
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `info` is a conditional branch: one that jumps or not depending on a register, like
+/// `JT`
+pub fn is_branch(info: &InstructionInfo) -> bool {
+    info.effects.contains(&Effect::Jump) && info.operands.contains(&OperandKind::Reg)
+}
+
+/// Whether there's a conditional branch at `address`
+fn branch_at(memory: &[u8], address: u16) -> bool {
+    match memory.get(address as usize..) {
+        Some(bytes) => matches!(decode(bytes), Ok(instruction) if is_branch(instruction.info())),
+        None => false,
+    }
+}
+
+/// Coverage of source lines, added up over one or more programs, to be written as an lcov
+/// tracefile
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Lcov {
+    files: BTreeMap<PathBuf, SourceCoverage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SourceCoverage {
+    /// How many times the instructions on each line were executed
+    lines: BTreeMap<usize, u64>,
+    /// The branches on each line, in order of address, or `None` for ones never executed
+    branches: BTreeMap<usize, Vec<Option<Branch>>>,
+}
+
+impl Lcov {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the `coverage` of the program in `memory`, which `lines` maps back to its source.
+    /// Instructions from source that didn't come from a file are left out.
+    pub fn add(&mut self, coverage: &Coverage, memory: &[u8], lines: &LineMap) {
+        // How many branches on each line have been seen so far, to tell them apart
+        let mut seen = HashMap::new();
+
+        for (address, source) in lines.iter() {
+            let file = match &source.file {
+                Some(file) => self.files.entry(file.clone()).or_default(),
+                None => continue,
+            };
+
+            let executions = coverage.executions(address);
+            *file.lines.entry(source.line).or_default() += executions;
+
+            if branch_at(memory, address) {
+                let index = seen.entry(source).or_insert(0);
+                let branch =
+                    Some(coverage.branch(address).unwrap_or_default()).filter(|_| executions > 0);
+
+                let branches = file.branches.entry(source.line).or_default();
+                match branches.get_mut(*index) {
+                    Some(Some(total)) => {
+                        let branch = branch.unwrap_or_default();
+                        total.taken += branch.taken;
+                        total.not_taken += branch.not_taken;
+                    }
+                    Some(total) => *total = branch,
+                    None => branches.push(branch),
+                }
+                *index += 1;
+            }
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+
+        for (path, file) in &self.files {
+            writeln!(out, "SF:{}", path.display())?;
+
+            let mut found = 0;
+            let mut hit = 0;
+            for (line, branches) in &file.branches {
+                for (block, branch) in branches.iter().enumerate() {
+                    let counts = match branch {
+                        Some(branch) => {
+                            hit += branch.directions();
+                            [branch.taken.to_string(), branch.not_taken.to_string()]
+                        }
+                        None => ["-".to_string(), "-".to_string()],
+                    };
+
+                    for (direction, count) in counts.iter().enumerate() {
+                        writeln!(out, "BRDA:{},{},{},{}", line, block, direction, count)?;
+                    }
+                    found += 2;
+                }
+            }
+            writeln!(out, "BRF:{}", found)?;
+            writeln!(out, "BRH:{}", hit)?;
+
+            for (line, executions) in &file.lines {
+                writeln!(out, "DA:{},{}", line, executions)?;
+            }
+            writeln!(out, "LF:{}", file.lines.len())?;
+            writeln!(
+                out,
+                "LH:{}",
+                file.lines.values().filter(|&&count| count > 0).count()
+            )?;
+
+            writeln!(out, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_image;
+    use crate::cpu::CPU;
+    use crate::lines::SourceLine;
+
+    /// Counts down from 3. The second branch is always taken, so the line after it never runs.
+    const PROGRAM: &str = "\
+LOAD A, 3
+LOAD C, 1
+loop: DEC A
+EQ A, 0, B
+JT B, done
+JT C, loop
+LOAD D, 9
+done: HALT";
+
+    /// Runs `PROGRAM` with coverage, and returns it with the line map, with every line in
+    /// `test.s`
+    fn covered() -> (CPU, SymbolMap, LineMap) {
+        let image = assemble_image(PROGRAM).unwrap();
+
+        let mut lines = LineMap::new();
+        for (address, source) in image.lines.iter() {
+            lines.insert(
+                address,
+                SourceLine {
+                    file: Some(PathBuf::from("test.s")),
+                    line: source.line,
+                },
+            );
+        }
+
+        let mut cpu = CPU::headless();
+        cpu.load(image.data);
+        cpu.coverage = Some(Coverage::new());
+        cpu.run();
+
+        (cpu, image.symbols, lines)
+    }
+
+    #[test]
+    fn test_branches() {
+        let (cpu, _, _) = covered();
+        let coverage = cpu.coverage.as_ref().unwrap();
+
+        assert_eq!(coverage.executions(0x8006), 3);
+        assert_eq!(coverage.executions(0x8014), 0);
+        assert_eq!(
+            coverage.branch(0x800C),
+            Some(Branch {
+                taken: 1,
+                not_taken: 2
+            })
+        );
+        assert_eq!(
+            coverage.branch(0x8010),
+            Some(Branch {
+                taken: 2,
+                not_taken: 0
+            })
+        );
+        assert_eq!(coverage.branch(0x8006), None);
+    }
+
+    #[test]
+    fn test_annotate() {
+        let (cpu, symbols, lines) = covered();
+        let coverage = cpu.coverage.as_ref().unwrap();
+
+        assert_eq!(
+            coverage.annotate(&cpu.memory, 0x8000, 0x8018, &symbols, &LineMap::new()),
+            "\
+; 7 of 8 instructions executed, and 3 of 4 branch directions taken
+        1  8000: 10 00 03     LOAD A, 0x03
+        1  8003: 10 02 01     LOAD C, 0x01
+loop:
+        3  8006: 51 00        DEC A
+        3  8008: 31 00 00 01  EQ A, 0x00, B
+        3  800C: 40 01 80 17  JT B, 0x8017      ; taken 1, not taken 2
+        2  8010: 40 02 80 06  JT C, 0x8006      ; taken 2, not taken 0
+    #####  8014: 10 03 09     LOAD D, 0x09
+done:
+        1  8017: 00           HALT
+"
+        );
+
+        // With a line map, anything not in it isn't counted as code
+        let annotated = coverage.annotate(&cpu.memory, 0x8000, 0x801A, &symbols, &lines);
+        assert!(
+            annotated.contains("\n    #####  8014: 10 03 09     LOAD D, 0x09      ; test.s:7\n")
+        );
+        assert!(annotated.contains("\n           8018: 00           HALT\n"));
+        assert!(annotated.starts_with("; 7 of 8 instructions"));
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (cpu, _, lines) = covered();
+        let coverage = cpu.coverage.as_ref().unwrap();
+
+        let lcov = |runs: usize| {
+            let mut lcov = Lcov::new();
+            for _ in 0..runs {
+                lcov.add(coverage, &cpu.memory, &lines);
+            }
+
+            let mut out = Vec::new();
+            lcov.write(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            lcov(1),
+            "\
+TN:
+SF:test.s
+BRDA:5,0,0,1
+BRDA:5,0,1,2
+BRDA:6,0,0,2
+BRDA:6,0,1,0
+BRF:4
+BRH:3
+DA:1,1
+DA:2,1
+DA:3,3
+DA:4,3
+DA:5,3
+DA:6,2
+DA:7,0
+DA:8,1
+LF:8
+LH:7
+end_of_record
+"
+        );
+
+        // Adding up runs of the same program doubles the counts
+        assert!(lcov(2).contains("\nBRDA:5,0,1,4\n"));
+        assert!(lcov(2).contains("\nDA:3,6\n"));
+        assert!(lcov(2).contains("\nLH:7\n"));
+    }
+}
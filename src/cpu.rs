@@ -1,236 +1,450 @@
+use crate::apu::APU;
+use crate::coverage::Coverage;
+use crate::debug_info::DebugInfo;
+use crate::instruction::{decode, DecodeError, Instruction};
+use crate::isa;
 use crate::ppu::PPU;
+use crate::profile::Profiler;
+use crate::replay::Replay;
+use crate::rom::Rom;
+use crate::snapshot::{Snapshot, StateError};
+use crate::trace::Tracer;
+use crate::watch::{WatchHit, WatchKind, Watchpoint};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+/// How many cycles the CPU runs every second
+pub const CLOCK_HZ: u32 = 153_600;
+
+/// How much memory there is. The last address, 0xFFFF, isn't part of it.
+pub const MEMORY_SIZE: usize = 0xFFFF;
+
+/// Where the address of the interrupt handler is stored, as Big Endian
+pub const INTERRUPT_VECTOR: u16 = 0xFFFC;
+
+/// An instruction that couldn't be executed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    /// Where the instruction is
+    pub pc: u16,
+    pub kind: FaultKind,
+    /// `pc` as `DebugInfo::location` describes it
+    pub location: String,
+}
+
+/// Why an instruction couldn't be executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// It isn't a valid instruction
+    Decode(DecodeError),
+    /// It starts or ends past the end of memory
+    OutOfMemory,
+    /// It's a `RETI` outside of an interrupt handler, so there's nowhere to return to
+    ReturnOutsideInterrupt,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.location)
+    }
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultKind::Decode(error) => write!(f, "{}", error),
+            FaultKind::OutOfMemory => write!(f, "Instruction runs past the end of memory"),
+            FaultKind::ReturnOutsideInterrupt => write!(
+                f,
+                "Returned from an interrupt outside of an interrupt handler"
+            ),
+        }
+    }
+}
+
+impl Error for Fault {}
 
 #[derive(Debug)]
 pub struct CPU {
     pub registers: [u8; 4],
-    pub memory: [u8; 0xFFFF],
+    pub memory: [u8; MEMORY_SIZE],
     pub pc: u16,
     pub ppu: PPU,
+    pub apu: APU,
+    /// How many cycles have been executed so far
+    pub cycles: u64,
+    /// How many instructions have been executed so far, counting a halt
+    pub instructions: u64,
+    /// Where to return to once the current interrupt handler is done, if we're in one
+    pub interrupt_return: Option<u16>,
+    /// The address of the instruction being executed, or the last one executed
+    pub instruction_pc: u16,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Accesses the watchpoints have noticed since they were last taken
+    pub watch_hits: Vec<WatchHit>,
+    /// Where executed instructions are traced to, if anywhere
+    pub tracer: Option<Tracer>,
+    /// Counts every instruction executed, if profiling
+    pub profiler: Option<Profiler>,
+    /// Records which instructions and branches are executed, if measuring coverage
+    pub coverage: Option<Coverage>,
+    /// The program's symbol and line maps, for saying where things happened in it
+    pub debug_info: DebugInfo,
 }
 
 impl CPU {
     pub fn new() -> Self {
+        Self::with_ppu(PPU::new())
+    }
+
+    /// Creates a CPU whose PPU never opens a window
+    pub fn headless() -> Self {
+        Self::with_ppu(PPU::headless())
+    }
+
+    /// Creates a CPU around an existing PPU, e.g. one with a different window scale
+    pub fn with_ppu(ppu: PPU) -> Self {
         Self {
             registers: [0; 4],
-            memory: [0; 0xFFFF],
+            memory: [0; MEMORY_SIZE],
             pc: 0,
-            ppu: PPU::new(),
+            ppu,
+            apu: APU::new(),
+            cycles: 0,
+            instructions: 0,
+            interrupt_return: None,
+            instruction_pc: 0,
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            debug_info: DebugInfo::default(),
         }
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x8000..(0x8000 + program.len())].copy_from_slice(&program[..]);
-        // Note: we initialize the pc here, which is where run() will start at.
-        self.pc = 0x8000;
+        self.load_at(&program, 0x8000);
     }
 
-    #[allow(unused_doc_comments)]
-    pub fn run(&mut self) {
-        loop {
-            self.ppu.render(self.memory);
-            self.memory = self.ppu.update_keys(self.memory);
-
-            dbg!(self.memory[0x0100]);
-
-            let opcode = self.mem_read(self.pc);
-            self.pc += 1;
-
-            match opcode {
-                /// Halt
-                0x00 => return,
-                /// No-op
-                0xFF => (),
-
-                /// Load value into register; LOAD
-                0x10 => {
-                    let reg_index = self.mem_read_next_for_register_index();
-
-                    let value = self.mem_read_next();
-
-                    self.registers[reg_index] = value;
-                }
-
-                /// Load from another register
-                0x11 => {
-                    let reg_index = self.mem_read_next_for_register_index();
-
-                    let content = self.registers[self.mem_read_next_as_usize()];
-
-                    self.registers[reg_index] = content;
-                }
-
-                /// Load to a register from memory
-                0x12 => {
-                    let reg_index = self.mem_read_next_for_register_index();
+    /// Copies `program` into memory at `address`, and starts executing from there
+    pub fn load_at(&mut self, program: &[u8], address: u16) {
+        let address = address as usize;
+        self.memory[address..(address + program.len())].copy_from_slice(program);
+        // Note: we initialize the pc here, which is where run() will start at.
+        self.pc = address as u16;
+    }
 
-                    let address = self.mem_read_u16_be_next();
+    /// Loads a ROM's program, and starts executing from its entry point
+    pub fn load_rom(&mut self, rom: &Rom) {
+        self.load_at(&rom.program, rom.load_address);
+        self.pc = rom.entry;
+    }
 
-                    self.registers[reg_index] = self.mem_read(address);
-                }
+    /// Takes key presses from `replay` from now on, rather than the window
+    pub fn start_replay(&mut self, replay: Replay) {
+        replay.apply(&mut self.memory, self.ppu.frame);
+        self.ppu.replay = Some(replay);
+    }
 
-                /// Store 8 bits to a region in memory from a register
-                0x20 => {
-                    let address = self.mem_read_u16_be_next();
+    /// Captures everything about the machine that changes as it runs
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            memory: Box::new(self.memory),
+            pc: self.pc,
+            cycles: self.cycles,
+            instructions: self.instructions,
+            interrupt_return: self.interrupt_return,
+            instruction_pc: self.instruction_pc,
+            ppu: self.ppu.state(),
+            apu: self.apu.state(),
+        }
+    }
 
-                    let reg_index = self.mem_read_next_for_register_index();
+    /// Puts the machine back how it was when `snapshot` was taken. The window, watchpoints,
+    /// tracer and anything being recorded are left as they are.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers = snapshot.registers;
+        self.memory = *snapshot.memory;
+        self.pc = snapshot.pc;
+        self.cycles = snapshot.cycles;
+        self.instructions = snapshot.instructions;
+        self.interrupt_return = snapshot.interrupt_return;
+        self.instruction_pc = snapshot.instruction_pc;
+        self.ppu.restore(&snapshot.ppu);
+        self.apu.restore(&snapshot.apu);
+    }
 
-                    self.mem_write(address, self.registers[reg_index]);
-                }
+    /// Saves a snapshot of the machine to a save state file at `path`
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), StateError> {
+        self.snapshot().save(path)
+    }
 
-                /// Compare $A == $B storing the result in $C
-                0x30 => {
-                    let reg1 = self.registers[self.mem_read_next_for_register_index()];
+    /// Puts the machine into the state saved at `path`, which works on a fresh CPU without a ROM
+    /// loaded since the save state has all of memory
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), StateError> {
+        self.restore(&Snapshot::open(path)?);
+        Ok(())
+    }
 
-                    let reg2 = self.registers[self.mem_read_next_for_register_index()];
+    /// Runs until the program halts or the window is closed
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
 
-                    // We create a `u8` from a `bool` - on true, it becomes 1, and on false it becomes 0.
-                    self.registers[self.mem_read_next_for_register_index()] =
-                        u8::from(reg1 == reg2);
-                }
+    /// Runs until `frames` more frames have been presented, or the program halts or the window is
+    /// closed. Returns false if it stopped early.
+    pub fn run_frames(&mut self, frames: u64) -> bool {
+        let target = self.ppu.frame + frames;
 
-                /// Compare $A == 0xB and store the result in $C
-                0x31 => {
-                    let reg = self.registers[self.mem_read_next_for_register_index()];
+        while self.ppu.frame < target {
+            if !self.step() {
+                return false;
+            }
+        }
 
-                    let value = self.mem_read(self.pc);
-                    self.pc += 1;
+        true
+    }
 
-                    // We create a `u8` from a `bool` - on true, it becomes 1, and on false it becomes 0.
-                    self.registers[self.mem_read_next_for_register_index()] =
-                        u8::from(reg == value);
-                }
+    /// Executes a single instruction. Returns false if it was a halt, or if the window has been
+    /// closed. Panics if there isn't a valid instruction at pc.
+    pub fn step(&mut self) -> bool {
+        self.try_step().unwrap_or_else(|fault| panic!("{}", fault))
+    }
 
-                /// Compare $A > $B storing the result in $C
-                0x32 => {
-                    let reg1 = self.registers[self.mem_read_next_for_register_index()];
+    /// Like `step`, but returns the fault if there isn't a valid instruction at pc, leaving pc
+    /// on it
+    pub fn try_step(&mut self) -> Result<bool, Fault> {
+        let instruction = self.try_fetch()?;
+        if matches!(instruction, Instruction::Reti) && self.interrupt_return.is_none() {
+            return Err(self.fault(FaultKind::ReturnOutsideInterrupt));
+        }
+        self.instruction_pc = self.pc;
+
+        if let Some(tracer) = &mut self.tracer {
+            let traced = tracer.trace(
+                self.cycles,
+                self.pc,
+                &instruction,
+                &self.registers,
+                &self.debug_info,
+            );
+            if let Err(e) = traced {
+                eprintln!("Could not write the trace, stopping it: {}", e);
+                self.tracer = None;
+            }
+        }
 
-                    let reg2 = self.registers[self.mem_read_next_for_register_index()];
+        self.pc = self.pc.wrapping_add(instruction.size() as u16);
+        self.instructions += 1;
 
-                    // We create a `u8` from a `bool` - on true, it becomes 1, and on false it becomes 0.
-                    self.registers[self.mem_read_next_for_register_index()] = u8::from(reg1 > reg2);
-                }
+        let interrupted = self.interrupt_return;
+        let taken = self.branch_taken(instruction);
+        let running = self.execute(instruction);
 
-                /// Compare $A < 0xB and store the result in $C
-                0x33 => {
-                    let reg1 = self.registers[self.mem_read_next_for_register_index()];
+        // A halt doesn't take any time, since nothing runs after it
+        let cycles = if running {
+            instruction.info().cycles
+        } else {
+            0
+        };
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(
+                self.instruction_pc,
+                instruction.opcode(),
+                cycles,
+                interrupted,
+            );
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(self.instruction_pc, taken);
+        }
 
-                    let reg2 = self.registers[self.mem_read_next_for_register_index()];
+        if !running {
+            return Ok(false);
+        }
 
-                    // We create a `u8` from a `bool` - on true, it becomes 1, and on false it becomes 0.
-                    self.registers[self.mem_read_next_for_register_index()] = u8::from(reg1 < reg2);
-                }
+        self.cycles += cycles as u64;
 
-                /// If $A is true, jump to 0xB in the program counter
-                0x40 => {
-                    let reg = self.registers[self.mem_read_next_for_register_index()];
+        self.apu.step(&mut self.memory, cycles);
+        if self.ppu.step(&mut self.memory, cycles) {
+            self.interrupt();
+        }
 
-                    // We don't use `mem_read_u16_be_next()` here for efficiency reasons - there
-                    // would be no need to increment the program counter if we do end up changing it.
-                    // If not, we'll increment it manually.
-                    let target = self.mem_read_u16_be(self.pc);
+        Ok(!self.ppu.closed)
+    }
 
-                    if reg == 1 {
-                        self.pc = target;
-                    } else {
-                        self.pc += 2;
-                    }
-                }
+    /// Decodes the instruction at self.pc, without moving past it. Panics if there isn't a valid
+    /// one there, saying where in the program that is.
+    pub fn fetch(&self) -> Instruction {
+        self.try_fetch().unwrap_or_else(|fault| panic!("{}", fault))
+    }
 
-                /// Increment $A
-                0x50 => {
-                    let reg_index = self.mem_read_next_for_register_index();
+    /// Like `fetch`, but returns the fault if there isn't a valid instruction at self.pc
+    pub fn try_fetch(&self) -> Result<Instruction, Fault> {
+        // Memory stops just short of 0xFFFF, so an instruction can't start or end there
+        let opcode = self
+            .peek_checked(self.pc)
+            .ok_or_else(|| self.fault(FaultKind::OutOfMemory))?;
+        let end = self.pc as usize + isa::lookup(opcode).map_or(1, |info| info.size());
+        if end > MEMORY_SIZE {
+            return Err(self.fault(FaultKind::OutOfMemory));
+        }
 
-                    self.registers[reg_index] += 1;
-                }
+        let bytes = (self.pc..end as u16)
+            .map(|address| self.peek(address))
+            .collect::<Vec<_>>();
 
-                /// Decrement $A
-                0x51 => {
-                    let reg_index = self.mem_read_next_for_register_index();
+        decode(&bytes).map_err(|error| self.fault(FaultKind::Decode(error)))
+    }
 
-                    self.registers[reg_index] -= 1;
-                }
+    /// A fault of the given `kind` in the instruction at pc
+    fn fault(&self, kind: FaultKind) -> Fault {
+        Fault {
+            pc: self.pc,
+            kind,
+            location: self.debug_info.location(self.pc),
+        }
+    }
 
-                /// Perform $A + $B and store the result in $C
-                0x52 => {
-                    let reg1_index = self.mem_read_next_for_register_index();
-                    let reg2_index = self.mem_read_next_for_register_index();
-                    let reg3_index = self.mem_read_next_for_register_index();
+    /// Carries out `instruction`, which self.pc should already have moved past. Returns false if
+    /// it was a halt.
+    pub fn execute(&mut self, instruction: Instruction) -> bool {
+        match instruction {
+            Instruction::Halt => return false,
+            Instruction::Nop => (),
 
-                    // We use .wrapping_add() here to denote that if we overflow, wrap to 0.
-                    self.registers[reg3_index] =
-                        self.registers[reg1_index].wrapping_add(self.registers[reg2_index]);
-                }
+            Instruction::Load { dst, imm } => self.registers[dst.index()] = imm,
+            Instruction::LoadReg { dst, src } => {
+                self.registers[dst.index()] = self.registers[src.index()]
+            }
+            Instruction::LoadMem { dst, addr } => self.registers[dst.index()] = self.mem_read(addr),
+            Instruction::Store { addr, src } => self.mem_write(addr, self.registers[src.index()]),
 
-                /// Perform $A - $B and store the result in $C
-                0x53 => {
-                    let reg1_index = self.mem_read_next_for_register_index();
-                    let reg2_index = self.mem_read_next_for_register_index();
-                    let reg3_index = self.mem_read_next_for_register_index();
+            // We create a `u8` from a `bool` - on true, it becomes 1, and on false it becomes 0.
+            Instruction::Eq { a, b, dst } => {
+                self.registers[dst.index()] =
+                    u8::from(self.registers[a.index()] == self.registers[b.index()])
+            }
+            Instruction::EqImm { a, imm, dst } => {
+                self.registers[dst.index()] = u8::from(self.registers[a.index()] == imm)
+            }
+            Instruction::Gt { a, b, dst } => {
+                self.registers[dst.index()] =
+                    u8::from(self.registers[a.index()] > self.registers[b.index()])
+            }
+            Instruction::Lt { a, b, dst } => {
+                self.registers[dst.index()] =
+                    u8::from(self.registers[a.index()] < self.registers[b.index()])
+            }
 
-                    self.registers[reg3_index] =
-                        self.registers[reg1_index].wrapping_sub(self.registers[reg2_index]);
+            Instruction::JumpIfTrue { cond, target } => {
+                if self.registers[cond.index()] == 1 {
+                    self.pc = target;
                 }
+            }
+            Instruction::Reti => {
+                self.pc = self
+                    .interrupt_return
+                    .take()
+                    .expect("Returned from an interrupt outside of an interrupt handler");
+            }
 
-                /// Perform $A + 0xB and store the result in $C
-                0x54 => {
-                    let reg1_index = self.mem_read_next_for_register_index();
-                    let val2 = self.mem_read_next();
-                    let reg3_index = self.mem_read_next_for_register_index();
-
-                    self.registers[reg3_index] = self.registers[reg1_index].wrapping_add(val2);
-                }
+            Instruction::Inc(reg) => self.registers[reg.index()] += 1,
+            Instruction::Dec(reg) => self.registers[reg.index()] -= 1,
 
-                /// Perform $A - 0xB and store the result in $C
-                0x55 => {
-                    let reg1_index = self.mem_read_next_for_register_index();
-                    let val2 = self.mem_read_next();
-                    let reg3_index = self.mem_read_next_for_register_index();
+            // We use .wrapping_add() here to denote that if we overflow, wrap to 0.
+            Instruction::Add { a, b, dst } => {
+                self.registers[dst.index()] =
+                    self.registers[a.index()].wrapping_add(self.registers[b.index()])
+            }
+            Instruction::Sub { a, b, dst } => {
+                self.registers[dst.index()] =
+                    self.registers[a.index()].wrapping_sub(self.registers[b.index()])
+            }
+            Instruction::AddImm { a, imm, dst } => {
+                self.registers[dst.index()] = self.registers[a.index()].wrapping_add(imm)
+            }
+            Instruction::SubImm { a, imm, dst } => {
+                self.registers[dst.index()] = self.registers[a.index()].wrapping_sub(imm)
+            }
+        }
 
-                    self.registers[reg3_index] = self.registers[reg1_index].wrapping_sub(val2);
-                }
+        true
+    }
 
-                _ => unimplemented!(),
-            }
+    /// Whether `instruction` will jump, if it's a conditional branch. This can't be told from
+    /// where it leaves pc, since a branch can jump to the next instruction.
+    fn branch_taken(&self, instruction: Instruction) -> Option<bool> {
+        match instruction {
+            Instruction::JumpIfTrue { cond, .. } => Some(self.registers[cond.index()] == 1),
+            _ => None,
         }
     }
 
-    /// Reads 8 bits after `addr`
-    fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    /// Jumps to the interrupt handler, unless we're already in one
+    fn interrupt(&mut self) {
+        if self.interrupt_return.is_none() {
+            self.interrupt_return = Some(self.pc);
+            self.pc = self.mem_read_u16_be(INTERRUPT_VECTOR);
+        }
     }
 
-    /// Reads the next 8 bits after self.pc and increments it respectively
-    fn mem_read_next(&mut self) -> u8 {
-        let res = self.mem_read(self.pc);
-        self.pc += 1;
+    /// Takes every access the watchpoints have noticed so far
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
 
-        res
+    /// Reads 8 bits after `addr`, without any watchpoints noticing
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[self.ppu.map_address(&self.memory, addr) as usize]
     }
 
-    /// Performs `mem_read_next()` but returns a safely casted usize
-    fn mem_read_next_as_usize(&mut self) -> usize {
-        self.mem_read_next() as usize
+    /// Like `peek`, but returns None for an address past the end of memory
+    fn peek_checked(&self, addr: u16) -> Option<u8> {
+        Some(addr)
+            .filter(|&addr| (addr as usize) < MEMORY_SIZE)
+            .map(|addr| self.peek(addr))
     }
 
-    /// Performs `mem_read_next_as_usize()` but checks to make sure the supplied value is a valid
-    /// register
-    fn mem_read_next_for_register_index(&mut self) -> usize {
-        // Note that since we use unsigned memory, there is no need to check if the value is larger
-        // than 0.
-        if self.mem_read(self.pc) <= 4 {
-            self.mem_read_next_as_usize()
-        } else {
-            panic!("Invalid register: {}", self.mem_read(self.pc));
-        }
+    /// Reads 8 bits after `addr`
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let physical = self.ppu.map_address(&self.memory, addr);
+        let data = self.memory[physical as usize];
+
+        self.watch(WatchKind::Read, addr, physical, data, data);
+        data
     }
 
     /// Writes `data` to `addr`
     fn mem_write(&mut self, addr: u16, data: u8) {
-        self.memory[addr as usize] = data;
+        let physical = self.ppu.map_address(&self.memory, addr);
+        let old = self.memory[physical as usize];
+        self.memory[physical as usize] = data;
+
+        self.watch(WatchKind::Write, addr, physical, old, data);
+    }
+
+    /// Records a hit for every watchpoint that notices an access
+    fn watch(&mut self, kind: WatchKind, address: u16, physical: u16, old: u8, new: u8) {
+        for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+            if watchpoint.matches(kind, address, physical, old, new) {
+                self.watch_hits.push(WatchHit {
+                    watchpoint: i,
+                    kind: watchpoint.kind,
+                    address,
+                    old,
+                    new,
+                    pc: self.instruction_pc,
+                });
+            }
+        }
     }
 
     /// Reads 16 bits after `pos`
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
         let hi = self.mem_read(pos + 1) as u16;
         (hi << 8) | (lo as u16)
@@ -245,28 +459,31 @@ impl CPU {
     }
 
     /// Reads 16 bits after `pos` as Big Endian
-    fn mem_read_u16_be(&self, pos: u16) -> u16 {
+    fn mem_read_u16_be(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
         let hi = self.mem_read(pos + 1) as u16;
         (lo << 8) | hi as u16
     }
+}
 
-    /// Reads the next 16 bits in memory as Big Endian and increments self.pc respectively
-    fn mem_read_u16_be_next(&mut self) -> u16 {
-        let res = self.mem_read_u16_be(self.pc);
-        self.pc += 2;
-
-        res
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::asm::assemble_image;
+    use crate::ppu::{
+        CONTROL_ADDR, CONTROL_COMPARE_INTERRUPT, CONTROL_DOUBLE_BUFFER, DISPLAYED_PAGE_ADDR,
+        PAGE_ADDR, SCANLINE_ADDR, SCANLINE_COMPARE_ADDR,
+    };
 
     #[test]
     fn blank_program() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![0x00];
 
         // We load the program in, which will add the opcode into memory and point the program
@@ -278,7 +495,7 @@ mod tests {
 
     #[test]
     fn test_load() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x10, 0x00, // $A (the register)
             0xFF, // 0xB (the value)
@@ -291,9 +508,29 @@ mod tests {
         assert_eq!(cpu.registers[0], 0xFF,)
     }
 
+    #[test]
+    fn test_load_rom() {
+        let mut cpu = CPU::headless();
+        let rom = Rom {
+            entry: 0x4002,
+            load_address: 0x4000,
+            program: vec![
+                0x10, 0x00, // skipped over by the entry point
+                0x10, 0x01, 0x2A, 0x00,
+            ],
+        };
+
+        cpu.load_rom(&rom);
+        assert_eq!(cpu.pc, 0x4002);
+        cpu.run();
+
+        assert_eq!(cpu.registers[0], 0x00);
+        assert_eq!(cpu.registers[1], 0x2A);
+    }
+
     #[test]
     fn test_load_from_register() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x11, 0x00, // $A (the register to write to)
             0x01, // $B (the register to read from)
@@ -310,7 +547,7 @@ mod tests {
 
     #[test]
     fn test_load_from_memory() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x12, 0x00, // $A (the register to write to)
             0x00, 0xAB, // 0xB (the region in memory to read from)
@@ -327,7 +564,7 @@ mod tests {
 
     #[test]
     fn test_store_to_mem() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x20, 0x00, 0xAB, // 0xB (the region in memory to write to)
             0x00, // $A (the register to read from)
@@ -344,7 +581,7 @@ mod tests {
 
     #[test]
     fn test_compare_registers_true() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x30, 0x00, // $A (the first register to compare)
             0x01, // $B (the second register to compare)
@@ -363,7 +600,7 @@ mod tests {
 
     #[test]
     fn test_compare_registers_false() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x30, // See test_compare_registers_true()
             0x00, 0x01, 0x02, 0x00,
@@ -380,7 +617,7 @@ mod tests {
 
     #[test]
     fn test_compare_register_with_val_true() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x31, 0x00, // $A (the first register to compare)
             0xFF, // 0xB (the second value to compare)
@@ -398,7 +635,7 @@ mod tests {
 
     #[test]
     fn test_compare_register_with_val_false() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x31, // See test_compare_register_with_val_true()
             0x00, 0xFF, 0x01, 0x00,
@@ -414,7 +651,7 @@ mod tests {
 
     #[test]
     fn test_jump_if_true() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x40, 0x00, // $A (the register that we're checking)
             0x80, 0x05, // 0xB (the region in memory we're jumping the program counter to)
@@ -432,7 +669,7 @@ mod tests {
 
     #[test]
     fn test_jump_if_false() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x40, // See test_jump_if_true()
             0x00, 0x80, 0x05, 0x00, // The program will reach here and end (address 32773)
@@ -447,7 +684,7 @@ mod tests {
 
     #[test]
     fn test_increment_reg() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x50, 0x00, // $A (the register to increment)
             0x00,
@@ -463,7 +700,7 @@ mod tests {
 
     #[test]
     fn test_decrement_reg() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x51, 0x00, // $A (the register to increment)
             0x00,
@@ -479,7 +716,7 @@ mod tests {
 
     #[test]
     fn test_add_regs() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x52, 0x00, // $A (the first register to add)
             0x01, // $B (the second register to add)
@@ -498,7 +735,7 @@ mod tests {
 
     #[test]
     fn test_add_regs_with_overflow() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x52, 0x00, // $A (the first register to add)
             0x01, // $B (the second register to add)
@@ -517,7 +754,7 @@ mod tests {
 
     #[test]
     fn test_sub_regs() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x53, 0x00, // $A (the first register to add)
             0x01, // $B (the second register to add)
@@ -536,7 +773,7 @@ mod tests {
 
     #[test]
     fn test_sub_regs_with_overflow() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x53, 0x00, // $A (the first register to add)
             0x01, // $B (the second register to add)
@@ -555,7 +792,7 @@ mod tests {
 
     #[test]
     fn test_add_reg_to_val() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x54, 0x00, // $A (the first register to add)
             0x0A, // 0xB (the second value to add)
@@ -574,7 +811,7 @@ mod tests {
 
     #[test]
     fn test_add_reg_to_val_with_overflow() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x54, 0x00, // $A (the first register to add)
             0x0A, // 0xB (the second value to add)
@@ -593,7 +830,7 @@ mod tests {
 
     #[test]
     fn test_sub_val_from_reg() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x55, 0x00, // $A (the first register to subtract)
             0x05, // 0xB (the second value to subtract)
@@ -612,7 +849,7 @@ mod tests {
 
     #[test]
     fn test_sub_val_from_reg_with_overflow() {
-        let mut cpu = CPU::new();
+        let mut cpu = CPU::headless();
         let program = vec![
             0x55, // See test_sub_val_from_reg()
             0x00, 0x0A, 0x01, 0x00,
@@ -626,4 +863,230 @@ mod tests {
         // 0 - 10
         assert_eq!(cpu.registers[1], 246,)
     }
+
+    #[test]
+    fn test_scanline_register() {
+        let mut cpu = CPU::headless();
+        let program = vec![
+            0x12, 0x00, 0x01, 0x01, // Read the current scanline into $A
+            0x31, 0x00, 0x03, 0x01, // Is it scanline 3?
+            0x40, 0x01, 0x80, 0x13, // If so, jump to the end
+            0x10, 0x02, 0x01, // Otherwise, jump back to the start
+            0x40, 0x02, 0x80, 0x00, //
+            0x00,
+        ];
+
+        cpu.load(program);
+        cpu.run();
+
+        assert_eq!(cpu.registers[0], 3);
+        assert_eq!(cpu.ppu.scanline, 3);
+        assert_eq!(cpu.mem_read(SCANLINE_ADDR), 3);
+    }
+
+    #[test]
+    fn test_scanline_compare_interrupt() {
+        let mut cpu = CPU::headless();
+        let program = vec![
+            0x31, 0x03, 0x42, 0x00, // Has the interrupt handler run yet?
+            0x40, 0x00, 0x80, 0x0C, // If so, jump to the end
+            0x40, 0x01, 0x80, 0x00, // Otherwise, keep waiting ($B is always true)
+            0x00,
+        ];
+
+        cpu.load(program);
+
+        // The handler just sets $D and returns
+        cpu.memory[0x9000..0x9004].copy_from_slice(&[0x10, 0x03, 0x42, 0x41]);
+        cpu.mem_write(INTERRUPT_VECTOR, 0x90);
+        cpu.mem_write(INTERRUPT_VECTOR + 1, 0x00);
+
+        cpu.mem_write(SCANLINE_COMPARE_ADDR, 2);
+        cpu.mem_write(CONTROL_ADDR, CONTROL_COMPARE_INTERRUPT);
+        cpu.registers[1] = 1;
+
+        cpu.run();
+
+        assert_eq!(cpu.registers[3], 0x42);
+        assert_eq!(cpu.interrupt_return, None);
+        assert_eq!(cpu.ppu.scanline, 2);
+    }
+
+    #[test]
+    fn test_double_buffer_writes_back_page() {
+        let mut cpu = CPU::headless();
+        let program = vec![
+            0x20, 0x02, 0x00, // Write to the first pixel of the framebuffer window...
+            0x00, // from $A
+            0x00,
+        ];
+
+        cpu.registers[0] = 0xFF;
+        cpu.mem_write(CONTROL_ADDR, CONTROL_DOUBLE_BUFFER);
+
+        cpu.load(program);
+        cpu.run();
+
+        // Page 0 is being displayed, so the write lands in page 1
+        assert_eq!(cpu.memory[0x0200], 0);
+        assert_eq!(cpu.memory[0x0600], 0xFF);
+    }
+
+    #[test]
+    fn test_double_buffer_flips_at_vblank() {
+        let mut cpu = CPU::headless();
+        let program = vec![
+            0x12, 0x00, 0x01, 0x07, // Read the displayed page into $A
+            0x40, 0x00, 0x80, 0x0C, // Once page 1 is displayed, jump ahead
+            0x40, 0x01, 0x80, 0x00, // Otherwise, keep waiting ($B is always true)
+            0x20, 0x02, 0x00, // Write to the first pixel of the framebuffer window...
+            0x01, // from $B
+            0x00,
+        ];
+
+        cpu.registers[1] = 1;
+        cpu.mem_write(CONTROL_ADDR, CONTROL_DOUBLE_BUFFER);
+        cpu.mem_write(PAGE_ADDR, 1);
+
+        cpu.load(program);
+        cpu.run();
+
+        assert_eq!(cpu.ppu.front_page, 1);
+        assert_eq!(cpu.mem_read(DISPLAYED_PAGE_ADDR), 1);
+        // Page 1 is now being displayed, so the write lands in page 0
+        assert_eq!(cpu.memory[0x0200], 1);
+        assert_eq!(cpu.memory[0x0600], 0);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = CPU::headless();
+        let program = vec![
+            0x10, 0x00, 0x05, // Load 5 into $A
+            0x20, 0x02, 0x00, 0x00, // Write $A to 0x0200
+            0x20, 0x02, 0x00, 0x00, // Write it again, which doesn't change anything
+            0x12, 0x01, 0x02, 0x01, // Read 0x0201 into $B
+            0x20, 0x03, 0x00, 0x00, // Write $A somewhere nothing is watching
+            0x00,
+        ];
+
+        cpu.watchpoints = vec![
+            Watchpoint::new(WatchKind::Write, 0x0200, 0x0200),
+            Watchpoint::new(WatchKind::Change, 0x0200, 0x0201),
+            Watchpoint::new(WatchKind::Read, 0x0200, 0x02FF),
+        ];
+
+        cpu.load(program);
+        cpu.run();
+
+        let hits = cpu
+            .take_watch_hits()
+            .iter()
+            .map(WatchHit::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hits,
+            vec![
+                "Watchpoint 0: wrote 0x05 to 0x0200 (was 0x00) at 0x8003",
+                "Watchpoint 1: 0x0200 changed from 0x00 to 0x05 at 0x8003",
+                "Watchpoint 0: wrote 0x05 to 0x0200 (was 0x05) at 0x8007",
+                "Watchpoint 2: read 0x00 from 0x0201 at 0x800b",
+            ]
+        );
+        assert!(cpu.watch_hits.is_empty());
+    }
+
+    #[test]
+    fn test_watchpoints_see_double_buffered_writes() {
+        let mut cpu = CPU::headless();
+        let program = vec![0x20, 0x02, 0x00, 0x00, 0x00];
+
+        cpu.registers[0] = 0xFF;
+        cpu.mem_write(CONTROL_ADDR, CONTROL_DOUBLE_BUFFER);
+        // The write goes to 0x0200 in the program, but lands in page 1
+        cpu.watchpoints = vec![Watchpoint::new(WatchKind::Change, 0x0600, 0x0600)];
+
+        cpu.load(program);
+        cpu.run();
+
+        assert_eq!(cpu.watch_hits.len(), 1);
+        assert_eq!(cpu.watch_hits[0].address, 0x0200);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown opcode: 0xee at 0x8007 (data at line 4)")]
+    fn test_fault_location() {
+        let image = assemble_image("LOAD A, 1\nJT A, data\n\ndata: HALT").unwrap();
+
+        let mut cpu = CPU::headless();
+        cpu.load(image.data);
+        cpu.debug_info = DebugInfo::new(image.symbols, image.lines);
+        cpu.memory[0x8007] = 0xEE;
+
+        cpu.run();
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut cpu = CPU::headless();
+        cpu.load(vec![0xFF, 0xEE]);
+
+        assert_eq!(cpu.try_step(), Ok(true));
+        assert_eq!(
+            cpu.try_step(),
+            Err(Fault {
+                pc: 0x8001,
+                kind: FaultKind::Decode(DecodeError::UnknownOpcode(0xEE)),
+                location: "0x8001".to_string(),
+            })
+        );
+
+        // The faulting instruction isn't executed, and pc is left on it
+        assert_eq!(cpu.pc, 0x8001);
+        assert_eq!(cpu.instructions, 1);
+    }
+
+    #[test]
+    fn test_fault_reti() {
+        let mut cpu = CPU::headless();
+        cpu.load(vec![0xFF, 0x41]);
+
+        assert_eq!(cpu.try_step(), Ok(true));
+        assert_eq!(
+            cpu.try_step().map_err(|fault| fault.to_string()),
+            Err("Returned from an interrupt outside of an interrupt handler at 0x8001".to_string())
+        );
+        assert_eq!(cpu.pc, 0x8001);
+        assert_eq!(cpu.instructions, 1);
+
+        // Inside one, it returns as usual
+        cpu.interrupt_return = Some(0x8000);
+        assert_eq!(cpu.try_step(), Ok(true));
+        assert_eq!(cpu.pc, 0x8000);
+    }
+
+    #[test]
+    fn test_fault_end_of_memory() {
+        let mut cpu = CPU::headless();
+        cpu.load(assemble_image("LOAD A, 1\nJT A, 0xFFFF").unwrap().data);
+
+        assert_eq!(cpu.try_step(), Ok(true));
+        assert_eq!(cpu.try_step(), Ok(true));
+        assert_eq!(
+            cpu.try_step(),
+            Err(Fault {
+                pc: 0xFFFF,
+                kind: FaultKind::OutOfMemory,
+                location: "0xffff".to_string(),
+            })
+        );
+
+        // An instruction that starts in memory but runs past the end of it
+        cpu.pc = 0xFFFC;
+        cpu.memory[0xFFFC] = 0x40;
+        assert_eq!(
+            cpu.try_step().map_err(|fault| fault.kind),
+            Err(FaultKind::OutOfMemory)
+        );
+    }
 }
@@ -0,0 +1,133 @@
+//! Debug info: the symbol map and line map written next to a program, which between them say
+//! where any address in it came from, e.g. `loop+0x2 at game.s:12`.
+
+use crate::disasm::Line;
+use crate::lines::LineMap;
+use crate::symbols::SymbolMap;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub symbols: SymbolMap,
+    pub lines: LineMap,
+}
+
+impl DebugInfo {
+    pub fn new(symbols: SymbolMap, lines: LineMap) -> Self {
+        Self { symbols, lines }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty() && self.lines.is_empty()
+    }
+
+    /// As much as is known about `address`: the label it's in and how far into it it is, and
+    /// the source line of the instruction there
+    pub fn describe(&self, address: u16) -> Option<String> {
+        match (self.symbols.describe(address), self.lines.get(address)) {
+            (Some(symbol), Some(source)) => Some(format!("{} at {}", symbol, source)),
+            (Some(symbol), None) => Some(symbol),
+            (None, Some(source)) => Some(source.to_string()),
+            (None, None) => None,
+        }
+    }
+
+    /// `address`, followed by its description if there is one, e.g. `0x8005 (loop+0x2)`
+    pub fn location(&self, address: u16) -> String {
+        match self.describe(address) {
+            Some(description) => format!("{:#06x} ({})", address, description),
+            None => format!("{:#06x}", address),
+        }
+    }
+
+    /// A disassembled `line`, with its description after it as a comment if there is one
+    pub fn annotate(&self, line: &Line) -> String {
+        match self.describe(line.address) {
+            Some(description) => format!("{:<36} ; {}", line.to_string(), description),
+            None => line.to_string(),
+        }
+    }
+
+    /// A listing of `lines`, with a line for each label before the instruction it's on, and the
+    /// source line of each instruction after it as a comment
+    pub fn listing(&self, lines: &[Line]) -> Vec<String> {
+        let mut listing = Vec::new();
+
+        for line in lines {
+            for (name, _) in self
+                .symbols
+                .iter()
+                .filter(|&(_, address)| address == line.address)
+            {
+                listing.push(format!("{}:", name));
+            }
+
+            listing.push(match self.lines.get(line.address) {
+                Some(source) => format!("{:<36} ; {}", line.to_string(), source),
+                None => line.to_string(),
+            });
+        }
+
+        listing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_image;
+    use crate::disasm::disassemble;
+
+    const PROGRAM: &str = "\
+main: LOAD A, 2
+loop: DEC A
+JT A, loop
+HALT";
+
+    fn debug_info() -> (Vec<u8>, DebugInfo) {
+        let image = assemble_image(PROGRAM).unwrap();
+        (image.data, DebugInfo::new(image.symbols, image.lines))
+    }
+
+    #[test]
+    fn test_describe() {
+        let (_, debug_info) = debug_info();
+
+        assert_eq!(
+            debug_info.describe(0x8003),
+            Some("loop at line 2".to_string())
+        );
+        assert_eq!(
+            debug_info.describe(0x8005),
+            Some("loop+0x2 at line 3".to_string())
+        );
+        assert_eq!(debug_info.describe(0x8006), Some("loop+0x3".to_string()));
+        assert_eq!(debug_info.describe(0x7000), None);
+        assert_eq!(debug_info.location(0x8000), "0x8000 (main at line 1)");
+        assert_eq!(debug_info.location(0x0200), "0x0200");
+
+        let only_lines = DebugInfo::new(SymbolMap::new(), debug_info.lines.clone());
+        assert_eq!(only_lines.describe(0x8009), Some("line 4".to_string()));
+        assert!(DebugInfo::default().is_empty());
+    }
+
+    #[test]
+    fn test_listing() {
+        let (data, debug_info) = debug_info();
+        let lines = disassemble(&data, 0x8000);
+
+        assert_eq!(
+            debug_info.annotate(&lines[2]),
+            "8005: 40 00 80 03  JT A, 0x8003      ; loop+0x2 at line 3"
+        );
+        assert_eq!(
+            debug_info.listing(&lines).join("\n"),
+            "\
+main:
+8000: 10 00 02     LOAD A, 0x02      ; line 1
+loop:
+8003: 51 00        DEC A             ; line 2
+8005: 40 00 80 03  JT A, 0x8003      ; line 3
+8009: 00           HALT              ; line 4"
+        );
+    }
+}
@@ -0,0 +1,803 @@
+//! An interactive debugger: it owns a CPU, and runs commands typed at a prompt against it.
+//!
+//! Addresses can be given as numbers, labels from a symbol map, `pc`, or expressions of those
+//! (e.g. `loop+3`). An empty line repeats the last command, like in gdb. With the CPU's symbol and
+//! line maps loaded, instructions are shown with the label they're in and their source line.
+
+use crate::cpu::{CPU, MEMORY_SIZE};
+use crate::disasm::{disassemble_one, Line};
+use crate::expr;
+use crate::rewind::Rewinder;
+use crate::watch::{WatchKind, Watchpoint};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+const HELP: &str = "\
+break LOC              (b)  stop when execution reaches LOC
+delete [LOC]           (d)  remove the breakpoint at LOC, or every breakpoint
+breakpoints            (bl) list breakpoints
+watch KIND LOC [N]     (w)  pause when N bytes from LOC are read, written or changed; KIND is
+                            `read`, `write` or `change`
+watchlog KIND LOC [N]       like `watch`, but only log each access
+unwatch [N]                 remove watchpoint N, or every watchpoint
+watchpoints            (wl) list watchpoints
+step [N]               (s)  execute N instructions, 1 by default
+next                   (n)  execute until the next instruction, running through loops and interrupts
+continue               (c)  execute until a breakpoint, watchpoint or invalid instruction, or until
+                            the program halts
+step-back [N]          (sb) go back N instructions, 1 by default
+reverse-continue       (rc) go back to the last time a breakpoint was reached
+registers              (r)  show the registers, pc and cycle count
+examine LOC [N]        (x)  show N bytes of memory from LOC, 16 by default
+set A|B|C|D|pc VAL          change a register, or pc
+set LOC VAL...              change the bytes of memory from LOC
+disassemble [LOC] [N]  (l)  disassemble N instructions from LOC, or around pc by default
+quit                   (q)  stop debugging";
+
+/// How many bytes `examine` shows on each line
+const BYTES_PER_LINE: usize = 16;
+
+/// How many instructions `disassemble` shows before and after pc, when given no address
+const CONTEXT_BEFORE: usize = 3;
+const CONTEXT_AFTER: usize = 5;
+
+/// The longest an instruction can be, for finding where the ones before pc start
+const MAX_INSTRUCTION_SIZE: u16 = 4;
+
+pub struct Debugger {
+    pub cpu: CPU,
+    breakpoints: BTreeSet<u16>,
+    /// Whether each of `cpu.watchpoints` only logs, rather than pausing execution
+    log_only: Vec<bool>,
+    /// The command an empty line repeats
+    last_command: String,
+    /// Whether the program has halted or the window has been closed, so it can't run any more
+    stopped: bool,
+    /// Keeps the history that `step-back` and `reverse-continue` go back through
+    rewinder: Rewinder,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU) -> Self {
+        let mut rewinder = Rewinder::new();
+        rewinder.record(&cpu);
+
+        Self {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            log_only: Vec::new(),
+            last_command: String::new(),
+            stopped: false,
+            rewinder,
+        }
+    }
+
+    /// Runs a line typed at the prompt, and returns what to show for it. `quit` is left to the
+    /// caller.
+    pub fn execute(&mut self, line: &str) -> String {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let result = match words.as_slice() {
+            [] => Ok(String::new()),
+            ["help"] | ["h"] => Ok(HELP.to_string()),
+            ["break", location] | ["b", location] => self.add_breakpoint(location),
+            ["delete"] | ["d"] => {
+                self.breakpoints.clear();
+                Ok("Deleted every breakpoint".to_string())
+            }
+            ["delete", location] | ["d", location] => self.delete_breakpoint(location),
+            ["breakpoints"] | ["bl"] => Ok(self.list_breakpoints()),
+            ["watch", kind, location] | ["w", kind, location] => {
+                self.add_watchpoint(kind, location, "1", false)
+            }
+            ["watch", kind, location, count] | ["w", kind, location, count] => {
+                self.add_watchpoint(kind, location, count, false)
+            }
+            ["watchlog", kind, location] => self.add_watchpoint(kind, location, "1", true),
+            ["watchlog", kind, location, count] => self.add_watchpoint(kind, location, count, true),
+            ["unwatch"] => {
+                self.cpu.watchpoints.clear();
+                self.log_only.clear();
+                Ok("Deleted every watchpoint".to_string())
+            }
+            ["unwatch", index] => self.delete_watchpoint(index),
+            ["watchpoints"] | ["wl"] => Ok(self.list_watchpoints()),
+            ["step"] | ["s"] => self.step(1),
+            ["step", count] | ["s", count] => self.parse(count).and_then(|count| self.step(count)),
+            ["next"] | ["n"] => self.next(),
+            ["continue"] | ["c"] => self.run_until(|_| false),
+            ["step-back"] | ["sb"] => self.step_back(1),
+            ["step-back", count] | ["sb", count] => {
+                self.parse(count).and_then(|count| self.step_back(count))
+            }
+            ["reverse-continue"] | ["rc"] => self.reverse_continue(),
+            ["registers"] | ["r"] => Ok(self.registers()),
+            ["examine", location] | ["x", location] => self.examine(location, "16"),
+            ["examine", location, count] | ["x", location, count] => self.examine(location, count),
+            ["set", target, values @ ..] if !values.is_empty() => self.set(target, values),
+            ["disassemble"] | ["l"] => Ok(self.disassemble_around_pc()),
+            ["disassemble", location] | ["l", location] => self.disassemble(location, "8"),
+            ["disassemble", location, count] | ["l", location, count] => {
+                self.disassemble(location, count)
+            }
+            _ => Err(format!("unknown command `{}`; try `help`", line)),
+        };
+
+        result.unwrap_or_else(|e| format!("error: {}", e))
+    }
+
+    /// Works out an address or number, which can use labels and `pc`
+    fn parse(&self, text: &str) -> Result<i64, String> {
+        expr::parse(text)?.eval(&mut |name| {
+            if name.eq_ignore_ascii_case("pc") {
+                return Ok(self.cpu.pc as i64);
+            }
+
+            self.cpu
+                .debug_info
+                .symbols
+                .get(name)
+                .map(i64::from)
+                .ok_or_else(|| format!("there's no label called `{}`", name))
+        })
+    }
+
+    fn parse_address(&self, text: &str) -> Result<u16, String> {
+        let address = self.parse(text)?;
+
+        match address {
+            0..=0xFFFE => Ok(address as u16),
+            _ => Err(format!("{:#x} is not a valid address", address)),
+        }
+    }
+
+    fn add_breakpoint(&mut self, location: &str) -> Result<String, String> {
+        let address = self.parse_address(location)?;
+        self.breakpoints.insert(address);
+
+        Ok(format!(
+            "Breakpoint at {}",
+            self.cpu.debug_info.location(address)
+        ))
+    }
+
+    fn delete_breakpoint(&mut self, location: &str) -> Result<String, String> {
+        let address = self.parse_address(location)?;
+
+        if self.breakpoints.remove(&address) {
+            Ok(format!(
+                "Deleted the breakpoint at {}",
+                self.cpu.debug_info.location(address)
+            ))
+        } else {
+            Err(format!("there's no breakpoint at {:#06x}", address))
+        }
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints".to_string();
+        }
+
+        self.breakpoints
+            .iter()
+            .map(|&address| self.annotated_line(address))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn step(&mut self, count: i64) -> Result<String, String> {
+        let mut steps = 0;
+
+        self.run_until(|_| {
+            steps += 1;
+            steps >= count
+        })
+    }
+
+    /// Runs until the instruction after this one, which steps over interrupt handlers and runs
+    /// a loop that jumps back to here until it's done. If this one isn't valid, it's stepped
+    /// onto so the fault is reported.
+    fn next(&mut self) -> Result<String, String> {
+        let after = match self.cpu.try_fetch() {
+            Ok(instruction) => self.cpu.pc.wrapping_add(instruction.size() as u16),
+            Err(_) => return self.step(1),
+        };
+
+        self.run_until(|cpu| cpu.pc == after)
+    }
+
+    /// Executes at least one instruction, and then keeps going until `done` says to stop, a
+    /// breakpoint or watchpoint is hit, an invalid instruction is reached or the program stops.
+    /// Returns where it stopped, after anything logged by watchpoints on the way. The program
+    /// can carry on from a fault once pc or the instruction has been fixed with `set`.
+    fn run_until<F: FnMut(&CPU) -> bool>(&mut self, mut done: F) -> Result<String, String> {
+        if self.stopped {
+            return Err("the program isn't running any more".to_string());
+        }
+
+        let mut output = Vec::new();
+        loop {
+            let running = match self.cpu.try_step() {
+                Ok(running) => running,
+                Err(fault) => {
+                    output.push(format!("Fault: {}", fault));
+                    output.push(self.annotated_line(self.cpu.pc));
+                    break;
+                }
+            };
+            self.rewinder.record(&self.cpu);
+
+            let mut watched = false;
+            for hit in self.cpu.take_watch_hits() {
+                watched |= !self.log_only[hit.watchpoint];
+                output.push(hit.to_string());
+            }
+
+            if !running {
+                self.stopped = true;
+
+                let reason = if self.cpu.ppu.closed {
+                    "The window was closed"
+                } else {
+                    "The program halted"
+                };
+                output.push(format!("{} after {} cycles", reason, self.cpu.cycles));
+                break;
+            }
+
+            if self.breakpoints.contains(&self.cpu.pc) {
+                output.push("Breakpoint".to_string());
+            }
+            if watched || self.breakpoints.contains(&self.cpu.pc) || done(&self.cpu) {
+                output.push(self.annotated_line(self.cpu.pc));
+                break;
+            }
+        }
+
+        Ok(output.join("\n"))
+    }
+
+    fn step_back(&mut self, count: i64) -> Result<String, String> {
+        if count < 1 {
+            return Err(format!("can't step back {} instructions", count));
+        }
+
+        self.rewinder.step_back(&mut self.cpu, count as u64)?;
+        self.stopped = self.cpu.ppu.closed;
+
+        Ok(self.annotated_line(self.cpu.pc))
+    }
+
+    fn reverse_continue(&mut self) -> Result<String, String> {
+        let found = self
+            .rewinder
+            .reverse_continue(&mut self.cpu, &self.breakpoints)?;
+        self.stopped = self.cpu.ppu.closed;
+
+        let reason = if found {
+            "Breakpoint".to_string()
+        } else {
+            format!(
+                "Reached the oldest snapshot, {} instructions in",
+                self.cpu.instructions
+            )
+        };
+
+        Ok(format!("{}\n{}", reason, self.annotated_line(self.cpu.pc)))
+    }
+
+    /// Adds a watchpoint on `count` bytes from `location`, which pauses execution unless it's
+    /// `log_only`
+    fn add_watchpoint(
+        &mut self,
+        kind: &str,
+        location: &str,
+        count: &str,
+        log_only: bool,
+    ) -> Result<String, String> {
+        let kind = WatchKind::parse(kind)
+            .ok_or_else(|| format!("`{}` isn't `read`, `write` or `change`", kind))?;
+        let start = self.parse_address(location)?;
+        let count = self.parse(count)?;
+
+        let end = start as i64 + count - 1;
+        if count < 1 || end as usize >= MEMORY_SIZE {
+            return Err(format!("can't watch {} bytes from {:#06x}", count, start));
+        }
+
+        let watchpoint = Watchpoint::new(kind, start, end as u16);
+        self.cpu.watchpoints.push(watchpoint);
+        self.log_only.push(log_only);
+
+        Ok(format!(
+            "Watchpoint {}: {}",
+            self.cpu.watchpoints.len() - 1,
+            watchpoint
+        ))
+    }
+
+    fn delete_watchpoint(&mut self, index: &str) -> Result<String, String> {
+        let index = self.parse(index)?;
+        if index < 0 || index as usize >= self.cpu.watchpoints.len() {
+            return Err(format!("there's no watchpoint {}", index));
+        }
+
+        self.cpu.watchpoints.remove(index as usize);
+        self.log_only.remove(index as usize);
+
+        Ok(format!("Deleted watchpoint {}", index))
+    }
+
+    fn list_watchpoints(&self) -> String {
+        if self.cpu.watchpoints.is_empty() {
+            return "No watchpoints".to_string();
+        }
+
+        self.cpu
+            .watchpoints
+            .iter()
+            .zip(&self.log_only)
+            .enumerate()
+            .map(|(i, (watchpoint, &log_only))| {
+                let action = if log_only { "logs" } else { "pauses" };
+                format!("{}: {} ({})", i, watchpoint, action)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn registers(&self) -> String {
+        let cpu = &self.cpu;
+        let mut text = format!(
+            "A={:02X} B={:02X} C={:02X} D={:02X} pc={:04X} cycles={}",
+            cpu.registers[0],
+            cpu.registers[1],
+            cpu.registers[2],
+            cpu.registers[3],
+            cpu.pc,
+            cpu.cycles
+        );
+
+        if let Some(address) = cpu.interrupt_return {
+            write!(
+                text,
+                "\nIn an interrupt handler, returning to {}",
+                cpu.debug_info.location(address)
+            )
+            .unwrap();
+        }
+
+        text
+    }
+
+    fn examine(&self, location: &str, count: &str) -> Result<String, String> {
+        let start = self.parse_address(location)? as usize;
+        let count = self.parse(count)?.max(0) as usize;
+        let end = (start + count).min(MEMORY_SIZE);
+
+        let lines = (start..end)
+            .step_by(BYTES_PER_LINE)
+            .map(|line| {
+                let bytes = self.cpu.memory[line..(line + BYTES_PER_LINE).min(end)]
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                format!("{:04X}: {}", line, bytes)
+            })
+            .collect::<Vec<_>>();
+
+        Ok(lines.join("\n"))
+    }
+
+    fn set(&mut self, target: &str, values: &[&str]) -> Result<String, String> {
+        let register = ["A", "B", "C", "D"]
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(target));
+
+        if let Some(index) = register.filter(|_| values.len() == 1) {
+            self.cpu.registers[index] = self.parse_byte(values[0])?;
+            self.rewinder.checkpoint(&self.cpu);
+            return Ok(self.registers());
+        }
+
+        if target.eq_ignore_ascii_case("pc") && values.len() == 1 {
+            self.cpu.pc = self.parse_address(values[0])?;
+            self.rewinder.checkpoint(&self.cpu);
+            return Ok(self.annotated_line(self.cpu.pc));
+        }
+
+        let start = self.parse_address(target)?;
+        if start as usize + values.len() > MEMORY_SIZE {
+            return Err("that goes past the end of memory".to_string());
+        }
+
+        let bytes = values
+            .iter()
+            .map(|value| self.parse_byte(value))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, byte) in bytes.into_iter().enumerate() {
+            self.cpu.memory[start as usize + i] = byte;
+        }
+        self.rewinder.checkpoint(&self.cpu);
+
+        self.examine(target, &values.len().to_string())
+    }
+
+    fn parse_byte(&self, text: &str) -> Result<u8, String> {
+        let value = self.parse(text)?;
+
+        match value {
+            -128..=255 => Ok(value as u8),
+            _ => Err(format!("{} does not fit in 8 bits", value)),
+        }
+    }
+
+    fn disassemble(&self, location: &str, count: &str) -> Result<String, String> {
+        let start = self.parse_address(location)?;
+        let count = self.parse(count)?.max(0) as usize;
+
+        Ok(self.listing(start, count))
+    }
+
+    /// A few instructions either side of pc
+    fn disassemble_around_pc(&self) -> String {
+        let pc = self.cpu.pc;
+
+        // Instructions are different sizes, so there's no telling where the ones before pc start.
+        // Go with the furthest start that decodes cleanly and lines up with pc.
+        let start = (pc.saturating_sub(MAX_INSTRUCTION_SIZE * CONTEXT_BEFORE as u16)..pc)
+            .find(|&start| {
+                let mut address = start;
+                while address < pc {
+                    let line = self.line(address);
+                    if line.text.starts_with(".byte") {
+                        return false;
+                    }
+                    address += line.bytes.len() as u16;
+                }
+                address == pc
+            })
+            .unwrap_or(pc);
+
+        let mut before: usize = 0;
+        let mut address = start;
+        while address < pc {
+            before += 1;
+            address += self.line(address).bytes.len() as u16;
+        }
+
+        let skip = before.saturating_sub(CONTEXT_BEFORE);
+        let lines = self.listing(start, before + CONTEXT_AFTER);
+
+        lines.lines().skip(skip).collect::<Vec<_>>().join("\n")
+    }
+
+    /// Disassembles `count` instructions from `start`, marking pc with `=>` and breakpoints
+    /// with `*`
+    fn listing(&self, start: u16, count: usize) -> String {
+        let mut lines = Vec::new();
+        let mut address = start as usize;
+
+        while lines.len() < count && address < MEMORY_SIZE {
+            let line = self.line(address as u16);
+            let current = if line.address == self.cpu.pc {
+                "=>"
+            } else {
+                "  "
+            };
+            let breakpoint = if self.breakpoints.contains(&line.address) {
+                "*"
+            } else {
+                " "
+            };
+
+            address += line.bytes.len();
+            lines.push(format!(
+                "{}{} {}",
+                current,
+                breakpoint,
+                self.cpu.debug_info.annotate(&line)
+            ));
+        }
+
+        lines.join("\n")
+    }
+
+    /// Disassembles the instruction at `address`
+    fn line(&self, address: u16) -> Line {
+        disassemble_one(&self.cpu.memory[address as usize..], address)
+    }
+
+    /// Disassembles the instruction at `address`, with where it is in the source
+    fn annotated_line(&self, address: u16) -> String {
+        self.cpu.debug_info.annotate(&self.line(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_object;
+    use crate::debug_info::DebugInfo;
+    use crate::link::{link, MemoryMap};
+
+    const PROGRAM: &str = "
+        main:   LOAD A, 3
+        loop:   DEC A
+                EQ A, 0, B
+                JT B, done
+                LOAD C, 1
+                JT C, loop
+        done:   STORE [0x0200], A
+                HALT
+    ";
+
+    fn debugger() -> Debugger {
+        let object = assemble_object(PROGRAM).unwrap();
+        let image = link(&[object], &MemoryMap::default()).unwrap();
+
+        let mut cpu = CPU::headless();
+        cpu.load(image.data);
+        cpu.debug_info = DebugInfo::new(image.symbols, image.lines);
+
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+
+        assert_eq!(
+            debugger.execute("break loop"),
+            "Breakpoint at 0x8003 (loop at line 3)"
+        );
+        assert_eq!(
+            debugger.execute("b done"),
+            "Breakpoint at 0x8014 (done at line 8)"
+        );
+        assert_eq!(
+            debugger.execute("continue"),
+            "Breakpoint\n8003: 51 00        DEC A             ; loop at line 3"
+        );
+        assert_eq!(debugger.cpu.registers[0], 3);
+
+        // An empty line continues again, around the loop
+        assert_eq!(
+            debugger.execute(""),
+            "Breakpoint\n8003: 51 00        DEC A             ; loop at line 3"
+        );
+        assert_eq!(debugger.cpu.registers[0], 2);
+
+        assert_eq!(
+            debugger.execute("delete loop"),
+            "Deleted the breakpoint at 0x8003 (loop at line 3)"
+        );
+        assert_eq!(
+            debugger.execute("bl"),
+            "8014: 20 02 00 00  STORE 0x0200, A   ; done at line 8"
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            "Breakpoint\n8014: 20 02 00 00  STORE 0x0200, A   ; done at line 8"
+        );
+        assert_eq!(debugger.execute("c"), "The program halted after 52 cycles");
+        assert_eq!(
+            debugger.execute("c"),
+            "error: the program isn't running any more"
+        );
+        assert_eq!(
+            debugger.execute("delete 0x8000"),
+            "error: there's no breakpoint at 0x8000"
+        );
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut debugger = debugger();
+
+        assert_eq!(
+            debugger.execute("s"),
+            "8003: 51 00        DEC A             ; loop at line 3"
+        );
+        assert_eq!(
+            debugger.execute("step 3"),
+            "800D: 10 02 01     LOAD C, 0x01      ; loop+0xa at line 6"
+        );
+
+        // `next` over the jump back runs the rest of the loop
+        debugger.execute("s");
+        assert_eq!(
+            debugger.execute("n"),
+            "8014: 20 02 00 00  STORE 0x0200, A   ; done at line 8"
+        );
+        assert_eq!(
+            debugger.execute("registers"),
+            "A=00 B=01 C=01 D=00 pc=8014 cycles=47"
+        );
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.execute("set 0x0200 1 2 0xFF"), "0200: 01 02 FF");
+        assert_eq!(
+            debugger.execute("x 0x01FE 20"),
+            "01FE: 00 00 01 02 FF 00 00 00 00 00 00 00 00 00 00 00\n\
+             020E: 00 00 00 00"
+        );
+        assert_eq!(
+            debugger.execute("set B -1"),
+            "A=00 B=FF C=00 D=00 pc=8000 cycles=0"
+        );
+        assert_eq!(
+            debugger.execute("set pc done"),
+            "8014: 20 02 00 00  STORE 0x0200, A   ; done at line 8"
+        );
+        assert_eq!(
+            debugger.execute("set 0x0200 256"),
+            "error: 256 does not fit in 8 bits"
+        );
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut debugger = debugger();
+        debugger.execute("b done");
+        debugger.execute("step 5");
+
+        assert_eq!(
+            debugger.execute("l"),
+            "    8005: 31 00 00 01  EQ A, 0x00, B     ; loop+0x2 at line 4\n    \
+             8009: 40 01 80 14  JT B, 0x8014      ; loop+0x6 at line 5\n    \
+             800D: 10 02 01     LOAD C, 0x01      ; loop+0xa at line 6\n\
+             =>  8010: 40 02 80 03  JT C, 0x8003      ; loop+0xd at line 7\n  \
+             * 8014: 20 02 00 00  STORE 0x0200, A   ; done at line 8\n    \
+             8018: 00           HALT              ; done+0x4 at line 9\n    \
+             8019: 00           HALT              ; done+0x5\n    \
+             801A: 00           HALT              ; done+0x6"
+        );
+        assert_eq!(
+            debugger.execute("disassemble main 2"),
+            "    8000: 10 00 03     LOAD A, 0x03      ; main at line 2\n    \
+             8003: 51 00        DEC A             ; loop at line 3"
+        );
+    }
+
+    #[test]
+    fn test_rewinding() {
+        let mut debugger = debugger();
+
+        debugger.execute("break loop");
+        debugger.execute("continue");
+        debugger.execute("continue");
+        assert_eq!(debugger.cpu.registers[0], 2);
+
+        assert_eq!(
+            debugger.execute("rc"),
+            "Breakpoint\n8003: 51 00        DEC A             ; loop at line 3"
+        );
+        assert_eq!(debugger.cpu.registers[0], 3);
+        assert_eq!(
+            debugger.execute("reverse-continue"),
+            "Reached the oldest snapshot, 0 instructions in\n\
+             8000: 10 00 03     LOAD A, 0x03      ; main at line 2"
+        );
+
+        // Going back after the program halts lets it run again
+        debugger.execute("delete");
+        assert_eq!(debugger.execute("c"), "The program halted after 52 cycles");
+        assert_eq!(
+            debugger.execute("step-back"),
+            "8018: 00           HALT              ; done+0x4 at line 9"
+        );
+        assert_eq!(
+            debugger.execute("sb 2"),
+            "8009: 40 01 80 14  JT B, 0x8014      ; loop+0x6 at line 5"
+        );
+        assert_eq!(
+            debugger.execute("s 2"),
+            "8018: 00           HALT              ; done+0x4 at line 9"
+        );
+
+        // Changes made by hand are kept when going back to after them
+        debugger.execute("set B 0x42");
+        assert_eq!(debugger.execute("s"), "The program halted after 52 cycles");
+        assert_eq!(
+            debugger.execute("sb"),
+            "8018: 00           HALT              ; done+0x4 at line 9"
+        );
+        assert_eq!(debugger.cpu.registers[1], 0x42);
+
+        assert_eq!(
+            debugger.execute("sb 100"),
+            "error: that's before the program started"
+        );
+        assert_eq!(
+            debugger.execute("sb 0"),
+            "error: can't step back 0 instructions"
+        );
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        debugger.execute("set 0x0200 5");
+
+        assert_eq!(
+            debugger.execute("watch change 0x0200 0x400"),
+            "Watchpoint 0: change 0x0200-0x05ff"
+        );
+        assert_eq!(
+            debugger.execute("watchlog write 0x0200"),
+            "Watchpoint 1: write 0x0200"
+        );
+        assert_eq!(
+            debugger.execute("wl"),
+            "0: change 0x0200-0x05ff (pauses)\n1: write 0x0200 (logs)"
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            "Watchpoint 0: 0x0200 changed from 0x05 to 0x00 at 0x8014\n\
+             Watchpoint 1: wrote 0x00 to 0x0200 (was 0x05) at 0x8014\n\
+             8018: 00           HALT              ; done+0x4 at line 9"
+        );
+
+        assert_eq!(debugger.execute("unwatch 0"), "Deleted watchpoint 0");
+        assert_eq!(
+            debugger.execute("unwatch 3"),
+            "error: there's no watchpoint 3"
+        );
+        assert_eq!(
+            debugger.execute("watch read 0xFFFE 2"),
+            "error: can't watch 2 bytes from 0xfffe"
+        );
+        assert_eq!(
+            debugger.execute("watch sniff 0x0200"),
+            "error: `sniff` isn't `read`, `write` or `change`"
+        );
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut debugger = debugger();
+        debugger.execute("set done 0xEE");
+
+        let fault = "Fault: Unknown opcode: 0xee at 0x8014 (done at line 8)\n\
+                     8014: EE           .byte 0xEE        ; done at line 8";
+        assert_eq!(debugger.execute("c"), fault);
+        assert_eq!(debugger.cpu.pc, 0x8014);
+
+        // The session carries on, and so can the program once it's fixed
+        assert_eq!(debugger.execute("n"), fault);
+        assert_eq!(debugger.execute("s"), fault);
+        debugger.execute("set done 0x20");
+        assert_eq!(debugger.execute("c"), "The program halted after 52 cycles");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut debugger = debugger();
+
+        assert_eq!(
+            debugger.execute("frobnicate"),
+            "error: unknown command `frobnicate`; try `help`"
+        );
+        assert_eq!(
+            debugger.execute("b nowhere"),
+            "error: there's no label called `nowhere`"
+        );
+        assert_eq!(
+            debugger.execute("x 0x10000"),
+            "error: 0x10000 is not a valid address"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::instruction::decode;
+use std::fmt;
+
+/// A single disassembled instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// The instruction as the assembler would accept it
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        write!(f, "{:04X}: {:<12} {}", self.address, bytes, self.text)
+    }
+}
+
+/// Disassembles all of `bytes`, the first of which is at `address`. To disassemble part of a
+/// CPU's memory, pass `&cpu.memory[start..end]` and `start`.
+pub fn disassemble(bytes: &[u8], address: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let line = disassemble_one(&bytes[offset..], address.wrapping_add(offset as u16));
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Disassembles the instruction at the start of `bytes`, which is at `address`. Anything that
+/// isn't a valid instruction comes out as a single `.byte`.
+pub fn disassemble_one(bytes: &[u8], address: u16) -> Line {
+    match decode(bytes) {
+        Ok(instruction) => Line {
+            address,
+            bytes: bytes[..instruction.size()].to_vec(),
+            text: instruction.to_string(),
+        },
+        Err(_) => Line {
+            address,
+            bytes: bytes[..1].to_vec(),
+            text: format!(".byte 0x{:02X}", bytes[0]),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn test_listing() {
+        let lines = disassemble(&[0x10, 0x00, 0xFF, 0x20, 0x02, 0x00, 0x00, 0x00], 0x8000);
+
+        assert_eq!(
+            lines.iter().map(Line::to_string).collect::<Vec<_>>(),
+            vec![
+                "8000: 10 00 FF     LOAD A, 0xFF",
+                "8003: 20 02 00 00  STORE 0x0200, A",
+                "8007: 00           HALT",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "
+            HALT
+            NOP
+            LOAD A, 0xFF
+            LOAD B, C
+            LOAD C, [0x00AB]
+            STORE 0x00AB, D
+            EQ A, B, C
+            EQ A, 0x05, C
+            GT A, B, C
+            LT A, B, C
+            JT A, 0x8005
+            RETI
+            INC B
+            DEC C
+            ADD A, B, C
+            SUB A, B, C
+            ADD A, 0x0A, B
+            SUB A, 0x0A, B
+        ";
+        let program = assemble(source).unwrap();
+
+        let text = disassemble(&program, 0x8000)
+            .into_iter()
+            .map(|line| line.text)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            text,
+            source
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(assemble(&text.join("\n")).unwrap(), program);
+    }
+
+    #[test]
+    fn test_invalid_instructions() {
+        let lines = disassemble(&[0x42, 0x50, 0x07, 0x10, 0x00], 0x8000);
+
+        assert_eq!(
+            lines
+                .iter()
+                .map(|line| line.text.as_str())
+                .collect::<Vec<_>>(),
+            // An unknown opcode, a bad register, and an instruction cut short by the end
+            vec![
+                ".byte 0x42",
+                ".byte 0x50",
+                ".byte 0x07",
+                ".byte 0x10",
+                "HALT"
+            ]
+        );
+        assert_eq!(lines[4].address, 0x8004);
+    }
+}
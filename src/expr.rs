@@ -0,0 +1,286 @@
+//! Integer expressions, as used in assembler operands and directives, e.g. `SCREEN + 32 * y`.
+//!
+//! Operators follow C's precedence, from loosest to tightest: `|`, `^`, `&`, `==` `!=`,
+//! `<` `>` `<=` `>=`, `<<` `>>`, `+` `-`, then `*` `/` `%`, with unary `-`, `~` and `!` above them
+//! all. Comparisons give 1 or 0. Numbers can be decimal, `0x` hex, `0b` binary or a character in
+//! single quotes.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// Binary operators, grouped from loosest to tightest binding
+const PRECEDENCE: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+const UNARY: &[&str] = &["-", "~", "!"];
+
+/// Every operator, with longer ones first so `<<` isn't read as two `<`s
+const OPERATORS: &[&str] = &[
+    "<<", ">>", "==", "!=", "<=", ">=", "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "~", "!",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+impl Expr {
+    /// Works out the value of the expression, using `symbol` to look up any names in it
+    pub fn eval<F>(&self, symbol: &mut F) -> Result<i64, String>
+    where
+        F: FnMut(&str) -> Result<i64, String>,
+    {
+        Ok(match self {
+            Expr::Number(number) => *number,
+            Expr::Symbol(name) => symbol(name)?,
+            Expr::Unary(operator, operand) => {
+                let operand = operand.eval(symbol)?;
+
+                match *operator {
+                    "-" => operand.wrapping_neg(),
+                    "~" => !operand,
+                    _ => i64::from(operand == 0),
+                }
+            }
+            Expr::Binary(operator, lhs, rhs) => {
+                let lhs = lhs.eval(symbol)?;
+                let rhs = rhs.eval(symbol)?;
+
+                match *operator {
+                    "|" => lhs | rhs,
+                    "^" => lhs ^ rhs,
+                    "&" => lhs & rhs,
+                    "==" => i64::from(lhs == rhs),
+                    "!=" => i64::from(lhs != rhs),
+                    "<=" => i64::from(lhs <= rhs),
+                    ">=" => i64::from(lhs >= rhs),
+                    "<" => i64::from(lhs < rhs),
+                    ">" => i64::from(lhs > rhs),
+                    "<<" | ">>" if !(0..64).contains(&rhs) => {
+                        return Err(format!("can't shift by {}", rhs))
+                    }
+                    "<<" => lhs << rhs,
+                    ">>" => lhs >> rhs,
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" | "%" if rhs == 0 => return Err("division by zero".to_string()),
+                    "/" => lhs.wrapping_div(rhs),
+                    _ => lhs.wrapping_rem(rhs),
+                }
+            }
+        })
+    }
+}
+
+/// Parses `text` as a whole expression
+pub fn parse(text: &str) -> Result<Expr, String> {
+    let error = || format!("invalid expression `{}`", text);
+
+    let tokens = tokenize(text).ok_or_else(error)?;
+    let mut parser = Parser { tokens, pos: 0 };
+
+    let expr = parser.binary(0).ok_or_else(error)?;
+    if parser.pos != parser.tokens.len() {
+        return Err(error());
+    }
+
+    Ok(expr)
+}
+
+/// Parses a number on its own, such as `0x1F`, `0b101` or `-3`
+pub fn parse_number(text: &str) -> Option<i64> {
+    let (digits, negative) = match text.strip_prefix('-') {
+        Some(digits) => (digits, true),
+        None => (text, false),
+    };
+
+    let number = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        i64::from_str_radix(binary, 2)
+    } else {
+        digits.parse()
+    }
+    .ok()?;
+
+    Some(if negative { -number } else { number })
+}
+
+/// Reads a possibly escaped character from the start of `text`, returning it and how many bytes
+/// it took up. Understands `\n`, `\r`, `\t`, `\0`, `\\`, `\'`, `\"` and `\xNN`.
+pub fn parse_char(text: &str) -> Option<(u8, usize)> {
+    let mut chars = text.chars();
+
+    match chars.next()? {
+        '\\' => Some(match chars.next()? {
+            'n' => (b'\n', 2),
+            'r' => (b'\r', 2),
+            't' => (b'\t', 2),
+            '0' => (0, 2),
+            'x' => (u8::from_str_radix(text.get(2..4)?, 16).ok()?, 4),
+            c if c == '\\' || c == '\'' || c == '"' => (c as u8, 2),
+            _ => return None,
+        }),
+        c if c.is_ascii() => Some((c as u8, 1)),
+        _ => None,
+    }
+}
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            rest = &rest[1..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+
+            tokens.push(if c.is_ascii_digit() {
+                Token::Number(parse_number(word)?)
+            } else {
+                Token::Symbol(word.to_string())
+            });
+            rest = &rest[end..];
+        } else if c == '\'' {
+            let (value, size) = parse_char(&rest[1..])?;
+            if !rest[1 + size..].starts_with('\'') {
+                return None;
+            }
+
+            tokens.push(Token::Number(value as i64));
+            rest = &rest[size + 2..];
+        } else {
+            let operator = OPERATORS
+                .iter()
+                .find(|operator| rest.starts_with(*operator))?;
+
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Some(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+
+        token
+    }
+
+    /// Parses operators at `level` of `PRECEDENCE` and tighter
+    fn binary(&mut self, level: usize) -> Option<Expr> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+
+        loop {
+            let operator = match self.tokens.get(self.pos) {
+                Some(Token::Operator(operator)) if PRECEDENCE[level].contains(operator) => {
+                    *operator
+                }
+                _ => break,
+            };
+            self.pos += 1;
+
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(operator, Box::new(lhs), Box::new(rhs));
+        }
+
+        Some(lhs)
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        match self.next()? {
+            Token::Operator(operator) if UNARY.contains(&operator) => {
+                Some(Expr::Unary(operator, Box::new(self.unary()?)))
+            }
+            Token::Number(number) => Some(Expr::Number(number)),
+            Token::Symbol(name) => Some(Expr::Symbol(name)),
+            Token::Open => {
+                let expr = self.binary(0)?;
+
+                match self.next()? {
+                    Token::Close => Some(expr),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<i64, String> {
+        parse(text)?.eval(&mut |name| match name {
+            "SCREEN" => Ok(0x0200),
+            _ => Err(format!("undefined symbol `{}`", name)),
+        })
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("SCREEN + 32 * 2 - 1"), Ok(0x0200 + 63));
+        assert_eq!(eval("1 << 4 | 1"), Ok(17));
+        assert_eq!(eval("SCREEN >> 8 == 2"), Ok(1));
+        assert_eq!(eval("-0x10 + ~0 + !0"), Ok(-16));
+        assert_eq!(eval("'A' + 1"), Ok(66));
+        assert_eq!(eval("'\\n'"), Ok(10));
+        assert_eq!(eval("0b101 % 3"), Ok(2));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval("1 +"), Err("invalid expression `1 +`".to_string()));
+        assert_eq!(eval("(1"), Err("invalid expression `(1`".to_string()));
+        assert_eq!(eval("0xZZ"), Err("invalid expression `0xZZ`".to_string()));
+        assert_eq!(eval("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(eval("1 << 64"), Err("can't shift by 64".to_string()));
+        assert_eq!(eval("FOO"), Err("undefined symbol `FOO`".to_string()));
+    }
+}
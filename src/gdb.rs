@@ -0,0 +1,593 @@
+//! A stub for the GDB remote serial protocol, so GDB (or anything else that speaks it) can debug
+//! a program over TCP.
+//!
+//! Registers are sent in this order, as Big Endian like the rest of the machine: `a`, `b`, `c`
+//! and `d` (8 bits each), `pc` (16 bits) and `flags` (8 bits). The CPU has no flags register of
+//! its own, so `flags` is made up: bit 0 is set while in an interrupt handler. Writes to it are
+//! ignored.
+//!
+//! Supported packets are `?`, `g`, `G`, `p`, `P`, `m`, `M`, `s`, `c`, `Z0`/`Z1` and `z0`/`z1`
+//! (breakpoints), `D`, `k`, and the `qSupported`, `qAttached` and `qXfer:features:read` queries
+//! needed to send the target description. Anything else gets the empty reply, which GDB takes to
+//! mean it isn't supported.
+
+use crate::cpu::{CPU, MEMORY_SIZE};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// The target description, which tells GDB what the registers are
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.maxemu.cpu">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="flags" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// The register numbers of `pc` and `flags`
+const PC: usize = 4;
+const FLAGS: usize = 5;
+
+/// Set in `flags` while in an interrupt handler
+const FLAG_INTERRUPT: u8 = 0b0000_0001;
+
+/// The signals stop replies report
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// How many instructions run between checks for GDB interrupting a `c`
+const INTERRUPT_CHECK_INTERVAL: u32 = 4096;
+
+/// The largest packet we accept, which `qSupported` tells GDB
+const PACKET_SIZE: usize = 0x1000;
+
+/// What to do after handling a packet
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Packet(String),
+    /// Reply, and then close the connection
+    Close(String),
+}
+
+pub struct GdbStub {
+    pub cpu: CPU,
+    breakpoints: BTreeSet<u16>,
+    /// Why the program last stopped, as a stop reply
+    stop_reason: String,
+    /// Whether the program has halted or the window has been closed
+    exited: bool,
+}
+
+impl GdbStub {
+    pub fn new(cpu: CPU) -> Self {
+        Self {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            stop_reason: format!("S{:02x}", SIGTRAP),
+            exited: false,
+        }
+    }
+
+    /// Handles the contents of a packet from GDB. While continuing, `interrupted` is called every
+    /// so often to check whether GDB wants it to stop.
+    pub fn handle<F: FnMut() -> bool>(&mut self, packet: &str, interrupted: F) -> Reply {
+        let command = packet.chars().next().unwrap_or_default();
+        let args = &packet[command.len_utf8().min(packet.len())..];
+
+        let reply = match command {
+            '?' => self.stop_reason.clone(),
+            'g' => self.read_registers(),
+            'G' => self.write_registers(args),
+            'p' => self.read_register(args),
+            'P' => self.write_register(args),
+            'm' => self.read_memory(args),
+            'M' => self.write_memory(args),
+            's' | 'c' if self.exited => self.stop_reason.clone(),
+            's' => self.resume(|_| true, || false),
+            'c' => self.resume(|_| false, interrupted),
+            'Z' | 'z' => self.breakpoint(command == 'Z', args),
+            'D' => return Reply::Close("OK".to_string()),
+            'k' => return Reply::Close(String::new()),
+            'H' => "OK".to_string(),
+            'q' => self.query(args),
+            _ => String::new(),
+        };
+
+        Reply::Packet(reply)
+    }
+
+    fn registers(&self) -> Vec<u8> {
+        let flags = if self.cpu.interrupt_return.is_some() {
+            FLAG_INTERRUPT
+        } else {
+            0
+        };
+
+        let mut registers = self.cpu.registers.to_vec();
+        registers.extend_from_slice(&self.cpu.pc.to_be_bytes());
+        registers.push(flags);
+        registers
+    }
+
+    fn read_registers(&self) -> String {
+        to_hex(&self.registers())
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        match from_hex(args) {
+            Some(bytes) if bytes.len() == self.registers().len() => {
+                self.cpu.registers.copy_from_slice(&bytes[..4]);
+                self.cpu.pc = u16::from_be_bytes([bytes[4], bytes[5]]);
+                "OK".to_string()
+            }
+            _ => error(1),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let registers = self.registers();
+
+        match usize::from_str_radix(args, 16) {
+            Ok(PC) => to_hex(&registers[PC..PC + 2]),
+            Ok(FLAGS) => to_hex(&registers[FLAGS + 1..]),
+            Ok(n) if n < 4 => to_hex(&registers[n..n + 1]),
+            _ => error(1),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let (n, value) = match args.split_once('=') {
+            Some((n, value)) => (usize::from_str_radix(n, 16).ok(), from_hex(value)),
+            None => return error(1),
+        };
+
+        match (n, value.as_deref()) {
+            (Some(n), Some(&[value])) if n < 4 => self.cpu.registers[n] = value,
+            (Some(PC), Some(&[hi, lo])) => self.cpu.pc = u16::from_be_bytes([hi, lo]),
+            (Some(FLAGS), Some(&[_])) => (),
+            _ => return error(1),
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        match memory_range(args) {
+            Some((start, length)) => to_hex(&self.cpu.memory[start..start + length]),
+            None => error(1),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let (range, data) = match args.split_once(':') {
+            Some(parts) => parts,
+            None => return error(1),
+        };
+
+        match (memory_range(range), from_hex(data)) {
+            (Some((start, length)), Some(data)) if data.len() == length => {
+                self.cpu.memory[start..start + length].copy_from_slice(&data);
+                "OK".to_string()
+            }
+            _ => error(1),
+        }
+    }
+
+    /// Runs until `done` says to stop, a breakpoint or invalid instruction is reached, the
+    /// program stops or GDB interrupts, and returns the stop reply. An invalid instruction is
+    /// reported as SIGILL, with pc left on it.
+    fn resume<D, I>(&mut self, mut done: D, mut interrupted: I) -> String
+    where
+        D: FnMut(&CPU) -> bool,
+        I: FnMut() -> bool,
+    {
+        let mut until_check = INTERRUPT_CHECK_INTERVAL;
+
+        self.stop_reason = loop {
+            let running = match self.cpu.try_step() {
+                Ok(running) => running,
+                Err(_) => break format!("S{:02x}", SIGILL),
+            };
+            if !running {
+                self.exited = true;
+                break format!("W{:02x}", self.cpu.registers[0]);
+            }
+
+            if self.breakpoints.contains(&self.cpu.pc) || done(&self.cpu) {
+                break format!("S{:02x}", SIGTRAP);
+            }
+
+            until_check -= 1;
+            if until_check == 0 {
+                if interrupted() {
+                    break format!("S{:02x}", SIGINT);
+                }
+                until_check = INTERRUPT_CHECK_INTERVAL;
+            }
+        };
+
+        self.stop_reason.clone()
+    }
+
+    /// Adds or removes a software or hardware breakpoint, which are the same thing here
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let fields = args.split(',').collect::<Vec<_>>();
+        let address = match fields.as_slice() {
+            ["0", address, _] | ["1", address, _] => u16::from_str_radix(address, 16).ok(),
+            _ => return String::new(),
+        };
+
+        match address {
+            Some(address) if insert => {
+                self.breakpoints.insert(address);
+            }
+            Some(address) => {
+                self.breakpoints.remove(&address);
+            }
+            None => return error(1),
+        }
+
+        "OK".to_string()
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+        }
+        if args == "Attached" {
+            return "1".to_string();
+        }
+
+        match args.strip_prefix("Xfer:features:read:target.xml:") {
+            Some(range) => match range.split_once(',').and_then(|(offset, length)| {
+                let offset = usize::from_str_radix(offset, 16).ok()?;
+                let length = usize::from_str_radix(length, 16).ok()?;
+                Some((offset, length))
+            }) {
+                Some((offset, length)) => {
+                    let start = offset.min(TARGET_XML.len());
+                    let end = start.saturating_add(length).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+
+                    format!("{}{}", more, &TARGET_XML[start..end])
+                }
+                None => error(1),
+            },
+            None => String::new(),
+        }
+    }
+}
+
+/// Waits for GDB to connect to `address`, then debugs `stub` until it detaches or kills it
+pub fn listen<A: ToSocketAddrs>(address: A, stub: &mut GdbStub) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+
+    serve(stream, stub)
+}
+
+/// Debugs `stub` over a connection to GDB, until it detaches or kills it
+pub fn serve(mut stream: TcpStream, stub: &mut GdbStub) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    loop {
+        let packet = match read_packet(&mut reader, &mut stream)? {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+
+        let reply = stub.handle(&packet, || interrupt_pending(&mut reader));
+        match reply {
+            Reply::Packet(reply) => write_packet(&mut stream, &reply)?,
+            Reply::Close(reply) => {
+                write_packet(&mut stream, &reply)?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Reads the next packet, acknowledging it. Returns `None` once the connection is closed.
+fn read_packet<R: BufRead, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<Option<String>> {
+    let mut bytes = reader.bytes();
+
+    loop {
+        // Skip acks, and interrupts that arrive while we're already stopped
+        match bytes.next().transpose()? {
+            Some(b'$') => (),
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+
+        // GDB was told how long packets can be, so anything longer isn't coming from it
+        let mut data = Vec::new();
+        loop {
+            match bytes.next().transpose()? {
+                Some(b'#') => break,
+                Some(byte) if data.len() < PACKET_SIZE => data.push(byte),
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("packet is longer than {:#x} bytes", PACKET_SIZE),
+                    ))
+                }
+                None => return Ok(None),
+            }
+        }
+
+        let mut checksum = [0; 2];
+        for digit in &mut checksum {
+            *digit = match bytes.next().transpose()? {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+        }
+
+        let valid = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+            == Some(self::checksum(&data));
+        if valid {
+            writer.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()));
+        }
+
+        writer.write_all(b"-")?;
+    }
+}
+
+fn write_packet<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    writer.write_all(encode(data).as_bytes())?;
+    writer.flush()
+}
+
+/// Checks, without waiting, whether GDB has sent an interrupt (a 0x03 byte)
+fn interrupt_pending(reader: &mut BufReader<TcpStream>) -> bool {
+    if reader.buffer().contains(&0x03) {
+        let buffered = reader.buffer().len();
+        reader.consume(buffered);
+        return true;
+    }
+
+    let stream = reader.get_mut();
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0];
+    let interrupted = matches!(stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+    let _ = stream.set_nonblocking(false);
+
+    interrupted
+}
+
+/// Frames `data` as a packet
+pub fn encode(data: &str) -> String {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data.as_bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.extend_from_slice(&[b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+
+    format!(
+        "${}#{:02x}",
+        String::from_utf8_lossy(&escaped),
+        checksum(&escaped)
+    )
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte == b'}' {
+            if let Some(&escaped) = bytes.next() {
+                unescaped.push(escaped ^ 0x20);
+            }
+        } else {
+            unescaped.push(byte);
+        }
+    }
+
+    unescaped
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Parses `ADDR,LENGTH` in hex, if it's all inside memory
+fn memory_range(text: &str) -> Option<(usize, usize)> {
+    let (start, length) = text.split_once(',')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+
+    start
+        .checked_add(length)
+        .filter(|&end| end <= MEMORY_SIZE)
+        .map(|_| (start, length))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn stub() -> GdbStub {
+        let program = assemble(
+            "
+                LOAD A, 2
+            loop:
+                DEC A
+                EQ A, 0, B
+                JT B, done
+                LOAD C, 1
+                JT C, loop
+            done:
+                STORE [0x0200], B
+                HALT
+            ",
+        )
+        .unwrap();
+
+        let mut cpu = CPU::headless();
+        cpu.load(program);
+        GdbStub::new(cpu)
+    }
+
+    fn handle(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet, || false) {
+            Reply::Packet(reply) | Reply::Close(reply) => reply,
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut stub = stub();
+
+        assert_eq!(handle(&mut stub, "g"), "00000000800000");
+        assert_eq!(handle(&mut stub, "G0102030480100a"), "OK");
+        assert_eq!(stub.cpu.registers, [1, 2, 3, 4]);
+        assert_eq!(stub.cpu.pc, 0x8010);
+
+        assert_eq!(handle(&mut stub, "P2=ff"), "OK");
+        assert_eq!(handle(&mut stub, "P4=8003"), "OK");
+        assert_eq!(handle(&mut stub, "p2"), "ff");
+        assert_eq!(handle(&mut stub, "p4"), "8003");
+        assert_eq!(handle(&mut stub, "p5"), "00");
+        assert_eq!(handle(&mut stub, "p9"), "E01");
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut stub = stub();
+
+        assert_eq!(handle(&mut stub, "m8000,4"), "10000251");
+        assert_eq!(handle(&mut stub, "M0200,3:0a0b0c"), "OK");
+        assert_eq!(handle(&mut stub, "m01ff,5"), "000a0b0c00");
+        assert_eq!(handle(&mut stub, "mfffe,2"), "E01");
+        assert_eq!(handle(&mut stub, "mffffffffffffffff,1"), "E01");
+        assert_eq!(handle(&mut stub, "M0200,2:0a"), "E01");
+    }
+
+    #[test]
+    fn test_execution() {
+        // An invalid instruction stops the program rather than the session, with pc left on it
+        let mut faulty = stub();
+        faulty.cpu.memory[0x8003] = 0xEE;
+        assert_eq!(handle(&mut faulty, "s"), "S05");
+        assert_eq!(handle(&mut faulty, "c"), "S04");
+        assert_eq!(faulty.cpu.pc, 0x8003);
+        assert_eq!(handle(&mut faulty, "?"), "S04");
+        assert_eq!(handle(&mut faulty, "s"), "S04");
+        assert_eq!(faulty.cpu.pc, 0x8003);
+
+        let mut stub = stub();
+
+        assert_eq!(handle(&mut stub, "?"), "S05");
+        assert_eq!(handle(&mut stub, "s"), "S05");
+        assert_eq!(stub.cpu.pc, 0x8003);
+
+        assert_eq!(handle(&mut stub, "Z0,8003,1"), "OK");
+        assert_eq!(handle(&mut stub, "c"), "S05");
+        assert_eq!(stub.cpu.pc, 0x8003);
+        assert_eq!(stub.cpu.registers[0], 1);
+
+        assert_eq!(handle(&mut stub, "z0,8003,1"), "OK");
+        assert_eq!(handle(&mut stub, "c"), "W00");
+        assert_eq!(stub.cpu.memory[0x0200], 1);
+        assert_eq!(handle(&mut stub, "?"), "W00");
+        assert_eq!(handle(&mut stub, "s"), "W00");
+
+        assert_eq!(handle(&mut stub, "Z2,0200,1"), "");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut cpu = CPU::headless();
+        cpu.load(assemble("loop: LOAD A, 1\nJT A, loop").unwrap());
+        let mut stub = GdbStub::new(cpu);
+
+        assert_eq!(stub.handle("c", || true), Reply::Packet("S02".to_string()));
+        assert_eq!(stub.cpu.cycles, INTERRUPT_CHECK_INTERVAL as u64 / 2 * 7);
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut stub = stub();
+
+        assert_eq!(
+            handle(&mut stub, "qSupported:multiprocess+;swbreak+"),
+            "PacketSize=1000;qXfer:features:read+"
+        );
+        assert_eq!(handle(&mut stub, "qAttached"), "1");
+        assert_eq!(handle(&mut stub, "vMustReplyEmpty"), "");
+
+        let start = handle(&mut stub, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(start, "m<?xml version=\"1");
+        let end = handle(&mut stub, "qXfer:features:read:target.xml:10,1000");
+        assert!(end.starts_with('l'));
+        assert_eq!(format!("{}{}", &start[1..], &end[1..]), TARGET_XML);
+
+        assert_eq!(stub.handle("D", || false), Reply::Close("OK".to_string()));
+    }
+
+    #[test]
+    fn test_packets() {
+        assert_eq!(encode("OK"), "$OK#9a");
+        assert_eq!(encode("a}b"), "$a}]b#9d");
+
+        let mut input = &b"+$m8000,4#95$g#00$g#67"[..];
+        let mut acks = Vec::new();
+
+        assert_eq!(
+            read_packet(&mut input, &mut acks).unwrap(),
+            Some("m8000,4".to_string())
+        );
+        // A packet with a bad checksum is refused, and the next one is read instead
+        assert_eq!(
+            read_packet(&mut input, &mut acks).unwrap(),
+            Some("g".to_string())
+        );
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), None);
+        assert_eq!(acks, b"+-+");
+
+        let long = format!("${}#00", "g".repeat(PACKET_SIZE + 1));
+        assert_eq!(
+            read_packet(&mut long.as_bytes(), &mut acks)
+                .unwrap_err()
+                .to_string(),
+            "packet is longer than 0x1000 bytes"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Golden-image testing: programs are run headless for a number of frames, and what the PPU drew
+//! is compared against a reference PNG stored in `tests/golden`. Other output, like audio, can be
+//! compared byte for byte against a reference file in the same place.
+//!
+//! To create or update a reference file, run the tests with `MAXEMU_BLESS=1` set.
+
+use crate::cpu::CPU;
+use crate::ppu::PPU;
+use std::env;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// How many differing pixels are listed individually in a mismatch report
+const LISTED_DIFFERENCES: usize = 10;
+
+/// Loads `program` into a headless CPU and runs it for `frames` frames (or until it halts)
+pub fn run_frames(program: Vec<u8>, frames: u64) -> CPU {
+    let mut cpu = CPU::headless();
+
+    cpu.load(program);
+    cpu.run_frames(frames);
+
+    cpu
+}
+
+/// Panics with a readable report if `ppu`'s buffer doesn't match the reference image `name`
+pub fn assert_matches(name: &str, ppu: &PPU) {
+    let expected_path = golden_dir().join(format!("{}.png", name));
+
+    if env::var_os("MAXEMU_BLESS").is_some() {
+        fs::create_dir_all(golden_dir()).unwrap();
+        ppu.screenshot(&expected_path, 1).unwrap();
+        return;
+    }
+
+    let (width, height, expected) = match read_png(&expected_path) {
+        Some(image) => image,
+        None => panic!(
+            "no reference image at {}; re-run with MAXEMU_BLESS=1 to create it",
+            expected_path.display()
+        ),
+    };
+
+    let actual = ppu.frame_rgb(1);
+    if (width, height) == (ppu.width, ppu.height) && expected == actual {
+        return;
+    }
+
+    let actual_path = actual_dir().join(format!("{}.actual.png", name));
+    fs::create_dir_all(actual_dir()).unwrap();
+    ppu.screenshot(&actual_path, 1).unwrap();
+
+    let mut report = String::new();
+    if (width, height) != (ppu.width, ppu.height) {
+        writeln!(
+            report,
+            "frame is {}x{} but {} is {}x{}",
+            ppu.width,
+            ppu.height,
+            expected_path.display(),
+            width,
+            height
+        )
+        .unwrap();
+    } else {
+        report.push_str(&diff(width, height, &expected, &actual));
+    }
+
+    panic!(
+        "frame does not match {}\n{}actual frame saved to {}; re-run with MAXEMU_BLESS=1 to accept it",
+        expected_path.display(),
+        report,
+        actual_path.display()
+    );
+}
+
+/// Panics with a report of the first difference if `actual` doesn't match the reference file
+/// `name` byte for byte
+pub fn assert_bytes_match(name: &str, actual: &[u8]) {
+    let expected_path = golden_dir().join(name);
+
+    if env::var_os("MAXEMU_BLESS").is_some() {
+        fs::create_dir_all(golden_dir()).unwrap();
+        fs::write(&expected_path, actual).unwrap();
+        return;
+    }
+
+    let expected = match fs::read(&expected_path) {
+        Ok(expected) => expected,
+        Err(_) => panic!(
+            "no reference file at {}; re-run with MAXEMU_BLESS=1 to create it",
+            expected_path.display()
+        ),
+    };
+
+    if expected == actual {
+        return;
+    }
+
+    let actual_path = actual_dir().join(name);
+    fs::create_dir_all(actual_dir()).unwrap();
+    fs::write(&actual_path, actual).unwrap();
+
+    let first_difference = expected
+        .iter()
+        .zip(actual)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or_else(|| expected.len().min(actual.len()));
+
+    panic!(
+        "output does not match {}\n\
+         expected {} bytes, got {}\n\
+         first difference at byte {:#x}: expected {}, got {}\n\
+         actual output saved to {}; re-run with MAXEMU_BLESS=1 to accept it",
+        expected_path.display(),
+        expected.len(),
+        actual.len(),
+        first_difference,
+        describe_byte(expected.get(first_difference)),
+        describe_byte(actual.get(first_difference)),
+        actual_path.display()
+    );
+}
+
+fn describe_byte(byte: Option<&u8>) -> String {
+    match byte {
+        Some(byte) => format!("{:#04x}", byte),
+        None => "the end of the file".to_string(),
+    }
+}
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+}
+
+/// Where output that didn't match is saved, for a closer look
+fn actual_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("golden")
+}
+
+/// Reads an 8-bit RGB PNG, returning its width, height and pixel data
+fn read_png(path: &Path) -> Option<(usize, usize, Vec<u8>)> {
+    let decoder = png::Decoder::new(File::open(path).ok()?);
+    let (info, mut reader) = decoder.read_info().unwrap();
+
+    assert_eq!(
+        (info.color_type, info.bit_depth),
+        (png::ColorType::RGB, png::BitDepth::Eight),
+        "{} is not an 8-bit RGB image",
+        path.display()
+    );
+
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).unwrap();
+
+    Some((info.width as usize, info.height as usize, data))
+}
+
+/// Describes how two images of the same size differ: how many pixels, a map of where, and the
+/// colours of the first few
+fn diff(width: usize, height: usize, expected: &[u8], actual: &[u8]) -> String {
+    let mut map = String::new();
+    let mut differences = Vec::new();
+
+    for y in 0..height {
+        map.push_str("  ");
+        for x in 0..width {
+            let i = (y * width + x) * 3;
+            let expected = &expected[i..i + 3];
+            let actual = &actual[i..i + 3];
+
+            if expected == actual {
+                map.push('.');
+            } else {
+                map.push('X');
+                differences.push((x, y, expected, actual));
+            }
+        }
+        map.push('\n');
+    }
+
+    let mut report = String::new();
+    writeln!(
+        report,
+        "{} of {} pixels differ, marked with X:",
+        differences.len(),
+        width * height
+    )
+    .unwrap();
+    report.push_str(&map);
+
+    writeln!(report, "first differences (x, y: expected -> actual):").unwrap();
+    for (x, y, expected, actual) in differences.iter().take(LISTED_DIFFERENCES) {
+        writeln!(
+            report,
+            "  ({}, {}): #{:02x}{:02x}{:02x} -> #{:02x}{:02x}{:02x}",
+            x, y, expected[0], expected[1], expected[2], actual[0], actual[1], actual[2]
+        )
+        .unwrap();
+    }
+    if differences.len() > LISTED_DIFFERENCES {
+        writeln!(
+            report,
+            "  ...and {} more",
+            differences.len() - LISTED_DIFFERENCES
+        )
+        .unwrap();
+    }
+
+    report
+}
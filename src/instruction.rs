@@ -0,0 +1,366 @@
+//! Decoded instructions, separate from where they live in memory. `decode` turns bytes into an
+//! `Instruction` using the layout in `isa::INSTRUCTIONS`, `encode` turns it back, and `Display`
+//! writes it as the assembler would accept it.
+
+use crate::isa::{self, InstructionInfo, OperandKind};
+use std::error::Error;
+use std::fmt;
+
+/// One of the CPU's four registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    A,
+    B,
+    C,
+    D,
+}
+
+impl Reg {
+    /// Every register, by index
+    pub const ALL: [Reg; 4] = [Reg::A, Reg::B, Reg::C, Reg::D];
+
+    /// The register with the given index, as it's encoded in an instruction
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(index as usize).copied()
+    }
+
+    /// The register's index into `CPU::registers`
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A single instruction, with its operands. Opcodes, sizes and cycle counts come from
+/// `isa::INSTRUCTIONS`, via `info()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Halt,
+    Nop,
+    Load { dst: Reg, imm: u8 },
+    LoadReg { dst: Reg, src: Reg },
+    LoadMem { dst: Reg, addr: u16 },
+    Store { addr: u16, src: Reg },
+    Eq { a: Reg, b: Reg, dst: Reg },
+    EqImm { a: Reg, imm: u8, dst: Reg },
+    Gt { a: Reg, b: Reg, dst: Reg },
+    Lt { a: Reg, b: Reg, dst: Reg },
+    JumpIfTrue { cond: Reg, target: u16 },
+    Reti,
+    Inc(Reg),
+    Dec(Reg),
+    Add { a: Reg, b: Reg, dst: Reg },
+    Sub { a: Reg, b: Reg, dst: Reg },
+    AddImm { a: Reg, imm: u8, dst: Reg },
+    SubImm { a: Reg, imm: u8, dst: Reg },
+}
+
+/// An operand, decoded according to its `OperandKind`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Reg(Reg),
+    Imm(u8),
+    Addr(u16),
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Instruction::Halt => 0x00,
+            Instruction::Nop => 0xFF,
+            Instruction::Load { .. } => 0x10,
+            Instruction::LoadReg { .. } => 0x11,
+            Instruction::LoadMem { .. } => 0x12,
+            Instruction::Store { .. } => 0x20,
+            Instruction::Eq { .. } => 0x30,
+            Instruction::EqImm { .. } => 0x31,
+            Instruction::Gt { .. } => 0x32,
+            Instruction::Lt { .. } => 0x33,
+            Instruction::JumpIfTrue { .. } => 0x40,
+            Instruction::Reti => 0x41,
+            Instruction::Inc(_) => 0x50,
+            Instruction::Dec(_) => 0x51,
+            Instruction::Add { .. } => 0x52,
+            Instruction::Sub { .. } => 0x53,
+            Instruction::AddImm { .. } => 0x54,
+            Instruction::SubImm { .. } => 0x55,
+        }
+    }
+
+    /// The instruction's entry in `isa::INSTRUCTIONS`
+    pub fn info(&self) -> &'static InstructionInfo {
+        isa::lookup(self.opcode()).expect("every instruction is in the table")
+    }
+
+    /// How many bytes the instruction takes up, including its opcode
+    pub fn size(&self) -> usize {
+        self.info().size()
+    }
+
+    /// The operands, in the order they're encoded
+    fn operands(&self) -> Vec<Operand> {
+        use Operand::{Addr, Imm};
+        let reg = Operand::Reg;
+
+        match *self {
+            Instruction::Halt | Instruction::Nop | Instruction::Reti => vec![],
+            Instruction::Load { dst, imm } => vec![reg(dst), Imm(imm)],
+            Instruction::LoadReg { dst, src } => vec![reg(dst), reg(src)],
+            Instruction::LoadMem { dst, addr } => vec![reg(dst), Addr(addr)],
+            Instruction::Store { addr, src } => vec![Addr(addr), reg(src)],
+            Instruction::Eq { a, b, dst }
+            | Instruction::Gt { a, b, dst }
+            | Instruction::Lt { a, b, dst }
+            | Instruction::Add { a, b, dst }
+            | Instruction::Sub { a, b, dst } => vec![reg(a), reg(b), reg(dst)],
+            Instruction::EqImm { a, imm, dst }
+            | Instruction::AddImm { a, imm, dst }
+            | Instruction::SubImm { a, imm, dst } => vec![reg(a), Imm(imm), reg(dst)],
+            Instruction::JumpIfTrue { cond, target } => vec![reg(cond), Addr(target)],
+            Instruction::Inc(r) | Instruction::Dec(r) => vec![reg(r)],
+        }
+    }
+
+    /// The instruction as bytes, ready to be loaded into memory
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode()];
+
+        for operand in self.operands() {
+            match operand {
+                Operand::Reg(reg) => bytes.push(reg as u8),
+                Operand::Imm(imm) => bytes.push(imm),
+                Operand::Addr(addr) => bytes.extend_from_slice(&addr.to_be_bytes()),
+            }
+        }
+
+        bytes
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Writes the instruction as the assembler would accept it, e.g. `LOAD A, 0xFF`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = self.info();
+        write!(f, "{}", info.mnemonic)?;
+
+        for (i, (operand, kind)) in self.operands().iter().zip(info.operands).enumerate() {
+            f.write_str(if i == 0 { " " } else { ", " })?;
+
+            match (operand, kind) {
+                (Operand::Reg(reg), _) => write!(f, "{}", reg)?,
+                (Operand::Imm(imm), _) => write!(f, "0x{:02X}", imm)?,
+                (Operand::Addr(addr), OperandKind::Mem) => write!(f, "[0x{:04X}]", addr)?,
+                (Operand::Addr(addr), _) => write!(f, "0x{:04X}", addr)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Why some bytes couldn't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// There are no bytes at all
+    Empty,
+    UnknownOpcode(u8),
+    /// A register operand that isn't 0 to 3
+    InvalidRegister(u8),
+    /// The bytes end partway through the instruction
+    Truncated,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "No instruction to decode"),
+            DecodeError::UnknownOpcode(opcode) => write!(f, "Unknown opcode: {:#04x}", opcode),
+            DecodeError::InvalidRegister(index) => write!(f, "Invalid register: {}", index),
+            DecodeError::Truncated => write!(f, "Instruction is cut short"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Decodes the instruction at the start of `bytes`. Anything after it is ignored; use
+/// `Instruction::size()` to find where the next one starts.
+pub fn decode(bytes: &[u8]) -> Result<Instruction, DecodeError> {
+    let opcode = *bytes.first().ok_or(DecodeError::Empty)?;
+    let info = isa::lookup(opcode).ok_or(DecodeError::UnknownOpcode(opcode))?;
+
+    if bytes.len() < info.size() {
+        return Err(DecodeError::Truncated);
+    }
+
+    let mut operands = Vec::with_capacity(info.operands.len());
+    let mut offset = 1;
+    for kind in info.operands {
+        operands.push(match kind {
+            OperandKind::Reg => Operand::Reg(
+                Reg::from_index(bytes[offset])
+                    .ok_or(DecodeError::InvalidRegister(bytes[offset]))?,
+            ),
+            OperandKind::Imm => Operand::Imm(bytes[offset]),
+            OperandKind::Addr | OperandKind::Mem => {
+                Operand::Addr(u16::from_be_bytes([bytes[offset], bytes[offset + 1]]))
+            }
+        });
+        offset += kind.size();
+    }
+
+    // The table has already checked the operands' kinds, so these patterns can't fail
+    let reg = |i: usize| match operands[i] {
+        Operand::Reg(reg) => reg,
+        _ => unreachable!(),
+    };
+    let imm = |i: usize| match operands[i] {
+        Operand::Imm(imm) => imm,
+        _ => unreachable!(),
+    };
+    let addr = |i: usize| match operands[i] {
+        Operand::Addr(addr) => addr,
+        _ => unreachable!(),
+    };
+
+    Ok(match opcode {
+        0x00 => Instruction::Halt,
+        0xFF => Instruction::Nop,
+        0x10 => Instruction::Load {
+            dst: reg(0),
+            imm: imm(1),
+        },
+        0x11 => Instruction::LoadReg {
+            dst: reg(0),
+            src: reg(1),
+        },
+        0x12 => Instruction::LoadMem {
+            dst: reg(0),
+            addr: addr(1),
+        },
+        0x20 => Instruction::Store {
+            addr: addr(0),
+            src: reg(1),
+        },
+        0x30 => Instruction::Eq {
+            a: reg(0),
+            b: reg(1),
+            dst: reg(2),
+        },
+        0x31 => Instruction::EqImm {
+            a: reg(0),
+            imm: imm(1),
+            dst: reg(2),
+        },
+        0x32 => Instruction::Gt {
+            a: reg(0),
+            b: reg(1),
+            dst: reg(2),
+        },
+        0x33 => Instruction::Lt {
+            a: reg(0),
+            b: reg(1),
+            dst: reg(2),
+        },
+        0x40 => Instruction::JumpIfTrue {
+            cond: reg(0),
+            target: addr(1),
+        },
+        0x41 => Instruction::Reti,
+        0x50 => Instruction::Inc(reg(0)),
+        0x51 => Instruction::Dec(reg(0)),
+        0x52 => Instruction::Add {
+            a: reg(0),
+            b: reg(1),
+            dst: reg(2),
+        },
+        0x53 => Instruction::Sub {
+            a: reg(0),
+            b: reg(1),
+            dst: reg(2),
+        },
+        0x54 => Instruction::AddImm {
+            a: reg(0),
+            imm: imm(1),
+            dst: reg(2),
+        },
+        0x55 => Instruction::SubImm {
+            a: reg(0),
+            imm: imm(1),
+            dst: reg(2),
+        },
+        _ => unreachable!("{} is in the table but can't be decoded", info.mnemonic),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_image;
+    use crate::isa::INSTRUCTIONS;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode(&[0x10, 0x00, 0xFF, 0x00]),
+            Ok(Instruction::Load {
+                dst: Reg::A,
+                imm: 0xFF
+            })
+        );
+        assert_eq!(
+            decode(&[0x20, 0x02, 0x00, 0x03]),
+            Ok(Instruction::Store {
+                addr: 0x0200,
+                src: Reg::D
+            })
+        );
+        assert_eq!(decode(&[]), Err(DecodeError::Empty));
+        assert_eq!(decode(&[0x42]), Err(DecodeError::UnknownOpcode(0x42)));
+        assert_eq!(decode(&[0x50, 0x04]), Err(DecodeError::InvalidRegister(4)));
+        assert_eq!(decode(&[0x12, 0x00, 0x01]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn test_display() {
+        let instruction = Instruction::LoadMem {
+            dst: Reg::C,
+            addr: 0x00AB,
+        };
+        assert_eq!(instruction.to_string(), "LOAD C, [0x00AB]");
+
+        let instruction = Instruction::SubImm {
+            a: Reg::A,
+            imm: 0x0A,
+            dst: Reg::B,
+        };
+        assert_eq!(instruction.to_string(), "SUB A, 0x0A, B");
+        assert_eq!(Instruction::Reti.to_string(), "RETI");
+    }
+
+    #[test]
+    fn test_every_opcode_round_trips() {
+        for info in INSTRUCTIONS {
+            // Every operand byte is different, and a valid register, so swapped operands show up
+            let bytes = (0..info.size() as u8)
+                .map(|i| if i == 0 { info.opcode } else { i % 4 })
+                .collect::<Vec<_>>();
+
+            let instruction = decode(&bytes).unwrap();
+            assert_eq!(instruction.opcode(), info.opcode);
+            assert_eq!(instruction.info(), info);
+            assert_eq!(instruction.encode(), bytes);
+            assert_eq!(
+                assemble_image(&instruction.to_string()).unwrap().data,
+                bytes,
+                "{} doesn't assemble back to itself",
+                instruction
+            );
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! The instruction set, described once. Operand layouts, sizes and cycle counts for the CPU's
+//! decoder, the assembler, the disassembler and `docs/instructions.md` all come from
+//! `INSTRUCTIONS`. Adding an instruction also takes an `Instruction` variant, with arms for it in
+//! `Instruction::opcode`, `Instruction::operands` and `decode`, and one in `CPU::execute`.
+
+use std::fmt::Write;
+
+/// The kinds of operand an instruction can take
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    /// A register index: `A`, `B`, `C` or `D`
+    Reg,
+    /// An 8-bit value
+    Imm,
+    /// A 16-bit address, stored as Big Endian
+    Addr,
+    /// A 16-bit address to read from, stored as Big Endian and written in brackets
+    Mem,
+}
+
+impl OperandKind {
+    /// How many bytes the operand takes up
+    pub fn size(self) -> usize {
+        match self {
+            OperandKind::Reg | OperandKind::Imm => 1,
+            OperandKind::Addr | OperandKind::Mem => 2,
+        }
+    }
+}
+
+/// What an instruction can change, besides moving the program counter past itself. The CPU has
+/// no flags register, so this is what takes its place.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    Register,
+    Memory,
+    /// Sets the program counter
+    Jump,
+    /// Enters or leaves an interrupt handler
+    Interrupt,
+    Halt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstructionInfo {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    /// The operands, in the order they're encoded and written
+    pub operands: &'static [OperandKind],
+    pub cycles: u32,
+    pub effects: &'static [Effect],
+    /// What the instruction does, calling its operands A, B and C in order
+    pub description: &'static str,
+}
+
+impl InstructionInfo {
+    /// How many bytes the instruction takes up, including its opcode
+    pub fn size(&self) -> usize {
+        1 + self.operands.iter().map(|kind| kind.size()).sum::<usize>()
+    }
+
+    /// How the instruction is written, e.g. `LOAD $A, 0xB`
+    pub fn syntax(&self) -> String {
+        let operands = self
+            .operands
+            .iter()
+            .zip(["A", "B", "C"].iter())
+            .map(|(kind, name)| match kind {
+                OperandKind::Reg => format!("${}", name),
+                OperandKind::Imm | OperandKind::Addr => format!("0x{}", name),
+                OperandKind::Mem => format!("[0x{}]", name),
+            })
+            .collect::<Vec<_>>();
+
+        if operands.is_empty() {
+            self.mnemonic.to_string()
+        } else {
+            format!("{} {}", self.mnemonic, operands.join(", "))
+        }
+    }
+}
+
+use Effect::*;
+use OperandKind::*;
+
+/// Every instruction the CPU knows. Cycle counts are one for every byte of the instruction,
+/// plus one for every access to memory outside of it.
+pub const INSTRUCTIONS: &[InstructionInfo] = &[
+    InstructionInfo {
+        opcode: 0x00,
+        mnemonic: "HALT",
+        operands: &[],
+        cycles: 1,
+        effects: &[Halt],
+        description: "Stop running",
+    },
+    InstructionInfo {
+        opcode: 0xFF,
+        mnemonic: "NOP",
+        operands: &[],
+        cycles: 1,
+        effects: &[],
+        description: "Do nothing",
+    },
+    InstructionInfo {
+        opcode: 0x10,
+        mnemonic: "LOAD",
+        operands: &[Reg, Imm],
+        cycles: 3,
+        effects: &[Register],
+        description: "Load 0xB into $A",
+    },
+    InstructionInfo {
+        opcode: 0x11,
+        mnemonic: "LOAD",
+        operands: &[Reg, Reg],
+        cycles: 3,
+        effects: &[Register],
+        description: "Load $B into $A",
+    },
+    InstructionInfo {
+        opcode: 0x12,
+        mnemonic: "LOAD",
+        operands: &[Reg, Mem],
+        cycles: 5,
+        effects: &[Register],
+        description: "Load the byte at 0xB into $A",
+    },
+    InstructionInfo {
+        opcode: 0x20,
+        mnemonic: "STORE",
+        operands: &[Addr, Reg],
+        cycles: 5,
+        effects: &[Memory],
+        description: "Store $B at 0xA",
+    },
+    InstructionInfo {
+        opcode: 0x30,
+        mnemonic: "EQ",
+        operands: &[Reg, Reg, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to 1 if $A == $B, and 0 otherwise",
+    },
+    InstructionInfo {
+        opcode: 0x31,
+        mnemonic: "EQ",
+        operands: &[Reg, Imm, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to 1 if $A == 0xB, and 0 otherwise",
+    },
+    InstructionInfo {
+        opcode: 0x32,
+        mnemonic: "GT",
+        operands: &[Reg, Reg, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to 1 if $A > $B, and 0 otherwise",
+    },
+    InstructionInfo {
+        opcode: 0x33,
+        mnemonic: "LT",
+        operands: &[Reg, Reg, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to 1 if $A < $B, and 0 otherwise",
+    },
+    InstructionInfo {
+        opcode: 0x40,
+        mnemonic: "JT",
+        operands: &[Reg, Addr],
+        cycles: 4,
+        effects: &[Jump],
+        description: "Jump to 0xB if $A is 1",
+    },
+    InstructionInfo {
+        opcode: 0x41,
+        mnemonic: "RETI",
+        operands: &[],
+        cycles: 1,
+        effects: &[Jump, Interrupt],
+        description: "Return from the current interrupt handler",
+    },
+    InstructionInfo {
+        opcode: 0x50,
+        mnemonic: "INC",
+        operands: &[Reg],
+        cycles: 2,
+        effects: &[Register],
+        description: "Increment $A",
+    },
+    InstructionInfo {
+        opcode: 0x51,
+        mnemonic: "DEC",
+        operands: &[Reg],
+        cycles: 2,
+        effects: &[Register],
+        description: "Decrement $A",
+    },
+    InstructionInfo {
+        opcode: 0x52,
+        mnemonic: "ADD",
+        operands: &[Reg, Reg, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to $A + $B, wrapping on overflow",
+    },
+    InstructionInfo {
+        opcode: 0x53,
+        mnemonic: "SUB",
+        operands: &[Reg, Reg, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to $A - $B, wrapping on overflow",
+    },
+    InstructionInfo {
+        opcode: 0x54,
+        mnemonic: "ADD",
+        operands: &[Reg, Imm, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to $A + 0xB, wrapping on overflow",
+    },
+    InstructionInfo {
+        opcode: 0x55,
+        mnemonic: "SUB",
+        operands: &[Reg, Imm, Reg],
+        cycles: 4,
+        effects: &[Register],
+        description: "Set $C to $A - 0xB, wrapping on overflow",
+    },
+];
+
+/// Looks up the instruction with the given opcode
+pub fn lookup(opcode: u8) -> Option<&'static InstructionInfo> {
+    INSTRUCTIONS
+        .iter()
+        .find(|instruction| instruction.opcode == opcode)
+}
+
+/// Generates the Markdown reference in `docs/instructions.md`
+pub fn reference() -> String {
+    let mut reference = String::new();
+
+    reference.push_str("# Instruction set\n\n");
+    reference.push_str("<!-- Generated from `src/isa.rs`; do not edit by hand. -->\n\n");
+    reference.push_str(
+        "Registers are written `$A`, and values and addresses `0xB`. Addresses are stored as Big \
+         Endian.\n\n",
+    );
+    reference.push_str("| Opcode | Instruction | Bytes | Cycles | Affects | Description |\n");
+    reference.push_str("|--------|-------------|-------|--------|---------|-------------|\n");
+
+    for instruction in INSTRUCTIONS {
+        let effects = instruction
+            .effects
+            .iter()
+            .map(|effect| format!("{:?}", effect).to_lowercase())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        writeln!(
+            reference,
+            "| `0x{:02X}` | `{}` | {} | {} | {} | {} |",
+            instruction.opcode,
+            instruction.syntax(),
+            instruction.size(),
+            instruction.cycles,
+            if effects.is_empty() { "-" } else { &effects },
+            instruction.description
+        )
+        .unwrap();
+    }
+
+    reference
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_opcodes_are_unique() {
+        for (i, instruction) in INSTRUCTIONS.iter().enumerate() {
+            assert!(
+                INSTRUCTIONS[i + 1..]
+                    .iter()
+                    .all(|other| other.opcode != instruction.opcode),
+                "opcode {:#04x} is listed twice",
+                instruction.opcode
+            );
+        }
+    }
+
+    #[test]
+    fn test_syntax() {
+        assert_eq!(lookup(0x00).unwrap().syntax(), "HALT");
+        assert_eq!(lookup(0x12).unwrap().syntax(), "LOAD $A, [0xB]");
+        assert_eq!(lookup(0x54).unwrap().syntax(), "ADD $A, 0xB, $C");
+        assert_eq!(lookup(0x12).unwrap().size(), 4);
+    }
+
+    #[test]
+    fn test_reference_is_up_to_date() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("docs")
+            .join("instructions.md");
+
+        if env::var_os("MAXEMU_BLESS").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, reference()).unwrap();
+        }
+
+        assert!(
+            fs::read_to_string(&path).ok() == Some(reference()),
+            "{} is out of date; re-run with MAXEMU_BLESS=1 to regenerate it",
+            path.display()
+        );
+    }
+}
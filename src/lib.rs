@@ -0,0 +1,28 @@
+pub mod apu;
+pub mod asm;
+pub mod audio;
+pub mod coverage;
+pub mod cpu;
+pub mod debug_info;
+pub mod debugger;
+pub mod disasm;
+pub mod expr;
+pub mod gdb;
+#[cfg(test)]
+mod golden;
+pub mod instruction;
+pub mod isa;
+pub mod lines;
+pub mod link;
+pub mod movie;
+pub mod object;
+pub mod ppu;
+pub mod profile;
+pub mod recorder;
+pub mod replay;
+pub mod rewind;
+pub mod rom;
+pub mod snapshot;
+pub mod symbols;
+pub mod trace;
+pub mod watch;
@@ -0,0 +1,142 @@
+//! Line maps: the source line every instruction was assembled from, saved next to the program as
+//! lines of `ADDR LINE FILE`, with the address in hex. The file is left off for source that
+//! didn't come from one.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// A line of source
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceLine {
+    pub file: Option<PathBuf>,
+    /// Starting at 1
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineMap {
+    /// The address of each instruction and where it came from, sorted by address
+    lines: Vec<(u16, SourceLine)>,
+}
+
+impl LineMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the instruction at `address` came from `source`, replacing whatever was there
+    pub fn insert(&mut self, address: u16, source: SourceLine) {
+        match self
+            .lines
+            .binary_search_by_key(&address, |(start, _)| *start)
+        {
+            Ok(index) => self.lines[index].1 = source,
+            Err(index) => self.lines.insert(index, (address, source)),
+        }
+    }
+
+    /// Where the instruction starting at `address` came from
+    pub fn get(&self, address: u16) -> Option<&SourceLine> {
+        self.lines
+            .binary_search_by_key(&address, |(start, _)| *start)
+            .ok()
+            .map(|index| &self.lines[index].1)
+    }
+
+    /// Every instruction, in order of address
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &SourceLine)> {
+        self.lines
+            .iter()
+            .map(|(address, source)| (*address, source))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for (address, source) in self.iter() {
+            match &source.file {
+                Some(file) => writeln!(out, "{:04X} {} {}", address, source.line, file.display())?,
+                None => writeln!(out, "{:04X} {}", address, source.line)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a map written by `write`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = Self::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let text = line.trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            // The file name is the rest of the line, spaces and all
+            let mut fields = text.splitn(3, ' ');
+            let address = fields
+                .next()
+                .and_then(|address| u16::from_str_radix(address, 16).ok());
+            let source_line = fields.next().and_then(|line| line.parse().ok());
+            let file = fields.next().map(|file| PathBuf::from(file.trim()));
+
+            match (address, source_line) {
+                (Some(address), Some(line)) => map.insert(address, SourceLine { file, line }),
+                _ => return Err(format!("line {}: invalid line `{}`", i + 1, text)),
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut map = LineMap::new();
+        map.insert(
+            0x8003,
+            SourceLine {
+                file: Some(PathBuf::from("lib/my math.s")),
+                line: 12,
+            },
+        );
+        map.insert(
+            0x8000,
+            SourceLine {
+                file: None,
+                line: 1,
+            },
+        );
+
+        let mut text = Vec::new();
+        map.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+
+        assert_eq!(text, "8000 1\n8003 12 lib/my math.s\n");
+        assert_eq!(LineMap::parse(&text), Ok(map.clone()));
+        assert_eq!(map.get(0x8003).unwrap().to_string(), "lib/my math.s:12");
+        assert_eq!(map.get(0x8000).unwrap().to_string(), "line 1");
+        assert_eq!(map.get(0x8001), None);
+        assert_eq!(
+            LineMap::parse("8000 1\n8003"),
+            Err("line 2: invalid line `8003`".to_string())
+        );
+    }
+}
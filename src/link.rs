@@ -0,0 +1,584 @@
+//! The linker, which places the sections of one or more objects in memory, fills in their
+//! relocations and makes a single program out of them.
+//!
+//! Where sections go is described by a memory map, with one command per line and `;` comments:
+//!
+//! ```text
+//! region rom 0x8000 0x7FFC   ; a name, where it starts and how big it is
+//! place text rom             ; sections go one after another in a region...
+//! place data rom
+//! place vectors 0xFFFC       ; ...or at a fixed address
+//! ```
+//!
+//! Sections with the same name in different objects are put together, in the order the objects
+//! were given. Sections the map doesn't mention go at the end of the first region.
+//!
+//! The program starts at the label `start`, or `main` if there isn't one, or at the start of the
+//! `text` section if there's neither.
+
+use crate::asm::{AsmError, ORIGIN};
+use crate::expr;
+use crate::lines::LineMap;
+use crate::object::{Object, RelocationKind, Target};
+use crate::symbols::SymbolMap;
+use std::collections::HashMap;
+
+/// The labels the program can start at, in order of preference
+const ENTRY_SYMBOLS: [&str; 2] = ["start", "main"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub start: u16,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Placement {
+    Region(String),
+    Address(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+    /// Sections by name, and where they go
+    pub placements: Vec<(String, Placement)>,
+}
+
+impl Default for MemoryMap {
+    /// Everything in a single region from `ORIGIN` up, where `CPU::load` puts programs
+    fn default() -> Self {
+        Self {
+            regions: vec![Region {
+                name: "rom".to_string(),
+                start: ORIGIN,
+                size: 0xFFFF - ORIGIN as u32,
+            }],
+            placements: Vec::new(),
+        }
+    }
+}
+
+impl MemoryMap {
+    /// Reads a memory map in the format described above
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = Self {
+            regions: Vec::new(),
+            placements: Vec::new(),
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            let number = |text: &str| {
+                expr::parse_number(text).ok_or_else(|| error(format!("invalid number `{}`", text)))
+            };
+
+            let line = line.split(';').next().unwrap();
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [] => (),
+                ["region", name, start, size] => {
+                    let start = number(start)?;
+                    let size = number(size)?;
+                    if start < 0 || size < 0 || start + size > 0xFFFF {
+                        return Err(error(format!("`{}` doesn't fit in memory", name)));
+                    }
+
+                    map.regions.push(Region {
+                        name: name.to_string(),
+                        start: start as u16,
+                        size: size as u32,
+                    });
+                }
+                ["place", section, target] => {
+                    let placement = if target.starts_with(|c: char| c.is_ascii_digit()) {
+                        match number(target)? {
+                            address if (0..=0xFFFF).contains(&address) => {
+                                Placement::Address(address as u16)
+                            }
+                            _ => return Err(error(format!("invalid address `{}`", target))),
+                        }
+                    } else {
+                        Placement::Region(target.to_string())
+                    };
+
+                    if map.placements.iter().any(|(placed, _)| placed == section) {
+                        return Err(error(format!("`{}` is placed more than once", section)));
+                    }
+                    map.placements.push((section.to_string(), placement));
+                }
+                _ => return Err(error(format!("invalid command `{}`", line.trim()))),
+            }
+        }
+
+        for (section, placement) in &map.placements {
+            if let Placement::Region(region) = placement {
+                if !map.regions.iter().any(|r| r.name == *region) {
+                    return Err(format!(
+                        "`{}` goes in `{}`, which isn't a region",
+                        section, region
+                    ));
+                }
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+/// A linked program
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    /// Where `data` goes in memory
+    pub start: u16,
+    /// Where the program starts executing
+    pub entry: u16,
+    pub data: Vec<u8>,
+    /// Where every label ended up
+    pub symbols: SymbolMap,
+    /// Where every instruction came from
+    pub lines: LineMap,
+}
+
+/// Links `objects` into a single program, placing them according to `map`. Errors caused by a
+/// relocation point at the source line it came from; others have no file or line.
+pub fn link(objects: &[Object], map: &MemoryMap) -> Result<Image, AsmError> {
+    let error = |message: String| AsmError {
+        file: None,
+        line: 0,
+        message,
+    };
+
+    // Gather up sections by name, in the order they're first seen. Sections that have to be at a
+    // fixed address are kept apart, as they can't be put together with anything else.
+    let mut names = Vec::<&str>::new();
+    let mut fixed = Vec::new();
+    for (o, object) in objects.iter().enumerate() {
+        for (s, section) in object.sections.iter().enumerate() {
+            if let Some(address) = section.address {
+                fixed.push(((o, s), address));
+            } else if !names.contains(&section.name.as_str()) {
+                names.push(&section.name);
+            }
+        }
+    }
+
+    // Where each section of each object starts
+    let mut bases = HashMap::new();
+    let mut layout = Layout::default();
+
+    for ((o, s), address) in fixed {
+        let section = &objects[o].sections[s];
+
+        layout
+            .place(address as u32, section.data.len(), &section.name)
+            .map_err(error)?;
+        bases.insert((o, s), address as u32);
+    }
+
+    // Everything the map places, then everything else, in order
+    let mut order = map
+        .placements
+        .iter()
+        .map(|(name, placement)| (name.as_str(), Some(placement)))
+        .collect::<Vec<_>>();
+    for name in &names {
+        if !map.placements.iter().any(|(placed, _)| placed == name) {
+            order.push((name, None));
+        }
+    }
+
+    for (name, placement) in order {
+        let size = objects
+            .iter()
+            .flat_map(|object| &object.sections)
+            .filter(|section| section.name == name && section.address.is_none())
+            .map(|section| section.data.len())
+            .sum::<usize>();
+
+        let mut address = match placement {
+            Some(Placement::Address(address)) => *address as u32,
+            _ => {
+                let region = match placement {
+                    Some(Placement::Region(region)) => {
+                        map.regions.iter().find(|r| r.name == *region)
+                    }
+                    _ => map.regions.first(),
+                }
+                .ok_or_else(|| error(format!("there's no region to put `{}` in", name)))?;
+
+                let address = layout.end_of(region);
+                if address + size as u32 > region.end() {
+                    return Err(error(format!(
+                        "`{}` doesn't fit in `{}`",
+                        name, region.name
+                    )));
+                }
+
+                address
+            }
+        };
+
+        layout.place(address, size, name).map_err(error)?;
+
+        for (o, object) in objects.iter().enumerate() {
+            for (s, section) in object.sections.iter().enumerate() {
+                if section.name == name && section.address.is_none() {
+                    bases.insert((o, s), address);
+                    address += section.data.len() as u32;
+                }
+            }
+        }
+    }
+
+    // Work out where every symbol is, and check globals are only defined once
+    let address_of = |o: usize, symbol: &crate::object::Symbol| match symbol.section {
+        Some(section) => bases[&(o, section)] as i64 + symbol.value,
+        None => symbol.value,
+    };
+
+    let mut globals = HashMap::new();
+    for (o, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            if globals
+                .insert(symbol.name.as_str(), address_of(o, symbol))
+                .is_some()
+            {
+                return Err(error(format!(
+                    "`{}` is defined more than once",
+                    symbol.name
+                )));
+            }
+        }
+    }
+
+    let mut symbols = SymbolMap::new();
+    for (o, object) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            if symbol.section.is_some() {
+                symbols.insert(&symbol.name, address_of(o, symbol) as u16);
+            }
+        }
+    }
+
+    // Globals win over labels local to one object
+    let entry = ENTRY_SYMBOLS
+        .iter()
+        .find_map(|name| match globals.get(name) {
+            Some(&address) => Some(address as u16),
+            None => symbols.get(name),
+        })
+        .or_else(|| {
+            objects
+                .iter()
+                .enumerate()
+                .flat_map(|(o, object)| object.sections.iter().enumerate().map(move |s| (o, s)))
+                .filter(|(_, (_, section))| section.name == "text")
+                .map(|(o, (s, _))| bases[&(o, s)] as u16)
+                .min()
+        })
+        .ok_or_else(|| {
+            error(
+                "there's no `start` or `main` label, or `text` section, to start the program at"
+                    .to_string(),
+            )
+        })?;
+
+    let mut lines = LineMap::new();
+    for (o, object) in objects.iter().enumerate() {
+        for entry in &object.lines {
+            let address = bases[&(o, entry.section)] as usize + entry.offset;
+            lines.insert(address as u16, entry.source.clone());
+        }
+    }
+
+    // Lay everything out, then fill in the relocations
+    let start = layout
+        .placed
+        .iter()
+        .map(|(start, _, _)| *start)
+        .min()
+        .unwrap_or(ORIGIN as u32);
+    let end = layout
+        .placed
+        .iter()
+        .map(|(_, end, _)| *end)
+        .max()
+        .unwrap_or(start);
+
+    let mut data = vec![0; (end - start) as usize];
+    for (o, object) in objects.iter().enumerate() {
+        // Empty sections take up no space, so they can be placed outside of the image
+        for (s, section) in object
+            .sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| !section.data.is_empty())
+        {
+            let offset = (bases[&(o, s)] - start) as usize;
+            data[offset..offset + section.data.len()].copy_from_slice(&section.data);
+        }
+
+        for relocation in &object.relocations {
+            let error = |message: String| AsmError {
+                file: relocation.file.clone(),
+                line: relocation.line,
+                message,
+            };
+
+            let target = match &relocation.target {
+                Target::Section(section) => bases[&(o, *section)] as i64,
+                Target::Symbol(name) => match object.symbols.iter().find(|s| s.name == *name) {
+                    Some(symbol) => address_of(o, symbol),
+                    None => *globals
+                        .get(name.as_str())
+                        .ok_or_else(|| error(format!("undefined symbol `{}`", name)))?,
+                },
+            };
+            let value = target + relocation.addend;
+
+            let offset = (bases[&(o, relocation.section)] - start) as usize + relocation.offset;
+            match relocation.kind {
+                RelocationKind::Byte if (-128..=0xFF).contains(&value) => {
+                    data[offset] = value as u8;
+                }
+                RelocationKind::Byte => {
+                    return Err(error(format!("{} does not fit in 8 bits", value)));
+                }
+                RelocationKind::Word if (-0x8000..=0xFFFF).contains(&value) => {
+                    data[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+                }
+                RelocationKind::Word => {
+                    return Err(error(format!("{} does not fit in 16 bits", value)));
+                }
+                RelocationKind::Address if (0..=0xFFFF).contains(&value) => {
+                    data[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+                }
+                RelocationKind::Address => {
+                    return Err(error(format!("{:#x} is not a valid address", value)));
+                }
+            }
+        }
+    }
+
+    Ok(Image {
+        start: start as u16,
+        entry,
+        data,
+        symbols,
+        lines,
+    })
+}
+
+impl Region {
+    /// Just past the end of the region
+    pub fn end(&self) -> u32 {
+        self.start as u32 + self.size
+    }
+}
+
+/// What's been placed in memory so far, as start, end and section name
+#[derive(Default)]
+struct Layout<'a> {
+    placed: Vec<(u32, u32, &'a str)>,
+}
+
+impl<'a> Layout<'a> {
+    fn place(&mut self, start: u32, size: usize, name: &'a str) -> Result<(), String> {
+        let end = start + size as u32;
+        if end > 0xFFFF {
+            return Err(format!("`{}` doesn't fit in memory", name));
+        }
+        if size == 0 {
+            return Ok(());
+        }
+
+        if let Some((_, _, other)) = self.placed.iter().find(|(s, e, _)| start < *e && *s < end) {
+            return Err(format!("`{}` and `{}` overlap", name, other));
+        }
+
+        self.placed.push((start, end, name));
+        Ok(())
+    }
+
+    /// Where the next section in `region` goes: after everything already in it
+    fn end_of(&self, region: &Region) -> u32 {
+        self.placed
+            .iter()
+            .filter(|(start, _, _)| (region.start as u32..region.end()).contains(start))
+            .map(|(_, end, _)| *end)
+            .fold(region.start as u32, u32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_object;
+    use crate::object::Section;
+
+    fn objects(sources: &[&str]) -> Vec<Object> {
+        sources
+            .iter()
+            .map(|source| assemble_object(source).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_link() {
+        let objects = objects(&[
+            "
+                .global main
+            main:
+                LOAD A, [counter]
+                JT A, helper
+                HALT
+
+                .section vectors
+                .word handler
+            ",
+            "
+                .global helper, counter, handler
+            helper: INC A
+            handler: RETI
+
+                .section data
+            counter: .byte 1
+            ",
+        ]);
+        let map = MemoryMap::parse(
+            "
+            region rom 0x8000 0x1000
+            region ram 0x9000 0x100   ; not really RAM, but out of the way
+            place text rom
+            place data ram
+            place vectors 0xFFFC
+            ",
+        )
+        .unwrap();
+
+        let image = link(&objects, &map).unwrap();
+
+        assert_eq!(image.start, 0x8000);
+        assert_eq!(image.entry, 0x8000);
+        assert_eq!(image.data.len(), 0x7FFE);
+        assert_eq!(
+            image.data[..12],
+            [0x12, 0x00, 0x90, 0x00, 0x40, 0x00, 0x80, 0x09, 0x00, 0x50, 0x00, 0x41]
+        );
+        assert_eq!(image.data[0x1000], 1);
+        assert_eq!(image.data[0x7FFC..], [0x80, 0x0B]);
+        assert_eq!(
+            image.symbols.iter().collect::<Vec<_>>(),
+            vec![
+                ("main", 0x8000),
+                ("helper", 0x8009),
+                ("handler", 0x800B),
+                ("counter", 0x9000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default_map() {
+        let objects = objects(&[
+            "JT A, end\n.section data\n.byte 7",
+            "end: .global end\nHALT",
+        ]);
+        let image = link(&objects, &MemoryMap::default()).unwrap();
+
+        // Sections go one after another, with each object's part of a section kept together
+        assert_eq!(image.start, ORIGIN);
+        assert_eq!(image.entry, ORIGIN);
+        assert_eq!(image.data, vec![0x40, 0x00, 0x80, 0x04, 0x00, 7]);
+    }
+
+    #[test]
+    fn test_entry() {
+        let map = MemoryMap::parse(
+            "
+            region rom 0x8000 0x100
+            place data 0x4000
+            place text rom
+            ",
+        )
+        .unwrap();
+
+        // The program is loaded from where `data` is, but starts at `text`
+        let image = link(&objects(&["NOP\nHALT\n.section data\n.byte 7"]), &map).unwrap();
+        assert_eq!(image.start, 0x4000);
+        assert_eq!(image.entry, 0x8000);
+
+        // ...unless there's a label to start at
+        let entry = |source: &str| link(&objects(&[source]), &map).unwrap().entry;
+        assert_eq!(entry("helper: HALT\nmain: NOP\nstart: HALT"), 0x8002);
+        assert_eq!(entry("helper: HALT\nmain: NOP"), 0x8001);
+
+        let data_only = Object {
+            sections: vec![Section {
+                name: "data".to_string(),
+                address: None,
+                data: vec![7],
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            link(&[data_only], &map).unwrap_err().to_string(),
+            "there's no `start` or `main` label, or `text` section, to start the program at"
+        );
+    }
+
+    #[test]
+    fn test_empty_section() {
+        let map =
+            MemoryMap::parse("region rom 0x8000 0x100\nplace data 0x9000\nplace text rom").unwrap();
+
+        // `text` is empty, and placed below everything that isn't
+        let image = link(&objects(&[".section data\nval: .byte 1"]), &map).unwrap();
+        assert_eq!(image.start, 0x9000);
+        assert_eq!(image.data, vec![1]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |sources: &[&str], map: &str| {
+            link(&objects(sources), &MemoryMap::parse(map).unwrap())
+                .unwrap_err()
+                .to_string()
+        };
+        let rom = "region rom 0x8000 0x10";
+
+        assert_eq!(
+            error(&["NOP\nJT A, nowhere"], rom),
+            "line 2: undefined symbol `nowhere`"
+        );
+        assert_eq!(
+            error(&[".global x\nx: NOP", ".global x\nx: NOP"], rom),
+            "`x` is defined more than once"
+        );
+        assert_eq!(
+            error(&[".ascii \"0123456789ABCDEF!\""], rom),
+            "`text` doesn't fit in `rom`"
+        );
+        assert_eq!(
+            error(
+                &["NOP\nNOP\n.section data\nNOP"],
+                "place text 0x8000\nplace data 0x8001"
+            ),
+            "`data` and `text` overlap"
+        );
+
+        assert_eq!(
+            MemoryMap::parse("region rom 0x8000 0x10\nplace text ram"),
+            Err("`text` goes in `ram`, which isn't a region".to_string())
+        );
+        assert_eq!(
+            MemoryMap::parse("place text 0x8000\nplace text 0x9000"),
+            Err("line 2: `text` is placed more than once".to_string())
+        );
+        assert_eq!(
+            MemoryMap::parse("region rom 0x8000 lots"),
+            Err("line 1: invalid number `lots`".to_string())
+        );
+    }
+}
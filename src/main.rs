@@ -1,46 +1,1101 @@
-mod cpu;
-mod ppu;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use maxemu::asm::{assemble_image_file, assemble_object_file, ORIGIN};
+use maxemu::audio::WavSink;
+use maxemu::coverage::{Coverage, Lcov};
+use maxemu::cpu::{Fault, CPU};
+use maxemu::debug_info::DebugInfo;
+use maxemu::debugger::Debugger;
+use maxemu::disasm::disassemble;
+use maxemu::gdb::{self, GdbStub};
+use maxemu::lines::LineMap;
+use maxemu::link::{link, Image, MemoryMap};
+use maxemu::movie::{Movie, MovieError};
+use maxemu::object::Object;
+use maxemu::ppu::{Hotkey, PPU, WINDOW_SCALE, WINDOW_SCALES};
+use maxemu::profile::Profiler;
+use maxemu::replay::Replay;
+use maxemu::rewind::Rewinder;
+use maxemu::rom::{self, Rom};
+use maxemu::symbols::SymbolMap;
+use maxemu::trace::Tracer;
+use maxemu::watch::{WatchKind, Watchpoint};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::cpu::CPU;
+/// How many cycles `maxemu test` runs a ROM for before giving up on it
+const TEST_MAX_CYCLES: &str = "10000000";
+
+/// How far ahead of the clock emulation has to get before it waits to catch up
+const THROTTLE_SLACK: Duration = Duration::from_millis(2);
 
 fn main() {
-    println!("Hello, world!");
+    let matches = App::new("maxemu")
+        .about("Runs, builds and debugs MaxEmu programs")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a ROM")
+                .args(&cpu_args())
+                .arg(Arg::with_name("clock").long("clock").value_name("HZ").help(
+                    "Runs at HZ cycles per second, rather than as fast as possible. The \
+                             machine's real clock is 153600.",
+                ))
+                .arg(
+                    Arg::with_name("max-cycles")
+                        .long("max-cycles")
+                        .value_name("N")
+                        .help("Stops after N cycles"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .value_name("KIND:ADDRESS[:LENGTH]")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "Logs every `read`, `write` or `change` of LENGTH bytes (1 by default) \
+                             from ADDRESS, e.g. `write:0x0200:0x400`",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("frames")
+                        .long("frames")
+                        .value_name("N")
+                        .help("Stops after N frames"),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .value_name("FILE")
+                        .help(
+                            "Counts the executions and cycles of every instruction, and writes a \
+                             report of where the time went to FILE once the program stops",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("profile-folded")
+                        .long("profile-folded")
+                        .value_name("FILE")
+                        .help(
+                            "Profiles like --profile, and writes the cycles to FILE as folded \
+                             stacks for flamegraph tools",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("coverage")
+                        .long("coverage")
+                        .value_name("FILE")
+                        .help(
+                            "Records which instructions and branches are executed, and writes \
+                             them to FILE once the program stops: as an lcov tracefile of the \
+                             source lines if it ends in .info, or an annotated disassembly \
+                             otherwise",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("save-state")
+                        .long("save-state")
+                        .value_name("FILE")
+                        .help("Saves the state to FILE once the program stops"),
+                )
+                .arg(
+                    Arg::with_name("record-movie")
+                        .long("record-movie")
+                        .value_name("FILE")
+                        .conflicts_with("state")
+                        .help(
+                            "Records the keys and a checksum of the machine in every frame to a \
+                             movie file",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("movie")
+                        .long("movie")
+                        .value_name("FILE")
+                        .conflicts_with_all(&["replay", "state", "record-movie"])
+                        .help(
+                            "Plays back a movie file, stopping at its last frame, and fails if the \
+                             machine ever goes differently",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("screenshot")
+                        .long("screenshot")
+                        .value_name("FILE")
+                        .help("Saves a PNG screenshot to FILE once the program stops"),
+                )
+                .arg(
+                    Arg::with_name("screenshot-scale")
+                        .long("screenshot-scale")
+                        .value_name("SCALE")
+                        .default_value("1")
+                        .help("How much to scale up the screenshot by"),
+                )
+                .arg(
+                    Arg::with_name("record")
+                        .long("record")
+                        .value_name("FILE")
+                        .help(
+                            "Records every frame to FILE, as a GIF if it ends in .gif or raw RGB \
+                             otherwise",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("record-scale")
+                        .long("record-scale")
+                        .value_name("SCALE")
+                        .default_value("1")
+                        .help("How much to scale up recorded frames by"),
+                )
+                .arg(
+                    Arg::with_name("wav")
+                        .long("wav")
+                        .value_name("FILE")
+                        .help("Writes the generated audio to a WAV file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Runs a ROM under the debugger; type `help` at its prompt for commands")
+                .args(&cpu_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("gdb")
+                .about("Runs a ROM under GDB, which connects to a local TCP port")
+                .args(&cpu_args())
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("1234")
+                        .help("The port to listen on"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about(
+                    "Runs test ROMs headless. A test passes if it halts with 0 in register A, \
+                     and fails if it halts with anything else or runs for too long.",
+                )
+                .arg(
+                    Arg::with_name("roms")
+                        .required(true)
+                        .multiple(true)
+                        .value_name("ROM")
+                        .help("The test ROMs to run"),
+                )
+                .arg(
+                    Arg::with_name("max-cycles")
+                        .long("max-cycles")
+                        .value_name("N")
+                        .default_value(TEST_MAX_CYCLES)
+                        .help("How many cycles a test can run for before it fails"),
+                )
+                .arg(
+                    Arg::with_name("coverage")
+                        .long("coverage")
+                        .value_name("FILE")
+                        .help(
+                            "Writes which instructions and branches the tests executed to FILE, \
+                             as an lcov tracefile if it ends in .info or annotated disassembly \
+                             otherwise. Each ROM's symbol and line maps are looked for next to \
+                             it.",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("asm")
+                .about("Assembles a program")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("The source file to assemble"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help(
+                            "Where to write the program, as a ROM if it ends in .rom or raw bytes \
+                             otherwise; defaults to the input with a .rom extension, or .o with \
+                             --object. The symbol and line maps go next to the program, as .sym \
+                             and .lines.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("object")
+                        .short("c")
+                        .long("object")
+                        .help("Writes a relocatable object to link, rather than a program"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("link")
+                .about(
+                    "Links objects into a program, a symbol map and a line map. The program starts \
+                     at `start` or `main`, or else at the start of `text`.",
+                )
+                .arg(
+                    Arg::with_name("inputs")
+                        .required(true)
+                        .multiple(true)
+                        .value_name("FILE")
+                        .help("The objects to link, as written by `asm --object`"),
+                )
+                .arg(
+                    Arg::with_name("map")
+                        .short("T")
+                        .long("map")
+                        .value_name("FILE")
+                        .help(
+                            "The memory map to place sections with; defaults to everything from \
+                             0x8000",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .default_value("a.rom")
+                        .help(
+                            "Where to write the program, as a ROM if it ends in .rom or raw bytes \
+                             otherwise; the symbol and line maps go next to it, as .sym and .lines",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassembles a ROM or raw program")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .value_name("FILE")
+                        .help(
+                            "The ROM or raw program to disassemble. Labels and source lines are \
+                             shown from the symbol and line maps next to it, if there are any.",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("base")
+                        .long("base")
+                        .value_name("ADDRESS")
+                        .help(
+                            "The address a raw program is loaded at; defaults to 0x8000. ROMs \
+                             say where they're loaded themselves.",
+                        ),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("run", Some(matches)) => run(matches),
+        ("debug", Some(matches)) => debug(matches),
+        ("gdb", Some(matches)) => gdb(matches),
+        ("test", Some(matches)) => test(matches),
+        ("asm", Some(matches)) => asm(matches),
+        ("link", Some(matches)) => link_objects(matches),
+        ("disasm", Some(matches)) => disasm(matches),
+        _ => unreachable!(),
+    }
+}
+
+/// Options for setting up the machine, shared by `run` and `debug`
+fn cpu_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("rom")
+            .required(true)
+            .value_name("ROM")
+            .help("The ROM to run, as written by `asm` or `link`"),
+        Arg::with_name("headless")
+            .long("headless")
+            .help("Runs without opening a window"),
+        Arg::with_name("scale")
+            .long("scale")
+            .value_name("SCALE")
+            .possible_values(&["1", "2", "4", "8", "16", "32"])
+            .help("How much the window scales up the screen by; defaults to 16"),
+        Arg::with_name("state")
+            .long("state")
+            .value_name("FILE")
+            .help("Starts from a save state (as saved with F5) instead of the ROM's entry point"),
+        Arg::with_name("replay")
+            .long("replay")
+            .value_name("FILE")
+            .help("Takes key presses from a replay file instead of the window"),
+        Arg::with_name("symbols")
+            .long("symbols")
+            .value_name("FILE")
+            .help(
+                "The symbol map to look labels up in; defaults to the ROM with a .sym extension, \
+                 if there is one",
+            ),
+        Arg::with_name("lines")
+            .long("lines")
+            .value_name("FILE")
+            .help(
+                "The line map to look source lines up in; defaults to the ROM with a .lines \
+                 extension, if there is one",
+            ),
+        Arg::with_name("trace")
+            .long("trace")
+            .value_name("FILE")
+            .help("Writes every instruction to FILE as it's executed, with the registers"),
+        Arg::with_name("trace-range")
+            .long("trace-range")
+            .value_name("START-END")
+            .multiple(true)
+            .number_of_values(1)
+            .requires("trace")
+            .help("Only traces instructions between START and END, inclusive"),
+    ]
+}
+
+/// Creates a CPU and loads the ROM into it, as set up by `cpu_args`
+fn load_cpu(matches: &ArgMatches) -> CPU {
+    let rom = open_rom(matches.value_of("rom").unwrap());
+
+    let mut cpu = if matches.is_present("headless") {
+        CPU::headless()
+    } else {
+        let scale = parse_arg(matches, "scale").unwrap_or(WINDOW_SCALE);
+        assert!(WINDOW_SCALES.contains(&scale));
+
+        CPU::with_ppu(PPU::with_scale(scale))
+    };
+    cpu.load_rom(&rom);
+    cpu.debug_info = DebugInfo::new(load_symbols(matches), load_lines(matches));
+
+    if let Some(path) = matches.value_of("state") {
+        cpu.load_state(path).unwrap_or_else(|e| fail(path, e));
+    }
+
+    if let Some(path) = matches.value_of("replay") {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| fail(path, e));
+        cpu.start_replay(Replay::parse(&text).unwrap_or_else(|e| fail(path, e)));
+    }
+
+    if let Some(path) = matches.value_of("trace") {
+        let mut tracer = Tracer::create(path).unwrap_or_else(|e| fail(path, e));
+        if let Some(ranges) = matches.values_of("trace-range") {
+            tracer.ranges = ranges
+                .map(|range| {
+                    parse_range(range).unwrap_or_else(|| {
+                        eprintln!("invalid range `{}`", range);
+                        exit(1);
+                    })
+                })
+                .collect();
+        }
+        cpu.tracer = Some(tracer);
+    }
+
+    cpu
+}
+
+/// Reads the symbol map given by `--symbols`, or the one next to the ROM if there is one, or
+/// returns an empty map if neither is
+fn load_symbols(matches: &ArgMatches) -> SymbolMap {
+    match matches.value_of("symbols") {
+        Some(path) => read_map(Path::new(path), SymbolMap::parse),
+        None => map_next_to(matches.value_of("rom").unwrap(), "sym", SymbolMap::parse),
+    }
+}
+
+/// Reads the line map given by `--lines`, or the one next to the ROM if there is one, or returns
+/// an empty map if neither is
+fn load_lines(matches: &ArgMatches) -> LineMap {
+    match matches.value_of("lines") {
+        Some(path) => read_map(Path::new(path), LineMap::parse),
+        None => map_next_to(matches.value_of("rom").unwrap(), "lines", LineMap::parse),
+    }
+}
+
+/// Reads the map next to `rom` with `extension`, or returns an empty one if there isn't one
+fn map_next_to<T: Default>(rom: &str, extension: &str, parse: fn(&str) -> Result<T, String>) -> T {
+    let path = Path::new(rom).with_extension(extension);
+    if path.exists() {
+        read_map(&path, parse)
+    } else {
+        T::default()
+    }
+}
+
+/// Reads the symbol and line maps next to `rom`, if there are any
+fn debug_info_next_to(rom: &str) -> DebugInfo {
+    DebugInfo::new(
+        map_next_to(rom, "sym", SymbolMap::parse),
+        map_next_to(rom, "lines", LineMap::parse),
+    )
+}
+
+/// Reads the symbol or line map at `path`, and exits if it's invalid
+fn read_map<T>(path: &Path, parse: fn(&str) -> Result<T, String>) -> T {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| fail(path.display(), e));
+    parse(&text).unwrap_or_else(|e| fail(path.display(), e))
+}
+
+/// Where `--coverage` is written to, and the coverage gathered for it so far
+struct CoverageReport {
+    path: PathBuf,
+    /// Coverage of the source, if it's going to be an lcov tracefile
+    lcov: Option<Lcov>,
+    /// Otherwise, the annotated disassembly of each program
+    listings: Vec<String>,
+}
+
+impl CoverageReport {
+    fn new(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        let lcov =
+            Some(Lcov::new()).filter(|_| matches!(path.extension(), Some(ext) if ext == "info"));
+
+        Self {
+            path,
+            lcov,
+            listings: Vec::new(),
+        }
+    }
+
+    /// Adds the coverage `cpu` gathered running `rom`, which is called `name`
+    fn add(&mut self, name: &str, cpu: &CPU, rom: &Rom) {
+        let coverage = cpu.coverage.as_ref().unwrap();
+        let DebugInfo { symbols, lines } = &cpu.debug_info;
+
+        match &mut self.lcov {
+            Some(lcov) => {
+                if lines.is_empty() {
+                    eprintln!(
+                        "warning: there's no line map for {}, so it isn't in the coverage",
+                        name
+                    );
+                }
+                lcov.add(coverage, &cpu.memory, lines);
+            }
+            None => {
+                let end = rom.load_address as usize + rom.program.len();
+                let listing = coverage.annotate(
+                    &cpu.memory,
+                    rom.load_address,
+                    end.min(0xFFFF) as u16,
+                    symbols,
+                    lines,
+                );
+                self.listings.push(format!("; {}\n{}", name, listing));
+            }
+        }
+    }
+
+    fn write(&self) {
+        let written = match &self.lcov {
+            Some(lcov) => File::create(&self.path).and_then(|file| {
+                let mut out = BufWriter::new(file);
+                lcov.write(&mut out)?;
+                out.flush()
+            }),
+            None => fs::write(&self.path, self.listings.join("\n")),
+        };
+
+        written.unwrap_or_else(|e| fail(self.path.display(), e));
+    }
+}
+
+/// A CPU, and the options from the command line for how `run` runs it
+struct Machine {
+    cpu: CPU,
+    max_cycles: Option<u64>,
+    /// How many cycles to run every second, and when we started
+    clock: Option<(u32, Instant)>,
+    /// Keeps the history the rewind key goes back through, when there's a window to press it in
+    rewinder: Option<Rewinder>,
+    /// Where F5 saves the state to, and F9 loads it from
+    state_path: PathBuf,
+    /// The movie being recorded, and where to write it
+    recording: Option<(Movie, PathBuf)>,
+    /// The movie being played back, and the first frame it didn't match in, if any
+    movie: Option<Movie>,
+    desync: Option<MovieError>,
+    /// The frame the movie was last recorded or checked in
+    frame: u64,
+    /// The instruction that stopped the program, if one couldn't be executed
+    fault: Option<Fault>,
+}
+
+impl Machine {
+    /// Loads the ROM, and sets up the options for `run`
+    fn new(matches: &ArgMatches) -> Self {
+        let mut cpu = load_cpu(matches);
+        if let Some(watches) = matches.values_of("watch") {
+            cpu.watchpoints = watches
+                .map(|watch| {
+                    parse_watchpoint(watch).unwrap_or_else(|| {
+                        eprintln!("invalid watchpoint `{}`", watch);
+                        exit(1);
+                    })
+                })
+                .collect();
+        }
+
+        let rom_path = matches.value_of("rom").unwrap();
+        let rom_checksum = || rom::crc32(&open_rom(rom_path).program);
+
+        let recording = matches.value_of("record-movie").map(|path| {
+            let mut movie = Movie::new(rom_checksum());
+            movie.record(&cpu);
+            (movie, PathBuf::from(path))
+        });
+
+        let mut desync = None;
+        let movie = matches.value_of("movie").map(|path| {
+            let text = fs::read_to_string(path).unwrap_or_else(|e| fail(path, e));
+            let movie = Movie::parse(&text).unwrap_or_else(|e| fail(path, e));
+            if movie.rom_checksum != rom_checksum() {
+                fail(
+                    path,
+                    format!(
+                        "the movie was recorded with a different ROM (its checksum is {:08x}, not \
+                         {:08x})",
+                        movie.rom_checksum,
+                        rom_checksum()
+                    ),
+                );
+            }
+
+            cpu.start_replay(movie.replay());
+            desync = movie.check(&cpu).err();
+            movie
+        });
+
+        let rewinder = cpu.ppu.window.as_ref().map(|_| {
+            let mut rewinder = Rewinder::new();
+            rewinder.record(&cpu);
+            rewinder
+        });
+
+        Self {
+            cpu,
+            rewinder,
+            state_path: Path::new(rom_path).with_extension("state"),
+            recording,
+            movie,
+            desync,
+            frame: 0,
+            fault: None,
+            max_cycles: parse_arg(matches, "max-cycles"),
+            clock: parse_arg(matches, "clock").map(|hz| (hz, Instant::now())),
+        }
+    }
+
+    /// Executes a single instruction. Returns false once it's time to stop, because the program
+    /// halted or faulted, the window was closed or we've run for long enough.
+    fn step(&mut self) -> bool {
+        if matches!(self.max_cycles, Some(max) if self.cpu.cycles >= max) || self.desync.is_some() {
+            return false;
+        }
+
+        if matches!(&self.movie, Some(movie) if movie.finished(self.cpu.ppu.frame)) {
+            return false;
+        }
+
+        let running = match self.cpu.try_step() {
+            Ok(running) => running,
+            Err(fault) => {
+                self.fault = Some(fault);
+                false
+            }
+        };
+        for hit in self.cpu.take_watch_hits() {
+            eprintln!("{}", hit);
+        }
+
+        if let Some(rewinder) = &mut self.rewinder {
+            rewinder.record(&self.cpu);
+
+            // Holding the rewind key goes back a snapshot every frame, until it's let go
+            let frame = Duration::from_secs_f64(1.0 / self.cpu.ppu.frame_rate());
+            let mut rewound = false;
+            while self.cpu.ppu.rewind_held() && rewinder.back(&mut self.cpu) {
+                self.cpu.ppu.show();
+                thread::sleep(frame);
+                rewound = true;
+            }
+
+            if rewound {
+                self.reset_clock();
+            }
+        }
+
+        match self.cpu.ppu.hotkey.take() {
+            Some(Hotkey::SaveState) => match self.cpu.save_state(&self.state_path) {
+                Ok(()) => println!("Saved the state to {}", self.state_path.display()),
+                Err(e) => eprintln!(
+                    "Could not save the state to {}: {}",
+                    self.state_path.display(),
+                    e
+                ),
+            },
+            Some(Hotkey::LoadState) => match self.cpu.load_state(&self.state_path) {
+                Ok(()) => {
+                    println!("Loaded the state from {}", self.state_path.display());
+                    self.cpu.ppu.show();
+
+                    // The history before the state was loaded has nothing to do with it
+                    if let Some(rewinder) = &mut self.rewinder {
+                        *rewinder = Rewinder::new();
+                        rewinder.record(&self.cpu);
+                    }
+                    self.reset_clock();
+                }
+                Err(e) => eprintln!(
+                    "Could not load the state from {}: {}",
+                    self.state_path.display(),
+                    e
+                ),
+            },
+            None => (),
+        }
+
+        // Rewinding or loading a state can go back a frame or more, which is recorded over
+        if self.cpu.ppu.frame != self.frame {
+            self.frame = self.cpu.ppu.frame;
+
+            if let Some((movie, _)) = &mut self.recording {
+                movie.record(&self.cpu);
+            }
+            if let Some(movie) = &self.movie {
+                self.desync = movie.check(&self.cpu).err();
+            }
+        }
+
+        if let Some((hz, start)) = self.clock {
+            let due = Duration::from_secs_f64(self.cpu.cycles as f64 / hz as f64);
+            let ahead = due.checked_sub(start.elapsed()).unwrap_or_default();
+            if ahead > THROTTLE_SLACK {
+                thread::sleep(ahead);
+            }
+        }
+
+        running
+    }
+
+    /// Starts the clock again from the current cycle count, e.g. once it's gone back
+    fn reset_clock(&mut self) {
+        if let Some((hz, start)) = &mut self.clock {
+            let due = Duration::from_secs_f64(self.cpu.cycles as f64 / *hz as f64);
+            *start = Instant::now().checked_sub(due).unwrap_or_else(Instant::now);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(tracer) = &mut self.cpu.tracer {
+            tracer.flush().unwrap_or_else(|e| {
+                eprintln!("Could not write the trace: {}", e);
+            });
+        }
+
+        if let Some((movie, path)) = &self.recording {
+            let written = File::create(path).and_then(|file| {
+                let mut out = BufWriter::new(file);
+                movie.write(&mut out)?;
+                out.flush()
+            });
+            written.unwrap_or_else(|e| fail(path.display(), e));
+        }
+    }
+
+    /// Reports how playing back the movie went, if there was one, once the program has stopped.
+    /// Returns false if it didn't match all the way through.
+    fn finish_movie(&self) -> bool {
+        let movie = match &self.movie {
+            Some(movie) => movie,
+            None => return true,
+        };
+
+        let frame = self.cpu.ppu.frame;
+        let error = match self.desync {
+            Some(desync) => Some(desync),
+            None if !movie.finished(frame) => Some(MovieError::Stopped { frame }),
+            None => None,
+        };
+
+        match error {
+            Some(error) => {
+                eprintln!("{}", error);
+                false
+            }
+            None => {
+                println!("Movie finished: all {} frames matched", movie.frames.len());
+                true
+            }
+        }
+    }
+}
 
-    let mut cpu = CPU::new();
-    let program = vec![
-        0x10, // write to...
-        0x00, // register A
-        0xFF, // the value.
+fn run(matches: &ArgMatches) {
+    let mut machine = Machine::new(matches);
+    let frames = parse_arg::<u64>(matches, "frames");
 
-        0xFF,
+    if let Some(path) = matches.value_of("record") {
+        let scale = parse_arg(matches, "record-scale").unwrap();
+        machine
+            .cpu
+            .ppu
+            .start_recording(path, scale)
+            .unwrap_or_else(|e| fail(path, e));
+    }
 
-        0x20, // write to (memory)...
-        0x02, // the region in memory pt. 1
-        0x00, // the region in memory pt. 2
-        0x00, // register A,
+    if let Some(path) = matches.value_of("wav") {
+        let sink = WavSink::create(path).unwrap_or_else(|e| fail(path, e));
+        machine.cpu.apu.sink = Some(Box::new(sink));
+    }
 
-        0xFF,
+    if matches.is_present("profile") || matches.is_present("profile-folded") {
+        machine.cpu.profiler = Some(Profiler::new());
+    }
+    if matches.is_present("coverage") {
+        machine.cpu.coverage = Some(Coverage::new());
+    }
 
-        0x20, // write to (memory)...
-        0x05, // the region in memory pt. 1
-        0xFF, // the region in memory pt. 2
-        0x00, // register A,
+    while !matches!(frames, Some(frames) if machine.cpu.ppu.frame >= frames) && machine.step() {}
+    machine.finish();
 
-        // mov 1 to register B
-        0x10,
-        0x01,
-        0x01,
+    if let Some(profiler) = &machine.cpu.profiler {
+        let symbols = &machine.cpu.debug_info.symbols;
 
-        0x40, // jump if...
-        0x01, // register B is true...
-        0x80,
-        0x00, // this region in memory
+        if let Some(path) = matches.value_of("profile") {
+            fs::write(path, profiler.report(&machine.cpu.memory, symbols))
+                .unwrap_or_else(|e| fail(path, e));
+        }
 
-        0xFF,
+        if let Some(path) = matches.value_of("profile-folded") {
+            File::create(path)
+                .and_then(|file| {
+                    let mut out = BufWriter::new(file);
+                    profiler.write_folded(&mut out, symbols)?;
+                    out.flush()
+                })
+                .unwrap_or_else(|e| fail(path, e));
+        }
+    }
 
-        0x00, // HALT
-    ];
+    if let Some(path) = matches.value_of("coverage") {
+        let rom = matches.value_of("rom").unwrap();
+
+        let mut report = CoverageReport::new(path);
+        report.add(rom, &machine.cpu, &open_rom(rom));
+        report.write();
+    }
+
+    if let Some(path) = matches.value_of("save-state") {
+        machine
+            .cpu
+            .save_state(path)
+            .unwrap_or_else(|e| fail(path, e));
+    }
+
+    if let Some(path) = matches.value_of("screenshot") {
+        let scale = parse_arg(matches, "screenshot-scale").unwrap();
+        machine
+            .cpu
+            .ppu
+            .screenshot(path, scale)
+            .unwrap_or_else(|e| fail(path, e));
+    }
+
+    machine.cpu.ppu.stop_recording().unwrap();
+    machine.cpu.apu.close_sink().unwrap();
+
+    if let Some(fault) = &machine.fault {
+        eprintln!("Fault: {}", fault);
+        exit(1);
+    }
+    if !machine.finish_movie() {
+        exit(1);
+    }
+}
+
+/// Runs the debugger's prompt until `quit` or the end of input
+fn debug(matches: &ArgMatches) {
+    let mut debugger = Debugger::new(load_cpu(matches));
+
+    println!("{}", debugger.execute("disassemble"));
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(maxemu) ");
+        io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(line) => line.unwrap(),
+            None => break,
+        };
+
+        match line.trim() {
+            "quit" | "q" => break,
+            line => println!("{}", debugger.execute(line)),
+        }
+    }
+}
+
+/// Waits for GDB to connect, and lets it debug the ROM until it detaches
+fn gdb(matches: &ArgMatches) {
+    let port = parse_arg::<u16>(matches, "port").unwrap();
+    let mut stub = GdbStub::new(load_cpu(matches));
+
+    println!("Waiting for GDB to connect to 127.0.0.1:{}", port);
+    gdb::listen(("127.0.0.1", port), &mut stub).unwrap_or_else(|e| fail("gdb", e));
+}
+
+/// Runs every test ROM, and fails if any of them do
+fn test(matches: &ArgMatches) {
+    let max_cycles = parse_arg::<u64>(matches, "max-cycles").unwrap();
+    let mut failed = 0;
+    let roms = matches.values_of("roms").unwrap().collect::<Vec<_>>();
+
+    let mut report = matches.value_of("coverage").map(CoverageReport::new);
+
+    for &path in &roms {
+        let rom = open_rom(path);
+        let mut cpu = CPU::headless();
+        cpu.load_rom(&rom);
+        cpu.debug_info = debug_info_next_to(path);
+        if report.is_some() {
+            cpu.coverage = Some(Coverage::new());
+        }
+
+        // Whether the program halted, or the fault that stopped it, once it's stopped
+        let mut stopped = None;
+        while cpu.cycles < max_cycles {
+            match cpu.try_step() {
+                Ok(true) => (),
+                Ok(false) => stopped = Some(Ok(())),
+                Err(fault) => stopped = Some(Err(fault)),
+            }
+            if stopped.is_some() {
+                break;
+            }
+        }
+
+        let result = match (stopped, cpu.registers[0]) {
+            (None, _) => Err(format!("still running after {} cycles", cpu.cycles)),
+            (Some(Err(fault)), _) => Err(fault.to_string()),
+            (Some(Ok(())), 0) => Ok(()),
+            (Some(Ok(())), code) => Err(format!(
+                "halted at {} with A = {:#04x}",
+                cpu.debug_info.location(cpu.instruction_pc),
+                code
+            )),
+        };
+
+        match result {
+            Ok(()) => println!("test {} ... ok", path),
+            Err(e) => {
+                println!("test {} ... FAILED: {}", path, e);
+                failed += 1;
+            }
+        }
+
+        if let Some(report) = &mut report {
+            report.add(path, &cpu, &rom);
+        }
+    }
+
+    if let Some(report) = &report {
+        report.write();
+    }
+
+    println!("{} passed; {} failed", roms.len() - failed, failed);
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+fn asm(matches: &ArgMatches) {
+    let input = Path::new(matches.value_of("input").unwrap());
+    let object = matches.is_present("object");
+    let output = match matches.value_of("output") {
+        Some(output) => Path::new(output).to_path_buf(),
+        None => input.with_extension(if object { "o" } else { "rom" }),
+    };
+
+    if object {
+        let object = assemble_object_file(input).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1);
+        });
+
+        File::create(&output)
+            .and_then(|file| object.write(BufWriter::new(file)))
+            .unwrap_or_else(|e| fail(output.display(), e));
+    } else {
+        let image = assemble_image_file(input).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1);
+        });
+
+        write_maps(&output, &image);
+        write_program(
+            &output,
+            Rom {
+                entry: image.entry,
+                load_address: image.start,
+                program: image.data,
+            },
+        );
+    }
+}
+
+fn link_objects(matches: &ArgMatches) {
+    let objects = matches
+        .values_of("inputs")
+        .unwrap()
+        .map(|input| {
+            let text = fs::read_to_string(input).unwrap_or_else(|e| fail(input, e));
+            Object::parse(&text).unwrap_or_else(|e| fail(input, e))
+        })
+        .collect::<Vec<_>>();
+
+    let map = match matches.value_of("map") {
+        Some(path) => {
+            let text = fs::read_to_string(path).unwrap_or_else(|e| fail(path, e));
+            MemoryMap::parse(&text).unwrap_or_else(|e| fail(path, e))
+        }
+        None => MemoryMap::default(),
+    };
+
+    let image = link(&objects, &map).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+
+    let output = Path::new(matches.value_of("output").unwrap());
+    write_maps(output, &image);
+
+    write_program(
+        output,
+        Rom {
+            entry: image.entry,
+            load_address: image.start,
+            program: image.data,
+        },
+    );
+}
+
+fn disasm(matches: &ArgMatches) {
+    let input = matches.value_of("input").unwrap();
+    let bytes = fs::read(input).unwrap_or_else(|e| fail(input, e));
+
+    let (program, base) = if bytes.starts_with(rom::MAGIC) {
+        let rom = Rom::parse(&bytes).unwrap_or_else(|e| fail(input, e));
+        (rom.program, rom.load_address)
+    } else {
+        let base = match matches.value_of("base") {
+            Some(base) => parse_address(base).unwrap_or_else(|| {
+                eprintln!("invalid address `{}`", base);
+                exit(1);
+            }),
+            None => ORIGIN,
+        };
+        (bytes, base)
+    };
+
+    let debug_info = debug_info_next_to(input);
+    for line in debug_info.listing(&disassemble(&program, base)) {
+        println!("{}", line);
+    }
+}
+
+fn open_rom(path: &str) -> Rom {
+    Rom::open(path).unwrap_or_else(|e| fail(path, e))
+}
+
+/// Writes the symbol and line maps of `image` next to `output`, as .sym and .lines
+fn write_maps(output: &Path, image: &Image) {
+    let symbols = output.with_extension("sym");
+    File::create(&symbols)
+        .and_then(|file| image.symbols.write(BufWriter::new(file)))
+        .unwrap_or_else(|e| fail(symbols.display(), e));
+
+    let lines = output.with_extension("lines");
+    File::create(&lines)
+        .and_then(|file| image.lines.write(BufWriter::new(file)))
+        .unwrap_or_else(|e| fail(lines.display(), e));
+}
+
+/// Writes `rom` to `output` if it ends in `.rom`, or just its program otherwise
+fn write_program(output: &Path, rom: Rom) {
+    let written = if matches!(output.extension(), Some(ext) if ext == "rom") {
+        rom.save(output).map_err(|e| e.to_string())
+    } else {
+        if rom.load_address != ORIGIN {
+            eprintln!(
+                "warning: the program starts at {:#06x}, not where raw programs are loaded",
+                rom.load_address
+            );
+        }
+        fs::write(output, &rom.program).map_err(|e| e.to_string())
+    };
+
+    written.unwrap_or_else(|e| fail(output.display(), e));
+}
+
+/// Parses the value of the argument `name`, if it was given, and exits if it's invalid
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("--{} must be a number, not `{}`", name, value);
+            exit(1);
+        })
+    })
+}
+
+/// Parses an address given either in hex (with a leading `0x`) or in decimal
+fn parse_address(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Parses `START-END`, as given to `--trace-range`
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (start, end) = text.split_once('-')?;
+    let (start, end) = (parse_address(start)?, parse_address(end)?);
+
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Parses a watchpoint given as `KIND:ADDRESS[:LENGTH]`
+fn parse_watchpoint(text: &str) -> Option<Watchpoint> {
+    let parts = text.split(':').collect::<Vec<_>>();
+    let (kind, start, length) = match parts.as_slice() {
+        [kind, start] => (kind, start, "1"),
+        [kind, start, length] => (kind, start, *length),
+        _ => return None,
+    };
+
+    let start = parse_address(start)?;
+    let length = parse_address(length).filter(|&length| length > 0)?;
+    let end = start.checked_add(length - 1).filter(|&end| end < 0xFFFF)?;
+
+    Some(Watchpoint::new(WatchKind::parse(kind)?, start, end))
+}
 
-    cpu.load(program);
-    cpu.run();
+/// Reports an error with `path` and exits
+fn fail<P: Display, E: Display>(path: P, error: E) -> ! {
+    eprintln!("{}: {}", path, error);
+    exit(1);
 }
@@ -0,0 +1,336 @@
+//! Movies: the keys pressed in every frame of a run, along with a checksum of the whole machine
+//! at each one, so the run can be played back exactly and any point where it goes differently
+//! (a desync) is caught straight away.
+//!
+//! A movie is a text file. After the version and the CRC-32 of the ROM's program it was
+//! recorded with, there's a line for every frame from 0 (when the ROM was loaded) on, with the
+//! value of the key register and the checksum of the machine once the frame had been presented:
+//!
+//! ```text
+//! ; MaxEmu movie
+//! version 1
+//! rom 9f3a0c11
+//! 0 00 5d1e0a33
+//! 1 00 0b7c41f2
+//! 2 04 e2d90c5a
+//! ```
+
+use crate::cpu::CPU;
+use crate::ppu::KEY_ADDR;
+use crate::replay::Replay;
+use std::fmt;
+use std::io::{self, Write};
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// The value of the key register
+    pub key: u8,
+    /// `Snapshot::checksum` of the machine
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    /// The CRC-32 of the program in the ROM the movie was recorded with
+    pub rom_checksum: u32,
+    /// Every frame, from frame 0 on
+    pub frames: Vec<Frame>,
+}
+
+/// Why a movie didn't play back the same way it was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    /// The machine's checksum didn't match the movie's in `frame`
+    Desync {
+        frame: u64,
+        expected: u32,
+        actual: u32,
+    },
+    /// The program halted or the window was closed in `frame`, before the movie's last frame
+    Stopped { frame: u64 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Desync {
+                frame,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Desync in frame {}: the movie's checksum is {:08x}, but the machine's is {:08x}",
+                frame, expected, actual
+            ),
+            MovieError::Stopped { frame } => write!(
+                f,
+                "The program stopped in frame {}, before the end of the movie",
+                frame
+            ),
+        }
+    }
+}
+
+impl Movie {
+    /// An empty movie, for recording a ROM whose program has a CRC-32 of `rom_checksum`
+    pub fn new(rom_checksum: u32) -> Self {
+        Self {
+            rom_checksum,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Reads a movie in the format described above
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.split(';').next().unwrap().trim()))
+            .filter(|(_, line)| !line.is_empty());
+
+        let mut header = |name: &str| match lines.next() {
+            Some((i, line)) => match line.strip_prefix(name) {
+                Some(value) => Ok((i, value.trim())),
+                None => Err(format!("line {}: expected `{}`, not `{}`", i, name, line)),
+            },
+            None => Err(format!("the movie ends before `{}`", name)),
+        };
+
+        let (i, version) = header("version")?;
+        if version != VERSION.to_string() {
+            return Err(format!(
+                "line {}: unsupported movie version `{}` (only version {} is supported)",
+                i, version, VERSION
+            ));
+        }
+
+        let (i, rom_checksum) = header("rom")?;
+        let rom_checksum = u32::from_str_radix(rom_checksum, 16)
+            .map_err(|_| format!("line {}: invalid ROM checksum `{}`", i, rom_checksum))?;
+
+        let mut movie = Self::new(rom_checksum);
+        for (i, line) in lines {
+            let error = |message: String| format!("line {}: {}", i, message);
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (frame, key, checksum) = match fields.as_slice() {
+                [frame, key, checksum] => (frame, key, checksum),
+                _ => {
+                    return Err(error(format!(
+                        "expected a frame, a key and a checksum, not `{}`",
+                        line
+                    )))
+                }
+            };
+
+            if frame.parse() != Ok(movie.frames.len()) {
+                return Err(error(format!(
+                    "expected frame {}, not `{}`",
+                    movie.frames.len(),
+                    frame
+                )));
+            }
+
+            movie.frames.push(Frame {
+                key: u8::from_str_radix(key, 16)
+                    .map_err(|_| error(format!("invalid key `{}`", key)))?,
+                checksum: u32::from_str_radix(checksum, 16)
+                    .map_err(|_| error(format!("invalid checksum `{}`", checksum)))?,
+            });
+        }
+
+        Ok(movie)
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "; MaxEmu movie")?;
+        writeln!(out, "version {}", VERSION)?;
+        writeln!(out, "rom {:08x}", self.rom_checksum)?;
+
+        for (i, frame) in self.frames.iter().enumerate() {
+            writeln!(out, "{} {:02x} {:08x}", i, frame.key, frame.checksum)?;
+        }
+
+        Ok(())
+    }
+
+    /// The movie's keys as a replay, which presses them in the same frames
+    pub fn replay(&self) -> Replay {
+        let mut replay = Replay::default();
+        for (i, frame) in self.frames.iter().enumerate() {
+            replay.push(i as u64, frame.key);
+        }
+
+        replay
+    }
+
+    /// Records the frame `cpu` is in, which should have only just been presented. Going back to
+    /// an earlier frame (by rewinding) records over the frames after it. A frame past the end of
+    /// the movie, e.g. from a loaded save state, can't be recorded and is left out.
+    pub fn record(&mut self, cpu: &CPU) {
+        let frame = cpu.ppu.frame as usize;
+        if frame > self.frames.len() {
+            return;
+        }
+
+        self.frames.truncate(frame);
+        self.frames.push(Frame {
+            key: cpu.memory[KEY_ADDR as usize],
+            checksum: cpu.snapshot().checksum(),
+        });
+    }
+
+    /// Checks `cpu` against the frame it's in, which should have only just been presented
+    pub fn check(&self, cpu: &CPU) -> Result<(), MovieError> {
+        let frame = cpu.ppu.frame;
+        let expected = match self.frames.get(frame as usize) {
+            Some(recorded) => recorded.checksum,
+            None => return Ok(()),
+        };
+
+        let actual = cpu.snapshot().checksum();
+        if expected != actual {
+            return Err(MovieError::Desync {
+                frame,
+                expected,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Whether `frame` is the last one in the movie, or after it
+    pub fn finished(&self, frame: u64) -> bool {
+        frame as usize + 1 >= self.frames.len()
+    }
+
+    /// Plays the whole movie on `cpu`, which should have just had the ROM loaded, checking every
+    /// frame
+    pub fn play(&self, cpu: &mut CPU) -> Result<(), MovieError> {
+        cpu.start_replay(self.replay());
+        self.check(cpu)?;
+
+        while !self.finished(cpu.ppu.frame) {
+            if !cpu.run_frames(1) {
+                return Err(MovieError::Stopped {
+                    frame: cpu.ppu.frame,
+                });
+            }
+            self.check(cpu)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    /// Draws whatever key is pressed, and halts once `s` is
+    const PROGRAM: &str = "\
+loop: LOAD A, [0x0100]
+STORE [0x0200], A
+ADD B, A, B
+STORE [0x0201], B
+EQ A, 3, C
+JT C, done
+LOAD D, 1
+JT D, loop
+done: HALT";
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::headless();
+        cpu.load(assemble(PROGRAM).unwrap());
+        cpu
+    }
+
+    /// Records a movie of the program with the keys in `replay`, for `frames` frames
+    fn record(replay: &str, frames: u64) -> Movie {
+        let mut cpu = cpu();
+        cpu.start_replay(Replay::parse(replay).unwrap());
+
+        let mut movie = Movie::new(0x1234_5678);
+        movie.record(&cpu);
+        for _ in 0..frames {
+            cpu.run_frames(1);
+            movie.record(&cpu);
+        }
+
+        movie
+    }
+
+    #[test]
+    fn test_play() {
+        let movie = record("2 d\n4 w\n5 -", 8);
+        assert_eq!(movie.frames.len(), 9);
+        assert_eq!(movie.replay(), Replay::parse("2 d\n4 w\n5 -").unwrap());
+
+        assert_eq!(movie.play(&mut cpu()), Ok(()));
+    }
+
+    #[test]
+    fn test_desync() {
+        let mut movie = record("2 d\n4 w\n5 -", 8);
+
+        // Holding a key for a frame longer changes what's drawn from then on
+        movie.frames[4].key = 4;
+        let actual = match movie.play(&mut cpu()) {
+            Err(MovieError::Desync {
+                frame: 4,
+                expected,
+                actual,
+            }) if expected == movie.frames[4].checksum => actual,
+            result => panic!("expected a desync in frame 4, not {:?}", result),
+        };
+        assert_ne!(actual, movie.frames[4].checksum);
+
+        // The program halts when it sees `s`, so it can't reach any frames after that
+        let mut movie = record("2 s", 2);
+        movie.frames.push(movie.frames[2]);
+        assert_eq!(
+            movie.play(&mut cpu()),
+            Err(MovieError::Stopped { frame: 2 })
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let movie = record("1 a\n3 -", 4);
+
+        let mut text = Vec::new();
+        movie.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+
+        assert!(text.starts_with("; MaxEmu movie\nversion 1\nrom 12345678\n0 00 "));
+        assert_eq!(Movie::parse(&text), Ok(movie));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Movie::parse("version 2\nrom 0"),
+            Err("line 1: unsupported movie version `2` (only version 1 is supported)".to_string())
+        );
+        assert_eq!(
+            Movie::parse("; no header"),
+            Err("the movie ends before `version`".to_string())
+        );
+        assert_eq!(
+            Movie::parse("version 1\nframe 0"),
+            Err("line 2: expected `rom`, not `frame 0`".to_string())
+        );
+        assert_eq!(
+            Movie::parse("version 1\nrom 0\n0 00 0\n2 00 0"),
+            Err("line 4: expected frame 1, not `2`".to_string())
+        );
+        assert_eq!(
+            Movie::parse("version 1\nrom 0\n0 xx 0"),
+            Err("line 3: invalid key `xx`".to_string())
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Relocatable object files, as made by `asm::assemble_object` and put together by `link::link`.
+//!
+//! Objects are saved as text, one record per line:
+//!
+//! ```text
+//! MAXOBJ 1
+//! section text -
+//! data 1000FF40018000
+//! section vectors FFFC
+//! data 0000
+//! symbol main global 0 0
+//! symbol SIZE local - 3
+//! reloc 0 5 address section 0 0 12 main.s
+//! reloc 1 0 word symbol handler 0 3 main.s
+//! line 0 0 11 main.s
+//! line 0 3 12 main.s
+//! ```
+//!
+//! A `section` has a name and either a fixed address or `-`, and is followed by its contents as
+//! hex. A `symbol` has a name, whether it's global, the index of its section (or `-` for a
+//! constant) and its offset into that section (or its value). A `reloc` has the index of the
+//! section and the offset to patch, its kind, what it refers to, a value to add, and the source
+//! line and file it came from. A `line` has the index of the section and the offset of an
+//! instruction, and the source line and file it came from, for the line map.
+
+use crate::lines::SourceLine;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+const MAGIC: &str = "MAXOBJ";
+const VERSION: u32 = 1;
+
+/// How many bytes of a section go on each `data` line
+const BYTES_PER_LINE: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    pub lines: Vec<LineEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    /// Where the section has to go, for code assembled at a known address
+    pub address: Option<u16>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// The index of the section it's in, or `None` for a constant
+    pub section: Option<usize>,
+    /// The offset into its section, or the constant's value
+    pub value: i64,
+    /// Whether other objects can refer to it
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    /// An 8-bit value
+    Byte,
+    /// A 16-bit value, stored as Big Endian
+    Word,
+    /// A 16-bit address, stored as Big Endian
+    Address,
+}
+
+/// What a relocation refers to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// The start of a section in the same object, by index
+    Section(usize),
+    /// A symbol, in the same object or a global in another
+    Symbol(String),
+}
+
+/// A value that can't be known until the object is linked, and where it goes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    /// The index of the section to patch
+    pub section: usize,
+    pub offset: usize,
+    pub kind: RelocationKind,
+    pub target: Target,
+    /// Added to the target's address
+    pub addend: i64,
+    /// The file and line it came from, for errors
+    pub file: Option<PathBuf>,
+    pub line: usize,
+}
+
+/// Where an instruction came from in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineEntry {
+    /// The index of the section the instruction is in
+    pub section: usize,
+    pub offset: usize,
+    pub source: SourceLine,
+}
+
+impl RelocationKind {
+    /// How many bytes the relocation patches
+    pub fn size(self) -> usize {
+        match self {
+            RelocationKind::Byte => 1,
+            RelocationKind::Word | RelocationKind::Address => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RelocationKind::Byte => "byte",
+            RelocationKind::Word => "word",
+            RelocationKind::Address => "address",
+        }
+    }
+}
+
+impl Object {
+    /// Writes the object out in the format described above
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "{} {}", MAGIC, VERSION)?;
+
+        for section in &self.sections {
+            match section.address {
+                Some(address) => writeln!(out, "section {} {:04X}", section.name, address)?,
+                None => writeln!(out, "section {} -", section.name)?,
+            }
+
+            for chunk in section.data.chunks(BYTES_PER_LINE) {
+                let hex = chunk
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect::<String>();
+                writeln!(out, "data {}", hex)?;
+            }
+        }
+
+        for symbol in &self.symbols {
+            writeln!(
+                out,
+                "symbol {} {} {} {}",
+                symbol.name,
+                if symbol.global { "global" } else { "local" },
+                symbol
+                    .section
+                    .map_or_else(|| "-".to_string(), |section| section.to_string()),
+                symbol.value
+            )?;
+        }
+
+        for relocation in &self.relocations {
+            let target = match &relocation.target {
+                Target::Section(section) => format!("section {}", section),
+                Target::Symbol(name) => format!("symbol {}", name),
+            };
+
+            write!(
+                out,
+                "reloc {} {} {} {} {} {}",
+                relocation.section,
+                relocation.offset,
+                relocation.kind.name(),
+                target,
+                relocation.addend,
+                relocation.line
+            )?;
+            if let Some(file) = &relocation.file {
+                write!(out, " {}", file.display())?;
+            }
+            writeln!(out)?;
+        }
+
+        for entry in &self.lines {
+            write!(
+                out,
+                "line {} {} {}",
+                entry.section, entry.offset, entry.source.line
+            )?;
+            if let Some(file) = &entry.source.file {
+                write!(out, " {}", file.display())?;
+            }
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads an object written by `write`. Errors say which line they're on.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate();
+        let mut object = Object::default();
+
+        match lines.next() {
+            Some((_, header)) if header == format!("{} {}", MAGIC, VERSION) => (),
+            Some((_, header)) if header.starts_with(MAGIC) => {
+                return Err(format!("unsupported object version `{}`", header))
+            }
+            _ => return Err("not an object file".to_string()),
+        }
+
+        for (i, line) in lines {
+            let error = |message: &str| format!("line {}: {}", i + 1, message);
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+
+            match fields.as_slice() {
+                [] => (),
+                ["section", name, address] => object.sections.push(Section {
+                    name: name.to_string(),
+                    address: match *address {
+                        "-" => None,
+                        address => Some(
+                            u16::from_str_radix(address, 16)
+                                .map_err(|_| error("invalid address"))?,
+                        ),
+                    },
+                    data: Vec::new(),
+                }),
+                ["data", hex] => {
+                    let section = object
+                        .sections
+                        .last_mut()
+                        .ok_or_else(|| error("data outside of a section"))?;
+
+                    if hex.len() % 2 != 0 {
+                        return Err(error("invalid data"));
+                    }
+                    for i in (0..hex.len()).step_by(2) {
+                        let byte = hex
+                            .get(i..i + 2)
+                            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                            .ok_or_else(|| error("invalid data"))?;
+                        section.data.push(byte);
+                    }
+                }
+                ["symbol", name, visibility, section, value] => object.symbols.push(Symbol {
+                    name: name.to_string(),
+                    section: match *section {
+                        "-" => None,
+                        section => Some(section.parse().map_err(|_| error("invalid section"))?),
+                    },
+                    value: value.parse().map_err(|_| error("invalid value"))?,
+                    global: match *visibility {
+                        "global" => true,
+                        "local" => false,
+                        _ => return Err(error("invalid symbol")),
+                    },
+                }),
+                ["reloc", section, offset, kind, target, name, addend, source_line, ..] => {
+                    // The file name is the rest of the line, spaces and all
+                    let file = line
+                        .splitn(9, char::is_whitespace)
+                        .nth(8)
+                        .map(|file| PathBuf::from(file.trim()));
+
+                    object.relocations.push(Relocation {
+                        section: section.parse().map_err(|_| error("invalid section"))?,
+                        offset: offset.parse().map_err(|_| error("invalid offset"))?,
+                        kind: match *kind {
+                            "byte" => RelocationKind::Byte,
+                            "word" => RelocationKind::Word,
+                            "address" => RelocationKind::Address,
+                            _ => return Err(error("invalid relocation kind")),
+                        },
+                        target: match *target {
+                            "section" => {
+                                Target::Section(name.parse().map_err(|_| error("invalid section"))?)
+                            }
+                            "symbol" => Target::Symbol(name.to_string()),
+                            _ => return Err(error("invalid relocation target")),
+                        },
+                        addend: addend.parse().map_err(|_| error("invalid addend"))?,
+                        file,
+                        line: source_line.parse().map_err(|_| error("invalid line"))?,
+                    });
+                }
+                ["line", section, offset, source_line, ..] => {
+                    let file = line
+                        .splitn(5, char::is_whitespace)
+                        .nth(4)
+                        .map(|file| PathBuf::from(file.trim()));
+
+                    object.lines.push(LineEntry {
+                        section: section.parse().map_err(|_| error("invalid section"))?,
+                        offset: offset.parse().map_err(|_| error("invalid offset"))?,
+                        source: SourceLine {
+                            file,
+                            line: source_line.parse().map_err(|_| error("invalid line"))?,
+                        },
+                    });
+                }
+                _ => return Err(error(&format!("invalid record `{}`", line))),
+            }
+        }
+
+        object.validate()?;
+        Ok(object)
+    }
+
+    /// Checks that every section index and relocation is in range
+    fn validate(&self) -> Result<(), String> {
+        for symbol in &self.symbols {
+            if matches!(symbol.section, Some(section) if section >= self.sections.len()) {
+                return Err(format!(
+                    "`{}` is in a section that doesn't exist",
+                    symbol.name
+                ));
+            }
+        }
+
+        for relocation in &self.relocations {
+            let fits = matches!(
+                self.sections.get(relocation.section),
+                Some(section) if relocation.offset + relocation.kind.size() <= section.data.len()
+            );
+            let target = match relocation.target {
+                Target::Section(section) => section < self.sections.len(),
+                Target::Symbol(_) => true,
+            };
+
+            if !fits || !target {
+                return Err(format!(
+                    "relocation at {}+{} is out of range",
+                    relocation.section, relocation.offset
+                ));
+            }
+        }
+
+        for entry in &self.lines {
+            if !matches!(self.sections.get(entry.section), Some(section) if entry.offset < section.data.len())
+            {
+                return Err(format!(
+                    "line at {}+{} is out of range",
+                    entry.section, entry.offset
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let object = Object {
+            sections: vec![
+                Section {
+                    name: "text".to_string(),
+                    address: None,
+                    data: (0..40).collect(),
+                },
+                Section {
+                    name: "vectors".to_string(),
+                    address: Some(0xFFFC),
+                    data: vec![0, 0],
+                },
+            ],
+            symbols: vec![
+                Symbol {
+                    name: "main".to_string(),
+                    section: Some(0),
+                    value: 4,
+                    global: true,
+                },
+                Symbol {
+                    name: "SIZE".to_string(),
+                    section: None,
+                    value: -3,
+                    global: false,
+                },
+            ],
+            relocations: vec![
+                Relocation {
+                    section: 0,
+                    offset: 5,
+                    kind: RelocationKind::Address,
+                    target: Target::Section(0),
+                    addend: 12,
+                    file: Some(PathBuf::from("my game/main.s")),
+                    line: 7,
+                },
+                Relocation {
+                    section: 1,
+                    offset: 0,
+                    kind: RelocationKind::Word,
+                    target: Target::Symbol("handler".to_string()),
+                    addend: -1,
+                    file: None,
+                    line: 2,
+                },
+            ],
+            lines: vec![
+                LineEntry {
+                    section: 0,
+                    offset: 0,
+                    source: SourceLine {
+                        file: Some(PathBuf::from("my game/main.s")),
+                        line: 6,
+                    },
+                },
+                LineEntry {
+                    section: 0,
+                    offset: 3,
+                    source: SourceLine {
+                        file: None,
+                        line: 7,
+                    },
+                },
+            ],
+        };
+
+        let mut text = Vec::new();
+        object.write(&mut text).unwrap();
+
+        assert_eq!(Object::parse(&String::from_utf8(text).unwrap()), Ok(object));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Object::parse("hello"),
+            Err("not an object file".to_string())
+        );
+        assert_eq!(
+            Object::parse("MAXOBJ 2"),
+            Err("unsupported object version `MAXOBJ 2`".to_string())
+        );
+        assert_eq!(
+            Object::parse("MAXOBJ 1\ndata 00"),
+            Err("line 2: data outside of a section".to_string())
+        );
+        assert_eq!(
+            Object::parse("MAXOBJ 1\nsection text -\ndata 00\nreloc 0 0 word section 0 0 1"),
+            Err("relocation at 0+0 is out of range".to_string())
+        );
+        assert_eq!(
+            Object::parse("MAXOBJ 1\nsection text -\ndata 00\nline 0 1 4"),
+            Err("line at 0+1 is out of range".to_string())
+        );
+    }
+}
@@ -1,65 +1,560 @@
+use crate::cpu::CLOCK_HZ;
+use crate::recorder::Recorder;
+use crate::replay::Replay;
 use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
-use std::process::exit;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+/// Where the last pressed key is written
+pub const KEY_ADDR: u16 = 0x0100;
+/// The scanline currently being drawn (read-only)
+pub const SCANLINE_ADDR: u16 = 0x0101;
+/// When the scanline reaches this value, a scanline-compare interrupt is raised (if enabled)
+pub const SCANLINE_COMPARE_ADDR: u16 = 0x0102;
+/// PPU control flags, see the `CONTROL_*` constants
+pub const CONTROL_ADDR: u16 = 0x0103;
+/// How far each scanline is scrolled to the left as it is drawn
+pub const SCROLL_X_ADDR: u16 = 0x0104;
+/// Added to every pixel value before it is turned into a colour
+pub const COLOUR_OFFSET_ADDR: u16 = 0x0105;
+/// The framebuffer page to display from the next vblank on; the other page is the one written
+pub const PAGE_ADDR: u16 = 0x0106;
+/// The framebuffer page currently being displayed (read-only)
+pub const DISPLAYED_PAGE_ADDR: u16 = 0x0107;
+/// Start of the framebuffer, one byte per pixel. With double buffering enabled, this is a window
+/// onto whichever page is currently being written.
+pub const FRAMEBUFFER_ADDR: u16 = 0x0200;
+/// How many bytes a single framebuffer page takes up
+pub const FRAMEBUFFER_SIZE: u16 = 0x0400;
+
+/// Raise an interrupt when the scanline matches `SCANLINE_COMPARE_ADDR`
+pub const CONTROL_COMPARE_INTERRUPT: u8 = 0b0000_0001;
+/// Use two framebuffer pages, flipping between them at vblank as selected by `PAGE_ADDR`
+pub const CONTROL_DOUBLE_BUFFER: u8 = 0b0000_0010;
+
+/// How many pixels across the screen is
+pub const SCREEN_WIDTH: usize = 32;
+/// How many pixels (and visible scanlines) down the screen is
+pub const SCREEN_HEIGHT: usize = 32;
+
+/// How many CPU cycles it takes to draw a single scanline
+pub const CYCLES_PER_SCANLINE: u32 = 64;
+/// How many scanlines are spent in vblank after the visible ones
+pub const VBLANK_SCANLINES: usize = 8;
+
+/// How much the window scales up the framebuffer by default
+pub const WINDOW_SCALE: usize = 16;
+/// Every scale the window supports
+pub const WINDOW_SCALES: [usize; 6] = [1, 2, 4, 8, 16, 32];
+
+/// A key pressed in the window for something the PPU can't do by itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    /// F5
+    SaveState,
+    /// F9
+    LoadState,
+}
+
+/// The parts of the PPU that change as it runs, for snapshots
+#[derive(Debug, Clone, PartialEq)]
+pub struct PpuState {
+    pub buffer: Vec<u32>,
+    pub scanline: usize,
+    pub cycles: u32,
+    pub frame: u64,
+    pub front_page: u8,
+}
 
 #[derive(Debug)]
 pub struct PPU {
     pub buffer: Vec<u32>,
-    pub window: Window,
+    /// The window frames are presented in, or `None` when running headless
+    pub window: Option<Window>,
     pub width: usize,
     pub height: usize,
+    /// The scanline currently being drawn; anything past `height` is vblank
+    pub scanline: usize,
+    /// Cycles spent so far on the current scanline
+    pub cycles: u32,
+    /// How many frames have been presented
+    pub frame: u64,
+    /// The framebuffer page being displayed, latched from `PAGE_ADDR` at every vblank
+    pub front_page: u8,
+    /// Where presented frames are being recorded to, if anywhere
+    pub recorder: Option<Recorder>,
+    /// Whether the window has been closed (or Escape pressed), meaning it's time to stop
+    pub closed: bool,
+    /// How much the window scales up the framebuffer, which screenshots and recordings taken
+    /// from the window match
+    pub scale: usize,
+    /// Where key presses come from instead of the window, if anywhere
+    pub replay: Option<Replay>,
+    /// The last hotkey pressed, until it's taken and dealt with
+    pub hotkey: Option<Hotkey>,
 }
 
 impl PPU {
     pub fn new() -> Self {
-        let width = 32;
-        let height = 32;
+        Self::with_scale(WINDOW_SCALE)
+    }
 
-        let window = Window::new(
-            "MaxEmu 2021",
-            width,
-            height,
-            WindowOptions {
-                scale: Scale::X16,
-                ..WindowOptions::default()
-            },
-        )
-        .unwrap();
+    /// Creates a PPU whose window scales up the framebuffer by `scale`, which has to be one of
+    /// `WINDOW_SCALES`
+    pub fn with_scale(scale: usize) -> Self {
+        let window_scale = match scale {
+            1 => Scale::X1,
+            2 => Scale::X2,
+            4 => Scale::X4,
+            8 => Scale::X8,
+            16 => Scale::X16,
+            32 => Scale::X32,
+            _ => panic!("Unsupported window scale: {}", scale),
+        };
+        let mut ppu = Self::headless();
+        ppu.scale = scale;
+
+        ppu.window = Some(
+            Window::new(
+                "MaxEmu 2021",
+                ppu.width,
+                ppu.height,
+                WindowOptions {
+                    scale: window_scale,
+                    ..WindowOptions::default()
+                },
+            )
+            .unwrap(),
+        );
+
+        ppu
+    }
+
+    /// Creates a PPU that renders into `buffer` without ever opening a window
+    pub fn headless() -> Self {
+        let width = SCREEN_WIDTH;
+        let height = SCREEN_HEIGHT;
 
         Self {
             buffer: vec![0; width * height],
-            window,
+            window: None,
             width,
             height,
+            scanline: 0,
+            cycles: 0,
+            frame: 0,
+            front_page: 0,
+            recorder: None,
+            closed: false,
+            scale: WINDOW_SCALE,
+            replay: None,
+            hotkey: None,
         }
     }
 
-    pub fn update_keys(&self, mut memory: [u8; 0xFFFF]) -> [u8; 0xFFFF] {
-        let keys = self.window.get_keys_pressed(KeyRepeat::No);
+    pub fn state(&self) -> PpuState {
+        PpuState {
+            buffer: self.buffer.clone(),
+            scanline: self.scanline,
+            cycles: self.cycles,
+            frame: self.frame,
+            front_page: self.front_page,
+        }
+    }
+
+    /// Puts the PPU back into `state`. The window isn't updated until `show` or the next frame.
+    pub fn restore(&mut self, state: &PpuState) {
+        self.buffer.clone_from(&state.buffer);
+        self.scanline = state.scanline;
+        self.cycles = state.cycles;
+        self.frame = state.frame;
+        self.front_page = state.front_page;
+    }
+
+    /// How many frames are presented every second, going by the CPU clock
+    pub fn frame_rate(&self) -> f64 {
+        let cycles_per_frame = CYCLES_PER_SCANLINE * (self.height + VBLANK_SCANLINES) as u32;
+
+        CLOCK_HZ as f64 / cycles_per_frame as f64
+    }
+
+    /// Where framebuffer page `page` lives in memory
+    pub fn page_address(page: u8) -> u16 {
+        FRAMEBUFFER_ADDR + page as u16 * FRAMEBUFFER_SIZE
+    }
+
+    /// Maps an address inside the framebuffer window onto the page currently being written.
+    /// Any other address is returned untouched.
+    pub fn map_address(&self, memory: &[u8; 0xFFFF], addr: u16) -> u16 {
+        let double_buffered = memory[CONTROL_ADDR as usize] & CONTROL_DOUBLE_BUFFER != 0;
+
+        if double_buffered
+            && (FRAMEBUFFER_ADDR..FRAMEBUFFER_ADDR + FRAMEBUFFER_SIZE).contains(&addr)
+        {
+            Self::page_address(self.front_page ^ 1) + (addr - FRAMEBUFFER_ADDR)
+        } else {
+            addr
+        }
+    }
+
+    /// Sets the key register from the replay if there is one, or the window otherwise
+    pub fn update_keys(&self, memory: &mut [u8; 0xFFFF]) {
+        if let Some(replay) = &self.replay {
+            replay.apply(memory, self.frame);
+            return;
+        }
+
+        let window = match &self.window {
+            Some(window) => window,
+            None => return,
+        };
+
+        let keys = window.get_keys_pressed(KeyRepeat::No);
         keys.map(|keys| {
             for t in keys {
                 match t {
-                    Key::W => memory[0x0100] = 1,
-                    Key::A => memory[0x0100] = 2,
-                    Key::S => memory[0x0100] = 3,
-                    Key::D => memory[0x0100] = 4,
-                    _ => memory[0x0100] = 0,
+                    Key::W => memory[KEY_ADDR as usize] = 1,
+                    Key::A => memory[KEY_ADDR as usize] = 2,
+                    Key::S => memory[KEY_ADDR as usize] = 3,
+                    Key::D => memory[KEY_ADDR as usize] = 4,
+                    _ => memory[KEY_ADDR as usize] = 0,
                 }
             }
         });
-        memory
     }
 
-    pub fn render(&mut self, memory: [u8; 0xFFFF]) {
-        for (i, j) in self.buffer.iter_mut().enumerate() {
-            *j = (memory[0x0200 + i] as u32).pow(4);
+    /// Advances the PPU by `cycles` CPU cycles, drawing every scanline that finishes along the
+    /// way. Returns true if a scanline-compare interrupt should be raised.
+    pub fn step(&mut self, memory: &mut [u8; 0xFFFF], cycles: u32) -> bool {
+        let mut interrupt = false;
+
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SCANLINE {
+            self.cycles -= CYCLES_PER_SCANLINE;
+
+            // A line is drawn once its time is up, so that anything the program changes while
+            // the line is current (e.g. in a scanline-compare interrupt) shows up on that line.
+            if self.scanline < self.height {
+                self.render_scanline(memory, self.scanline);
+            }
+
+            self.scanline += 1;
+            if self.scanline == self.height {
+                self.present();
+                self.update_keys(memory);
+                self.flip(memory);
+            } else if self.scanline == self.height + VBLANK_SCANLINES {
+                self.scanline = 0;
+            }
+
+            memory[SCANLINE_ADDR as usize] = self.scanline as u8;
+
+            if memory[CONTROL_ADDR as usize] & CONTROL_COMPARE_INTERRUPT != 0
+                && memory[SCANLINE_COMPARE_ADDR as usize] as usize == self.scanline
+            {
+                interrupt = true;
+            }
         }
 
-        if !self.window.is_key_down(minifb::Key::Escape) {
-            self.window
-                .update_with_buffer(&*self.buffer, self.width, self.height)
-                .unwrap();
+        interrupt
+    }
+
+    /// Latches the page to display for the next frame. Since this only happens during vblank,
+    /// a frame is never drawn from a page that is half written.
+    fn flip(&mut self, memory: &mut [u8; 0xFFFF]) {
+        self.front_page = if memory[CONTROL_ADDR as usize] & CONTROL_DOUBLE_BUFFER != 0 {
+            memory[PAGE_ADDR as usize] & 1
         } else {
-            exit(0);
+            0
+        };
+
+        memory[DISPLAYED_PAGE_ADDR as usize] = self.front_page;
+    }
+
+    /// Draws a single line of the displayed page using the current register values
+    pub fn render_scanline(&mut self, memory: &[u8; 0xFFFF], line: usize) {
+        let scroll = memory[SCROLL_X_ADDR as usize] as usize;
+        let colour_offset = memory[COLOUR_OFFSET_ADDR as usize];
+        let row = Self::page_address(self.front_page) as usize + line * self.width;
+
+        for x in 0..self.width {
+            let value = memory[row + (x + scroll) % self.width].wrapping_add(colour_offset);
+            self.buffer[line * self.width + x] = (value as u32).pow(4);
+        }
+    }
+
+    /// Shows the finished frame in the window
+    pub fn present(&mut self) {
+        self.frame += 1;
+
+        if let Some(scale) = self.recorder.as_ref().map(Recorder::scale) {
+            let rgb = self.frame_rgb(scale);
+            if let Err(e) = self.recorder.as_mut().unwrap().record(&rgb) {
+                eprintln!("Could not record frame, stopping the recording: {}", e);
+                self.recorder = None;
+            }
+        }
+
+        let window = match &mut self.window {
+            Some(window) => window,
+            None => return,
+        };
+
+        if window.is_key_down(Key::Escape) || !window.is_open() {
+            self.closed = true;
+            return;
+        }
+
+        window
+            .update_with_buffer(&*self.buffer, self.width, self.height)
+            .unwrap();
+
+        let take_screenshot = window.is_key_pressed(Key::F12, KeyRepeat::No);
+        let toggle_recording = window.is_key_pressed(Key::F10, KeyRepeat::No);
+
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            self.hotkey = Some(Hotkey::SaveState);
+        } else if window.is_key_pressed(Key::F9, KeyRepeat::No) {
+            self.hotkey = Some(Hotkey::LoadState);
+        }
+
+        if take_screenshot {
+            let path = format!("maxemu-{}.png", self.frame);
+            match self.screenshot(&path, self.scale) {
+                Ok(()) => println!("Saved screenshot to {}", path),
+                Err(e) => eprintln!("Could not save screenshot to {}: {}", path, e),
+            }
+        }
+
+        if toggle_recording {
+            if self.recorder.is_some() {
+                match self.stop_recording() {
+                    Ok(()) => println!("Stopped recording"),
+                    Err(e) => eprintln!("Could not finish the recording: {}", e),
+                }
+            } else {
+                let path = format!("maxemu-{}.gif", self.frame);
+                match self.start_recording(&path, self.scale) {
+                    Ok(()) => println!("Recording to {}", path),
+                    Err(e) => eprintln!("Could not record to {}: {}", path, e),
+                }
+            }
+        }
+    }
+
+    /// Shows the buffer in the window again without counting a new frame, e.g. once it's been
+    /// restored from a snapshot
+    pub fn show(&mut self) {
+        if let Some(window) = &mut self.window {
+            window
+                .update_with_buffer(&self.buffer, self.width, self.height)
+                .unwrap();
+        }
+    }
+
+    /// Whether the rewind key (Backspace) is held down in the window
+    pub fn rewind_held(&self) -> bool {
+        matches!(&self.window, Some(window) if window.is_key_down(Key::Backspace))
+    }
+
+    /// Starts recording every presented frame to `path`, scaled up by `scale`. See `Recorder`
+    /// for the formats available.
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P, scale: usize) -> io::Result<()> {
+        self.recorder = Some(Recorder::new(
+            path,
+            self.width,
+            self.height,
+            scale,
+            self.frame_rate(),
+        )?);
+
+        Ok(())
+    }
+
+    /// Stops recording, if we are, making sure everything ends up on disk
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the current buffer as 8-bit RGB, with every pixel scaled up to a `scale` by
+    /// `scale` square
+    pub fn frame_rgb(&self, scale: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.width * self.height * scale * scale * 3);
+
+        for y in 0..self.height * scale {
+            for x in 0..self.width * scale {
+                let pixel = self.buffer[(y / scale) * self.width + x / scale];
+                data.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+            }
         }
+
+        data
+    }
+
+    /// Saves the current buffer to a PNG file at `path`, scaled up by `scale`
+    pub fn screenshot<P: AsRef<Path>>(&self, path: P, scale: usize) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+
+        let mut encoder = png::Encoder::new(
+            file,
+            (self.width * scale) as u32,
+            (self.height * scale) as u32,
+        );
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.frame_rgb(scale))?;
+
+        Ok(())
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CPU, INTERRUPT_VECTOR};
+    use crate::golden::{assert_matches, run_frames};
+
+    /// Sets pixel `x`, `y` of the framebuffer window to `value` (using $A)
+    fn draw(x: u16, y: u16, value: u8) -> Vec<u8> {
+        let addr = FRAMEBUFFER_ADDR + y * 32 + x;
+
+        vec![0x10, 0x00, value, 0x20, (addr >> 8) as u8, addr as u8, 0x00]
+    }
+
+    /// Appends a loop that spins forever (using $D)
+    fn spin(program: &mut Vec<u8>) {
+        let addr = 0x8000 + program.len() as u16 + 3;
+
+        program.extend_from_slice(&[0x10, 0x03, 0x01, 0x40, 0x03, (addr >> 8) as u8, addr as u8]);
+    }
+
+    /// Installs `handler` at 0x9000 as the scanline-compare interrupt handler, first raised at
+    /// `scanline`
+    fn install_handler(memory: &mut [u8; 0xFFFF], handler: &[u8], scanline: u8) {
+        memory[0x9000..0x9000 + handler.len()].copy_from_slice(handler);
+        memory[INTERRUPT_VECTOR as usize] = 0x90;
+        memory[INTERRUPT_VECTOR as usize + 1] = 0x00;
+
+        memory[SCANLINE_COMPARE_ADDR as usize] = scanline;
+        memory[CONTROL_ADDR as usize] |= CONTROL_COMPARE_INTERRUPT;
+    }
+
+    #[test]
+    fn golden_diagonals() {
+        let mut program = Vec::new();
+        for i in 0..32 {
+            program.extend(draw(i, i, (i * 8) as u8));
+            program.extend(draw(31 - i, i, 0xFF - (i * 8) as u8));
+        }
+        spin(&mut program);
+
+        let cpu = run_frames(program, 3);
+
+        assert_matches("diagonals", &cpu.ppu);
+    }
+
+    #[test]
+    fn golden_split_screen_scroll() {
+        let mut cpu = CPU::headless();
+        let mut program = Vec::new();
+        spin(&mut program);
+        cpu.load(program);
+
+        for y in 0..32 {
+            cpu.memory[FRAMEBUFFER_ADDR as usize + y * 32 + 4] = 0x77;
+            cpu.memory[FRAMEBUFFER_ADDR as usize + y * 32 + 20] = 0xAB;
+        }
+
+        // Scrolls everything from scanline 16 down by 8 pixels, and resets it at scanline 0
+        let handler = [
+            0x12, 0x00, 0x01, 0x01, // 9000: Read the scanline into $A
+            0x31, 0x00, 0x10, 0x01, // 9004: Are we at scanline 16?
+            0x40, 0x01, 0x90, 0x1B, // 9008: If so, jump to 901B
+            0x10, 0x00, 0x00, // 900C: Set the scroll to 0...
+            0x20, 0x01, 0x04, 0x00, //
+            0x10, 0x00, 0x10, // 9013: ...and come back at scanline 16
+            0x20, 0x01, 0x02, 0x00, //
+            0x41, // 901A
+            0x10, 0x00, 0x08, // 901B: Set the scroll to 8...
+            0x20, 0x01, 0x04, 0x00, //
+            0x10, 0x00, 0x00, // 9022: ...and come back at scanline 0
+            0x20, 0x01, 0x02, 0x00, //
+            0x41,
+        ];
+        install_handler(&mut cpu.memory, &handler, 16);
+
+        cpu.run_frames(3);
+
+        assert_matches("split_screen_scroll", &cpu.ppu);
+    }
+
+    #[test]
+    fn golden_colour_bands() {
+        let mut cpu = CPU::headless();
+        let mut program = Vec::new();
+        spin(&mut program);
+        cpu.load(program);
+
+        for i in 0..FRAMEBUFFER_SIZE as usize {
+            cpu.memory[FRAMEBUFFER_ADDR as usize + i] = 0x40 + (i % 32) as u8;
+        }
+
+        // Shifts the colours a bit further every 4 scanlines, and resets them at vblank
+        let handler = [
+            0x12, 0x00, 0x01, 0x02, // 9000: Read the scanline compare into $A
+            0x31, 0x00, 0x20, 0x01, // 9004: Have we reached vblank?
+            0x40, 0x01, 0x90, 0x21, // 9008: If so, jump to 9021
+            0x54, 0x00, 0x04, 0x00, // 900C: Come back in 4 scanlines
+            0x20, 0x01, 0x02, 0x00, //
+            0x12, 0x00, 0x01, 0x05, // 9014: Shift the colours by 16
+            0x54, 0x00, 0x10, 0x00, //
+            0x20, 0x01, 0x05, 0x00, //
+            0x41, // 9020
+            0x10, 0x00, 0x00, // 9021: Reset the colours, and come back at scanline 0
+            0x20, 0x01, 0x02, 0x00, //
+            0x20, 0x01, 0x05, 0x00, //
+            0x41,
+        ];
+        install_handler(&mut cpu.memory, &handler, 0);
+
+        cpu.run_frames(3);
+
+        assert_matches("colour_bands", &cpu.ppu);
+    }
+
+    #[test]
+    fn golden_double_buffer() {
+        let mut cpu = CPU::headless();
+        let mut program = Vec::new();
+        spin(&mut program);
+        cpu.load(program);
+
+        // Page 0 gets horizontal stripes and page 1 vertical ones
+        for y in 0..32 {
+            for x in 0..32 {
+                let i = y * 32 + x;
+                cpu.memory[PPU::page_address(0) as usize + i] = if y % 4 == 0 { 0x77 } else { 0 };
+                cpu.memory[PPU::page_address(1) as usize + i] = if x % 4 == 0 { 0xE5 } else { 0 };
+            }
+        }
+
+        cpu.memory[CONTROL_ADDR as usize] = CONTROL_DOUBLE_BUFFER;
+        cpu.memory[PAGE_ADDR as usize] = 1;
+
+        cpu.run_frames(3);
+
+        assert_matches("double_buffer", &cpu.ppu);
     }
 }
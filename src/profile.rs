@@ -0,0 +1,375 @@
+//! An exact profiler, which counts every instruction executed and the cycles it took rather than
+//! sampling.
+//!
+//! Counts are kept per address and per opcode, and can be added up per symbol with a symbol map.
+//! Since the only way in and out of code other than jumping is an interrupt, the "stack" for the
+//! folded-stack output (as read by flamegraph tools) is just where the program was interrupted,
+//! then the interrupt handler:
+//!
+//! ```text
+//! main 1200
+//! main;draw 340
+//! loop 5000
+//! loop;draw 360
+//! ```
+
+use crate::disasm::disassemble_one;
+use crate::isa;
+use crate::symbols::SymbolMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// How many times the instruction at each address was executed
+    executions: Vec<u64>,
+    /// How many cycles were spent on the instruction at each address
+    cycles: Vec<u64>,
+    /// How many times each opcode was executed, and the cycles spent on it
+    opcodes: Vec<(u64, u64)>,
+    /// The cycles spent on each address, split by where the interrupt handler it was executed in
+    /// returns to, if it was in one
+    stacks: HashMap<(Option<u16>, u16), u64>,
+}
+
+/// The counts for one row of a report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub executions: u64,
+    pub cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            executions: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            opcodes: vec![(0, 0); 0x100],
+            stacks: HashMap::new(),
+        }
+    }
+
+    /// Counts an instruction with `opcode` at `pc`, which took `cycles`. `interrupted` is where
+    /// the interrupt handler it was in returns to, if it was in one.
+    pub fn record(&mut self, pc: u16, opcode: u8, cycles: u32, interrupted: Option<u16>) {
+        let cycles = cycles as u64;
+
+        self.executions[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+
+        let counts = &mut self.opcodes[opcode as usize];
+        counts.0 += 1;
+        counts.1 += cycles;
+
+        *self.stacks.entry((interrupted, pc)).or_default() += cycles;
+    }
+
+    /// How many times the instruction at `address` was executed
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executions[address as usize]
+    }
+
+    /// How many cycles were spent on the instruction at `address`
+    pub fn cycles(&self, address: u16) -> u64 {
+        self.cycles[address as usize]
+    }
+
+    pub fn total_executions(&self) -> u64 {
+        self.executions.iter().sum()
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.cycles.iter().sum()
+    }
+
+    /// Every address that was executed, most cycles first
+    pub fn by_address(&self) -> Vec<Entry> {
+        let entries = (0..=0xFFFF)
+            .filter(|&address| self.executions(address) > 0)
+            .map(|address| Entry {
+                name: format!("{:04X}", address),
+                executions: self.executions(address),
+                cycles: self.cycles(address),
+            })
+            .collect();
+
+        sorted(entries)
+    }
+
+    /// Every opcode that was executed, most cycles first
+    pub fn by_opcode(&self) -> Vec<Entry> {
+        let entries = self
+            .opcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, &(executions, _))| executions > 0)
+            .map(|(opcode, &(executions, cycles))| Entry {
+                name: match isa::lookup(opcode as u8) {
+                    Some(info) => format!("{:02X} {}", opcode, info.syntax()),
+                    None => format!("{:02X}", opcode),
+                },
+                executions,
+                cycles,
+            })
+            .collect();
+
+        sorted(entries)
+    }
+
+    /// The counts added up for each symbol, over the addresses from it up to the next one, most
+    /// cycles first. Anything before the first symbol is counted under `?`.
+    pub fn by_symbol(&self, symbols: &SymbolMap) -> Vec<Entry> {
+        let mut totals = BTreeMap::<String, (u64, u64)>::new();
+        for address in (0..=0xFFFF).filter(|&address| self.executions(address) > 0) {
+            let total = totals.entry(frame(symbols, address, true)).or_default();
+            total.0 += self.executions(address);
+            total.1 += self.cycles(address);
+        }
+
+        let entries = totals
+            .into_iter()
+            .map(|(name, (executions, cycles))| Entry {
+                name,
+                executions,
+                cycles,
+            })
+            .collect();
+
+        sorted(entries)
+    }
+
+    /// A report of where the time went: by symbol (if there are any), by address, with each
+    /// instruction disassembled from `memory`, and by opcode
+    pub fn report(&self, memory: &[u8], symbols: &SymbolMap) -> String {
+        let total = self.total_cycles();
+        let mut report = format!(
+            "{} instructions executed in {} cycles\n",
+            self.total_executions(),
+            total
+        );
+
+        let mut table = |title: &str, heading: &str, entries: Vec<Entry>| {
+            write!(
+                report,
+                "\n{}:\n{:>12} {:>7} {:>12}  {}\n",
+                title, "Cycles", "%", "Executions", heading
+            )
+            .unwrap();
+
+            for entry in entries {
+                let percent = match total {
+                    0 => 0.0,
+                    total => entry.cycles as f64 * 100.0 / total as f64,
+                };
+                writeln!(
+                    report,
+                    "{:>12} {:>6.2}% {:>12}  {}",
+                    entry.cycles, percent, entry.executions, entry.name
+                )
+                .unwrap();
+            }
+        };
+
+        if !symbols.is_empty() {
+            table("By symbol", "Symbol", self.by_symbol(symbols));
+        }
+
+        let instructions = self
+            .by_address()
+            .into_iter()
+            .map(|entry| {
+                let address = u16::from_str_radix(&entry.name, 16).unwrap();
+                let mut name = match memory.get(address as usize..) {
+                    Some(bytes) if !bytes.is_empty() => {
+                        format!("{:<36}", disassemble_one(bytes, address).to_string())
+                    }
+                    _ => entry.name,
+                };
+                if !symbols.is_empty() {
+                    name = format!("{} ; {}", name, frame(symbols, address, false));
+                }
+
+                Entry {
+                    name: name.trim_end().to_string(),
+                    ..entry
+                }
+            })
+            .collect();
+        table("By address", "Instruction", instructions);
+
+        table("By opcode", "Opcode", self.by_opcode());
+
+        report
+    }
+
+    /// Writes the cycles spent in each stack of symbols (or addresses, without any), as lines of
+    /// `outer;inner cycles` for flamegraph tools
+    pub fn write_folded<W: Write>(&self, mut out: W, symbols: &SymbolMap) -> io::Result<()> {
+        let mut stacks = BTreeMap::<String, u64>::new();
+        for (&(interrupted, pc), &cycles) in &self.stacks {
+            let stack = match interrupted {
+                Some(address) => format!(
+                    "{};{}",
+                    folded_frame(symbols, address),
+                    folded_frame(symbols, pc)
+                ),
+                None => folded_frame(symbols, pc),
+            };
+            *stacks.entry(stack).or_default() += cycles;
+        }
+
+        for (stack, cycles) in stacks {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sorts `entries` by cycles, then executions, most first, and then by name
+fn sorted(mut entries: Vec<Entry>) -> Vec<Entry> {
+    entries.sort_by(|a, b| {
+        (b.cycles, b.executions)
+            .cmp(&(a.cycles, a.executions))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    entries
+}
+
+/// The symbol `address` is in, or `?` if it's before all of them. With `whole` false, how far
+/// into the symbol it is gets added on, e.g. `loop+0x3`.
+fn frame(symbols: &SymbolMap, address: u16, whole: bool) -> String {
+    let name = if whole {
+        symbols.lookup(address).map(|(name, _)| name.to_string())
+    } else {
+        symbols.describe(address)
+    };
+
+    name.unwrap_or_else(|| "?".to_string())
+}
+
+/// A frame in a folded stack: the symbol `address` is in, or the address itself without a map
+fn folded_frame(symbols: &SymbolMap, address: u16) -> String {
+    if symbols.is_empty() {
+        format!("{:04X}", address)
+    } else {
+        frame(symbols, address, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+
+    /// Counts down from 3
+    const PROGRAM: &str = "\
+LOAD A, 3
+LOAD C, 1
+loop: DEC A
+EQ A, 0, B
+JT B, done
+JT C, loop
+done: HALT";
+
+    fn profiled() -> CPU {
+        let mut cpu = CPU::headless();
+        cpu.load(assemble(PROGRAM).unwrap());
+        cpu.profiler = Some(Profiler::new());
+        cpu.run();
+        cpu
+    }
+
+    #[test]
+    fn test_counts() {
+        let cpu = profiled();
+        let profiler = cpu.profiler.as_ref().unwrap();
+
+        // `DEC A` is 2 cycles, and runs once each time round the loop
+        assert_eq!(profiler.executions(0x8006), 3);
+        assert_eq!(profiler.cycles(0x8006), 6);
+        assert_eq!(profiler.total_executions(), cpu.instructions);
+        assert_eq!(profiler.total_cycles(), cpu.cycles);
+
+        let opcodes = profiler.by_opcode();
+        assert_eq!(
+            opcodes[0],
+            Entry {
+                name: "40 JT $A, 0xB".to_string(),
+                executions: 5,
+                cycles: 20,
+            }
+        );
+        assert!(opcodes.contains(&Entry {
+            name: "00 HALT".to_string(),
+            executions: 1,
+            cycles: 0,
+        }));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let cpu = profiled();
+        let profiler = cpu.profiler.as_ref().unwrap();
+
+        let mut symbols = SymbolMap::new();
+        symbols.insert("loop", 0x8006);
+        symbols.insert("done", 0x8014);
+
+        let by_symbol = profiler.by_symbol(&symbols);
+        let names = by_symbol
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["loop", "?", "done"]);
+        assert_eq!(
+            by_symbol.iter().map(|entry| entry.cycles).sum::<u64>(),
+            cpu.cycles
+        );
+
+        let report = profiler.report(&cpu.memory, &symbols);
+        assert!(report.starts_with(&format!(
+            "{} instructions executed in {} cycles\n\nBy symbol:\n",
+            cpu.instructions, cpu.cycles
+        )));
+        assert!(report.contains("\n          38  86.36%           11  loop\n"));
+        assert!(report.contains("\n           6  13.64%            3  8006: 51 00        DEC A "));
+        assert!(report.contains("  8008: 31 00 00 01  EQ A, 0x00, B     ; loop+0x2\n"));
+        assert!(report.contains("\n           0   0.00%            1  00 HALT\n"));
+    }
+
+    #[test]
+    fn test_folded() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x8000, 0x10, 3, None);
+        profiler.record(0x8004, 0x10, 3, None);
+        profiler.record(0x9000, 0x41, 4, Some(0x8004));
+        profiler.record(0x9000, 0x41, 4, Some(0x8000));
+
+        let folded = |symbols: &SymbolMap| {
+            let mut out = Vec::new();
+            profiler.write_folded(&mut out, symbols).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            folded(&SymbolMap::new()),
+            "8000 3\n8000;9000 4\n8004 3\n8004;9000 4\n"
+        );
+
+        let mut symbols = SymbolMap::new();
+        symbols.insert("main", 0x8000);
+        symbols.insert("handler", 0x9000);
+        assert_eq!(folded(&symbols), "main 6\nmain;handler 8\n");
+    }
+}
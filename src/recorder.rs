@@ -0,0 +1,162 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// How hard to work on reducing a GIF frame to 256 colours, from 1 (hardest) to 30. The PPU
+/// never shows more than 256, so this only comes up if that changes.
+const GIF_QUANTIZE_SPEED: i32 = 10;
+
+/// Records presented frames to a file, either as an animated GIF or as raw RGB frames
+pub struct Recorder {
+    output: Output,
+    width: usize,
+    height: usize,
+    scale: usize,
+    frame_rate: f64,
+    /// How many frames have been recorded so far
+    pub frames: u64,
+}
+
+enum Output {
+    Gif(gif::Encoder<BufWriter<File>>),
+    /// 8-bit RGB frames one after the other, which ffmpeg can read with
+    /// `-f rawvideo -pix_fmt rgb24 -s <width>x<height> -r <frame rate>`
+    Raw(BufWriter<File>),
+}
+
+impl Recorder {
+    /// Starts recording to `path`. Files ending in `.gif` become an animated GIF, anything else
+    /// gets raw frames. Frames are `width` by `height` pixels before being scaled up by `scale`,
+    /// and are shown at `frame_rate` frames per second.
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+        scale: usize,
+        frame_rate: f64,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = BufWriter::new(File::create(path)?);
+
+        let output = if path.extension().is_some_and(|ext| ext == "gif") {
+            let mut encoder =
+                gif::Encoder::new(file, (width * scale) as u16, (height * scale) as u16, &[])
+                    .map_err(gif_error)?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(gif_error)?;
+
+            Output::Gif(encoder)
+        } else {
+            Output::Raw(file)
+        };
+
+        Ok(Self {
+            output,
+            width,
+            height,
+            scale,
+            frame_rate,
+            frames: 0,
+        })
+    }
+
+    /// How much frames are scaled up by
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Adds a frame, given as 8-bit RGB at the recording's scale
+    pub fn record(&mut self, rgb: &[u8]) -> io::Result<()> {
+        match &mut self.output {
+            Output::Gif(encoder) => {
+                let mut frame = gif::Frame::from_rgb_speed(
+                    (self.width * self.scale) as u16,
+                    (self.height * self.scale) as u16,
+                    rgb,
+                    GIF_QUANTIZE_SPEED,
+                );
+
+                // GIF delays are in hundredths of a second, which rarely divides evenly into
+                // the frame rate. We round the time each frame *ends* at rather than the length
+                // of each frame, so the rounding errors don't pile up over a long recording.
+                let start = (self.frames as f64 * 100.0 / self.frame_rate).round();
+                let end = ((self.frames + 1) as f64 * 100.0 / self.frame_rate).round();
+                frame.delay = (end - start) as u16;
+
+                encoder.write_frame(&frame).map_err(gif_error)?;
+            }
+            Output::Raw(file) => file.write_all(rgb)?,
+        }
+
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    /// Flushes everything recorded so far and closes the file
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self.output {
+            // Taking the file back out of the encoder writes the GIF trailer
+            Output::Gif(encoder) => encoder.into_inner()?,
+            Output::Raw(file) => file,
+        };
+
+        file.flush()
+    }
+}
+
+/// Turns GIF encoding errors into I/O errors, which is what they almost always are anyway
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    match e {
+        gif::EncodingError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self.output {
+            Output::Gif(_) => "gif",
+            Output::Raw(_) => "raw",
+        };
+
+        f.debug_struct("Recorder")
+            .field("format", &format)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("scale", &self.scale)
+            .field("frame_rate", &self.frame_rate)
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_gif() {
+        let path = env::temp_dir().join(format!("maxemu-recording-{}.gif", std::process::id()));
+
+        let mut recorder = Recorder::new(&path, 2, 1, 2, 60.0).unwrap();
+        for frame in 0..3 {
+            let rgb = (0..4 * 2)
+                .flat_map(|x| [x * 16 + frame; 3])
+                .collect::<Vec<_>>();
+            recorder.record(&rgb).unwrap();
+        }
+        assert_eq!(recorder.frames, 3);
+        recorder.finish().unwrap();
+
+        let gif = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(gif.starts_with(b"GIF89a"));
+        assert_eq!(gif.last(), Some(&0x3B), "the trailer is missing");
+    }
+}
@@ -0,0 +1,133 @@
+//! Input replays, which press keys at set frames instead of taking them from the window, so a run
+//! can be repeated exactly.
+//!
+//! A replay is a text file with one change of key per line, and `;` comments:
+//!
+//! ```text
+//! 0   -     ; nothing pressed to begin with
+//! 60  d     ; hold right from frame 60...
+//! 90  -     ; ...until frame 90
+//! 120 0x01  ; keys can also be given as the value for the key register
+//! ```
+//!
+//! Keys are `w`, `a`, `s` and `d`, or `-` for none. Each one is held until the next line.
+
+use crate::cpu::MEMORY_SIZE;
+use crate::ppu::KEY_ADDR;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Replay {
+    /// The frame each key is pressed at, and the value for the key register, sorted by frame
+    events: Vec<(u64, u8)>,
+}
+
+impl Replay {
+    /// Reads a replay in the format described above
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut replay = Self::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (frame, key) = match fields.as_slice() {
+                [frame, key] => (frame, key),
+                _ => return Err(error(format!("expected a frame and a key, not `{}`", line))),
+            };
+
+            let frame = frame
+                .parse()
+                .map_err(|_| error(format!("invalid frame `{}`", frame)))?;
+            let key = parse_key(key).ok_or_else(|| error(format!("invalid key `{}`", key)))?;
+
+            if matches!(replay.events.last(), Some(&(last, _)) if last > frame) {
+                return Err(error(format!(
+                    "frame {} comes before the line above it",
+                    frame
+                )));
+            }
+            replay.events.push((frame, key));
+        }
+
+        Ok(replay)
+    }
+
+    /// The value of the key register during `frame`
+    pub fn key(&self, frame: u64) -> u8 {
+        let index = self.events.partition_point(|&(start, _)| start <= frame);
+
+        self.events[..index].last().map_or(0, |&(_, key)| key)
+    }
+
+    /// Holds `key` from `frame` on. `frame` can't come before any frame already in the replay.
+    pub fn push(&mut self, frame: u64, key: u8) {
+        debug_assert!(!matches!(self.events.last(), Some(&(last, _)) if last > frame));
+
+        if self.key(frame) != key {
+            self.events.push((frame, key));
+        }
+    }
+
+    /// Forgets every key pressed after `frame`
+    pub fn truncate(&mut self, frame: u64) {
+        let index = self.events.partition_point(|&(start, _)| start <= frame);
+        self.events.truncate(index);
+    }
+
+    /// Sets the key register to what's held during `frame`
+    pub fn apply(&self, memory: &mut [u8; MEMORY_SIZE], frame: u64) {
+        memory[KEY_ADDR as usize] = self.key(frame);
+    }
+}
+
+/// Parses a key name, or a value for the key register in decimal or hex
+fn parse_key(text: &str) -> Option<u8> {
+    match text.to_ascii_lowercase().as_str() {
+        "-" => Some(0),
+        "w" => Some(1),
+        "a" => Some(2),
+        "s" => Some(3),
+        "d" => Some(4),
+        text => match text.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16).ok(),
+            None => text.parse().ok(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let replay = Replay::parse("; a comment\n2 d\n\n5 -  ; let go\n5 w\n8 0x03\n").unwrap();
+
+        let keys = (0..10).map(|frame| replay.key(frame)).collect::<Vec<_>>();
+        assert_eq!(keys, [0, 0, 4, 4, 4, 1, 1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Replay::parse("1 w\n2"),
+            Err("line 2: expected a frame and a key, not `2`".to_string())
+        );
+        assert_eq!(
+            Replay::parse("x w"),
+            Err("line 1: invalid frame `x`".to_string())
+        );
+        assert_eq!(
+            Replay::parse("1 up"),
+            Err("line 1: invalid key `up`".to_string())
+        );
+        assert_eq!(
+            Replay::parse("5 w\n3 a"),
+            Err("line 2: frame 3 comes before the line above it".to_string())
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! Rewinding to an earlier point in a run.
+//!
+//! A `Rewinder` keeps a snapshot of the machine every few frames, and a log of the key register
+//! in every frame since the oldest one. Any instruction after the oldest snapshot can then be
+//! reached by restoring the snapshot before it and running forward again with the same keys,
+//! which ends up in exactly the same state since nothing else feeds into the machine.
+
+use crate::cpu::CPU;
+use crate::ppu::KEY_ADDR;
+use crate::replay::Replay;
+use crate::snapshot::Snapshot;
+use std::collections::{BTreeSet, VecDeque};
+use std::mem;
+
+/// How many frames apart snapshots are taken by default
+pub const SNAPSHOT_INTERVAL: u64 = 1;
+/// How many snapshots are kept by default, which is 10 seconds' worth at the default interval
+pub const SNAPSHOT_CAPACITY: usize = 600;
+
+#[derive(Debug)]
+pub struct Rewinder {
+    /// How many frames apart snapshots are taken
+    pub interval: u64,
+    /// The most snapshots kept; the oldest ones are dropped to make room for new ones
+    pub capacity: usize,
+    /// Sorted from oldest to newest
+    snapshots: VecDeque<Snapshot>,
+    /// The key register in every frame, as it was once the PPU updated it
+    inputs: Replay,
+    /// The last frame the key register was logged for
+    logged_frame: Option<u64>,
+}
+
+impl Rewinder {
+    pub fn new() -> Self {
+        Self {
+            interval: SNAPSHOT_INTERVAL,
+            capacity: SNAPSHOT_CAPACITY,
+            snapshots: VecDeque::new(),
+            inputs: Replay::default(),
+            logged_frame: None,
+        }
+    }
+
+    /// Keeps track of `cpu` after it's been started or stepped, logging its keys and taking a
+    /// snapshot when one is due. It has to be called after every step for the log to be right.
+    pub fn record(&mut self, cpu: &CPU) {
+        let frame = cpu.ppu.frame;
+        if self.logged_frame != Some(frame) {
+            self.inputs.push(frame, cpu.memory[KEY_ADDR as usize]);
+            self.logged_frame = Some(frame);
+        }
+
+        let due = match self.snapshots.back() {
+            Some(newest) => frame >= newest.ppu.frame + self.interval,
+            None => true,
+        };
+        if due {
+            self.push(cpu.snapshot());
+        }
+    }
+
+    /// Takes a snapshot of `cpu` straight away, replacing any from this instruction on. This is
+    /// needed when the machine has been changed from outside, e.g. by a debugger, since running
+    /// forward from an older snapshot wouldn't make the same change.
+    pub fn checkpoint(&mut self, cpu: &CPU) {
+        while matches!(self.snapshots.back(), Some(newest) if newest.instructions >= cpu.instructions)
+        {
+            self.snapshots.pop_back();
+        }
+
+        self.push(cpu.snapshot());
+    }
+
+    fn push(&mut self, snapshot: Snapshot) {
+        self.snapshots.push_back(snapshot);
+
+        while self.snapshots.len() > self.capacity.max(1) {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// How many instructions in the oldest point that can be rewound to is
+    pub fn oldest(&self) -> Option<u64> {
+        self.snapshots.front().map(|oldest| oldest.instructions)
+    }
+
+    /// Restores the newest snapshot from before the current instruction, forgetting any newer
+    /// ones. Returns false if there isn't one.
+    pub fn back(&mut self, cpu: &mut CPU) -> bool {
+        while let Some(newest) = self.snapshots.back() {
+            if newest.instructions < cpu.instructions {
+                cpu.restore(newest);
+                self.forget_future(cpu);
+                return true;
+            }
+
+            self.snapshots.pop_back();
+        }
+
+        false
+    }
+
+    /// Goes back `count` instructions
+    pub fn step_back(&mut self, cpu: &mut CPU, count: u64) -> Result<(), String> {
+        let target = cpu
+            .instructions
+            .checked_sub(count)
+            .ok_or_else(|| "that's before the program started".to_string())?;
+
+        self.go_to(cpu, target)
+    }
+
+    /// Goes back to the last time execution reached one of `breakpoints`, before the current
+    /// instruction. Returns false, having gone back as far as possible, if it never did.
+    pub fn reverse_continue(
+        &mut self,
+        cpu: &mut CPU,
+        breakpoints: &BTreeSet<u16>,
+    ) -> Result<bool, String> {
+        // Search between each snapshot and the one after it, newest first
+        let mut end = cpu.instructions;
+        for index in (0..self.snapshots.len()).rev() {
+            let start = self.snapshots[index].instructions;
+            if start >= end {
+                continue;
+            }
+
+            cpu.restore(&self.snapshots[index]);
+            let mut last_hit = None;
+            self.run_again(cpu, |cpu| {
+                if breakpoints.contains(&cpu.pc) && cpu.instructions < end {
+                    last_hit = Some(cpu.instructions);
+                }
+                cpu.instructions >= end
+            });
+
+            if let Some(target) = last_hit {
+                self.go_to(cpu, target)?;
+                return Ok(true);
+            }
+            end = start;
+        }
+
+        let oldest = self
+            .oldest()
+            .ok_or_else(|| "there's no history to go back through".to_string())?;
+        self.go_to(cpu, oldest)?;
+
+        Ok(false)
+    }
+
+    /// Goes to just before instruction number `target` is executed
+    pub fn go_to(&mut self, cpu: &mut CPU, target: u64) -> Result<(), String> {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.instructions <= target)
+            .ok_or_else(|| match self.oldest() {
+                Some(oldest) => format!(
+                    "can't go back that far; the oldest snapshot is {} instructions in",
+                    oldest
+                ),
+                None => "there's no history to go back through".to_string(),
+            })?;
+
+        cpu.restore(snapshot);
+        self.run_again(cpu, |cpu| cpu.instructions >= target);
+        self.forget_future(cpu);
+
+        Ok(())
+    }
+
+    /// Runs `cpu` forward from a restored snapshot until `done` says to stop, with the keys from
+    /// the log. Everything outside the machine is set aside while it runs, so that the window
+    /// isn't touched and nothing is traced, profiled, covered, recorded, played or watched a
+    /// second time.
+    fn run_again<F: FnMut(&CPU) -> bool>(&self, cpu: &mut CPU, mut done: F) {
+        let window = cpu.ppu.window.take();
+        let recorder = cpu.ppu.recorder.take();
+        let replay = cpu.ppu.replay.replace(self.inputs.clone());
+        let tracer = cpu.tracer.take();
+        let profiler = cpu.profiler.take();
+        let coverage = cpu.coverage.take();
+        let sink = cpu.apu.sink.take();
+        let samples = mem::take(&mut cpu.apu.samples);
+        let watchpoints = mem::take(&mut cpu.watchpoints);
+
+        while !done(cpu) && cpu.step() {}
+
+        cpu.ppu.window = window;
+        cpu.ppu.recorder = recorder;
+        cpu.ppu.replay = replay;
+        cpu.tracer = tracer;
+        cpu.profiler = profiler;
+        cpu.coverage = coverage;
+        cpu.apu.sink = sink;
+        cpu.apu.samples = samples;
+        cpu.watchpoints = watchpoints;
+    }
+
+    /// Forgets the snapshots and keys after where `cpu` is now, since running on from here might
+    /// not go the same way
+    fn forget_future(&mut self, cpu: &CPU) {
+        while matches!(self.snapshots.back(), Some(newest) if newest.instructions > cpu.instructions)
+        {
+            self.snapshots.pop_back();
+        }
+
+        self.inputs.truncate(cpu.ppu.frame);
+        self.logged_frame = Some(cpu.ppu.frame);
+    }
+}
+
+impl Default for Rewinder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    /// Counts up in B forever, and copies the key register into the framebuffer
+    const PROGRAM: &str = "\
+LOAD A, 1
+loop: ADD B, 1, B
+STORE [0x0200], B
+LOAD C, [0x0100]
+STORE [0x0201], C
+JT A, loop";
+
+    /// A CPU running `PROGRAM` with keys pressed every few frames, and a rewinder keeping track
+    /// of it
+    fn machine() -> (CPU, Rewinder) {
+        let mut cpu = CPU::headless();
+        cpu.load(assemble(PROGRAM).unwrap());
+        cpu.start_replay(Replay::parse("2 d\n3 -\n5 w\n9 s").unwrap());
+
+        let mut rewinder = Rewinder::new();
+        rewinder.record(&cpu);
+
+        (cpu, rewinder)
+    }
+
+    fn run(cpu: &mut CPU, rewinder: &mut Rewinder, instructions: u64) {
+        while cpu.instructions < instructions {
+            assert!(cpu.step());
+            rewinder.record(cpu);
+        }
+    }
+
+    #[test]
+    fn test_step_back() {
+        let (mut cpu, mut rewinder) = machine();
+
+        run(&mut cpu, &mut rewinder, 500);
+        let early = cpu.snapshot();
+        run(&mut cpu, &mut rewinder, 9000);
+        let late = cpu.snapshot();
+        run(&mut cpu, &mut rewinder, 9001);
+        assert!(cpu.ppu.frame > 10);
+
+        // Going back has to work out the keys from the log, not the replay
+        cpu.ppu.replay = None;
+
+        rewinder.step_back(&mut cpu, 1).unwrap();
+        assert_eq!(cpu.snapshot(), late);
+        rewinder.step_back(&mut cpu, 8500).unwrap();
+        assert_eq!(cpu.snapshot(), early);
+
+        assert_eq!(
+            rewinder.step_back(&mut cpu, 501),
+            Err("that's before the program started".to_string())
+        );
+    }
+
+    #[test]
+    fn test_capacity() {
+        let (mut cpu, mut rewinder) = machine();
+        rewinder.capacity = 3;
+
+        run(&mut cpu, &mut rewinder, 2000);
+        let oldest = rewinder.oldest().unwrap();
+        assert!(oldest > 0);
+
+        assert_eq!(
+            rewinder.go_to(&mut cpu, oldest - 1),
+            Err(format!(
+                "can't go back that far; the oldest snapshot is {} instructions in",
+                oldest
+            ))
+        );
+        assert_eq!(cpu.instructions, 2000);
+
+        rewinder.go_to(&mut cpu, oldest).unwrap();
+        assert_eq!(cpu.instructions, oldest);
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let (mut cpu, mut rewinder) = machine();
+        let breakpoints = [0x8003].iter().copied().collect();
+
+        run(&mut cpu, &mut rewinder, 2002);
+        let b = cpu.registers[1];
+
+        // Every time round the loop goes back one iteration, to just before B is added to
+        assert_eq!(rewinder.reverse_continue(&mut cpu, &breakpoints), Ok(true));
+        assert_eq!((cpu.pc, cpu.registers[1]), (0x8003, b.wrapping_sub(1)));
+        assert_eq!(rewinder.reverse_continue(&mut cpu, &breakpoints), Ok(true));
+        assert_eq!((cpu.pc, cpu.registers[1]), (0x8003, b.wrapping_sub(2)));
+
+        // Without any breakpoints, it goes all the way back
+        assert_eq!(
+            rewinder.reverse_continue(&mut cpu, &BTreeSet::new()),
+            Ok(false)
+        );
+        assert_eq!((cpu.pc, cpu.instructions), (0x8000, 0));
+    }
+
+    #[test]
+    fn test_back() {
+        let (mut cpu, mut rewinder) = machine();
+
+        run(&mut cpu, &mut rewinder, 1000);
+        let frame = cpu.ppu.frame;
+
+        // Each snapshot is a frame apart
+        assert!(rewinder.back(&mut cpu));
+        assert_eq!(cpu.ppu.frame, frame);
+        assert!(rewinder.back(&mut cpu));
+        assert_eq!(cpu.ppu.frame, frame - 1);
+
+        while rewinder.back(&mut cpu) {}
+        assert_eq!(cpu.instructions, 0);
+    }
+}
@@ -0,0 +1,318 @@
+//! The ROM file format, which is how programs are stored on disk.
+//!
+//! A ROM is a 16-byte header followed by the program. Every field is Big Endian:
+//!
+//! | Offset | Size | Field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | The magic number, `MXRM`                           |
+//! | 4      | 2    | The format version, currently 1                    |
+//! | 6      | 2    | The entry point, where execution starts            |
+//! | 8      | 2    | The load address, where the program goes in memory |
+//! | 10     | 2    | The size of the program in bytes                   |
+//! | 12     | 4    | The CRC-32 of the program                          |
+
+use crate::asm::ORIGIN;
+use crate::cpu::MEMORY_SIZE;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"MXRM";
+pub const VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rom {
+    /// Where execution starts
+    pub entry: u16,
+    /// Where the program is loaded in memory
+    pub load_address: u16,
+    pub program: Vec<u8>,
+}
+
+/// Why a ROM couldn't be loaded
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    /// The file is shorter than a header
+    TooShort(usize),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    /// The header says the program is `expected` bytes, but there are `actual`
+    SizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The program runs past the end of memory
+    DoesNotFit {
+        load_address: u16,
+        size: usize,
+    },
+    /// The entry point isn't inside the program
+    EntryOutside {
+        entry: u16,
+        load_address: u16,
+        size: usize,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::TooShort(size) => write!(
+                f,
+                "File is too short to be a ROM: {} bytes, but the header alone is {}",
+                size, HEADER_SIZE
+            ),
+            RomError::BadMagic(magic) => write!(
+                f,
+                "Not a ROM: it starts with {:02X?} rather than {:02X?}",
+                magic, MAGIC
+            ),
+            RomError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported ROM version {} (only version {} is supported)",
+                version, VERSION
+            ),
+            RomError::SizeMismatch { expected, actual } => write!(
+                f,
+                "The header says the program is {} bytes, but there are {}",
+                expected, actual
+            ),
+            RomError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: the header says {:#010x}, but the program's is {:#010x}; the \
+                 ROM is probably corrupt",
+                expected, actual
+            ),
+            RomError::DoesNotFit { load_address, size } => write!(
+                f,
+                "A {}-byte program loaded at {:#06x} doesn't fit in memory",
+                size, load_address
+            ),
+            RomError::EntryOutside {
+                entry,
+                load_address,
+                size,
+            } => write!(
+                f,
+                "The entry point {:#06x} is outside the program, which is loaded at {:#06x}-{:#06x}",
+                entry,
+                load_address,
+                *load_address as usize + size
+            ),
+        }
+    }
+}
+
+impl Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+impl Rom {
+    /// Makes a ROM of a program that is loaded at, and starts at, `ORIGIN`
+    pub fn new(program: Vec<u8>) -> Self {
+        Self {
+            entry: ORIGIN,
+            load_address: ORIGIN,
+            program,
+        }
+    }
+
+    /// Reads and validates the ROM at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, RomError> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Validates and reads a ROM from its bytes
+    pub fn parse(bytes: &[u8]) -> Result<Self, RomError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(RomError::TooShort(bytes.len()));
+        }
+
+        let (header, program) = bytes.split_at(HEADER_SIZE);
+        let u16_at = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+
+        let mut magic = [0; 4];
+        magic.copy_from_slice(&header[0..4]);
+        if &magic != MAGIC {
+            return Err(RomError::BadMagic(magic));
+        }
+
+        let version = u16_at(4);
+        if version != VERSION {
+            return Err(RomError::UnsupportedVersion(version));
+        }
+
+        let size = u16_at(10) as usize;
+        if size != program.len() {
+            return Err(RomError::SizeMismatch {
+                expected: size,
+                actual: program.len(),
+            });
+        }
+
+        let expected = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+        let actual = crc32(program);
+        if expected != actual {
+            return Err(RomError::ChecksumMismatch { expected, actual });
+        }
+
+        let rom = Self {
+            entry: u16_at(6),
+            load_address: u16_at(8),
+            program: program.to_vec(),
+        };
+        rom.validate()?;
+
+        Ok(rom)
+    }
+
+    /// Checks that the program fits in memory and that the entry point is inside it
+    pub fn validate(&self) -> Result<(), RomError> {
+        let start = self.load_address as usize;
+        let size = self.program.len();
+
+        if start + size > MEMORY_SIZE {
+            return Err(RomError::DoesNotFit {
+                load_address: self.load_address,
+                size,
+            });
+        }
+
+        if !(start..start + size).contains(&(self.entry as usize)) {
+            return Err(RomError::EntryOutside {
+                entry: self.entry,
+                load_address: self.load_address,
+                size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The ROM as it's stored on disk, header and all
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RomError> {
+        self.validate()?;
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.program.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&self.entry.to_be_bytes());
+        bytes.extend_from_slice(&self.load_address.to_be_bytes());
+        bytes.extend_from_slice(&(self.program.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&crc32(&self.program).to_be_bytes());
+        bytes.extend_from_slice(&self.program);
+
+        Ok(bytes)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), RomError> {
+        fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+}
+
+/// The CRC-32 (as used by zip and PNG) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rom = Rom {
+            entry: 0x8002,
+            load_address: 0x8000,
+            program: vec![0xFF, 0xFF, 0x50, 0x00, 0x00],
+        };
+        let bytes = rom.to_bytes().unwrap();
+
+        assert_eq!(
+            &bytes[..HEADER_SIZE - 4],
+            b"MXRM\x00\x01\x80\x02\x80\x00\x00\x05"
+        );
+        assert_eq!(bytes.len(), HEADER_SIZE + 5);
+        assert_eq!(Rom::parse(&bytes).unwrap(), rom);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = Rom::new(vec![0x00]).to_bytes().unwrap();
+        let error = |bytes: &[u8]| Rom::parse(bytes).unwrap_err().to_string();
+
+        assert_eq!(
+            error(&bytes[..10]),
+            "File is too short to be a ROM: 10 bytes, but the header alone is 16"
+        );
+        assert_eq!(
+            error(b"PK\x03\x04 not a rom at all"),
+            "Not a ROM: it starts with [50, 4B, 03, 04] rather than [4D, 58, 52, 4D]"
+        );
+
+        let mut version = bytes.clone();
+        version[5] = 2;
+        assert_eq!(
+            error(&version),
+            "Unsupported ROM version 2 (only version 1 is supported)"
+        );
+
+        assert_eq!(
+            error(&bytes[..HEADER_SIZE]),
+            "The header says the program is 1 bytes, but there are 0"
+        );
+
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_SIZE] = 0xFF;
+        assert!(error(&corrupt).starts_with("Checksum mismatch"));
+
+        let too_big = Rom {
+            entry: 0xFFF0,
+            load_address: 0xFFF0,
+            program: vec![0; 0x20],
+        };
+        assert_eq!(
+            too_big.to_bytes().unwrap_err().to_string(),
+            "A 32-byte program loaded at 0xfff0 doesn't fit in memory"
+        );
+
+        let outside = Rom {
+            entry: 0x9000,
+            ..Rom::new(vec![0x00])
+        };
+        assert_eq!(
+            outside.to_bytes().unwrap_err().to_string(),
+            "The entry point 0x9000 is outside the program, which is loaded at 0x8000-0x8001"
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Snapshots of the machine, taken with `CPU::snapshot` and put back with `CPU::restore`, and the
+//! save state format they're stored on disk in.
+//!
+//! A save state is a 10-byte header followed by the machine's state. Every field is Big Endian:
+//!
+//! | Size      | Field                                                        |
+//! |-----------|--------------------------------------------------------------|
+//! | 4         | The magic number, `MXST`                                     |
+//! | 2         | The format version, currently 1                              |
+//! | 4         | The CRC-32 of everything after the header                    |
+//! | 4         | Registers A to D                                             |
+//! | 2         | `pc`                                                         |
+//! | 2         | The address of the last instruction executed                 |
+//! | 1         | 1 if in an interrupt handler, or 0                           |
+//! | 2         | Where the interrupt handler returns to, or 0                 |
+//! | 8         | Cycles executed                                              |
+//! | 8         | Instructions executed                                        |
+//! | 65535     | Memory                                                       |
+//! | 2         | The PPU's scanline                                           |
+//! | 4         | Cycles spent on the scanline so far                          |
+//! | 8         | Frames presented                                             |
+//! | 1         | The framebuffer page being displayed                         |
+//! | 4         | How many pixels the PPU's buffer has                         |
+//! | 4 each    | The pixels                                                   |
+//! | 44        | The APU's channels and clocks, see `ApuState::to_bytes`      |
+
+use crate::apu::ApuState;
+use crate::cpu::MEMORY_SIZE;
+use crate::ppu::{PpuState, SCREEN_HEIGHT, SCREEN_WIDTH, VBLANK_SCANLINES};
+use crate::rom::crc32;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"MXST";
+pub const VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 10;
+
+/// Everything about the machine that changes as it runs. What's outside of it (the window, the
+/// tracer, recordings and so on) isn't included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub registers: [u8; 4],
+    pub memory: Box<[u8; MEMORY_SIZE]>,
+    pub pc: u16,
+    pub cycles: u64,
+    pub instructions: u64,
+    pub interrupt_return: Option<u16>,
+    pub instruction_pc: u16,
+    pub ppu: PpuState,
+    pub apu: ApuState,
+}
+
+/// Why a save state couldn't be loaded
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The file ends before the state does
+    Truncated,
+    /// There are this many bytes left over after the state
+    TrailingBytes(usize),
+    /// The interrupt flag is neither 0 nor 1
+    BadInterruptFlag(u8),
+    /// The PPU's buffer, displayed page or scanline is out of range, as described
+    BadPpuState(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "{}", e),
+            StateError::BadMagic(magic) => write!(
+                f,
+                "Not a save state: it starts with {:02X?} rather than {:02X?}",
+                magic, MAGIC
+            ),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported save state version {} (only version {} is supported)",
+                version, VERSION
+            ),
+            StateError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: the header says {:#010x}, but the state's is {:#010x}; the \
+                 save state is probably corrupt",
+                expected, actual
+            ),
+            StateError::Truncated => write!(f, "The save state ends too soon"),
+            StateError::TrailingBytes(count) => write!(
+                f,
+                "There are {} bytes left over after the save state",
+                count
+            ),
+            StateError::BadInterruptFlag(flag) => {
+                write!(f, "Invalid interrupt flag {:#04x} in the save state", flag)
+            }
+            StateError::BadPpuState(problem) => {
+                write!(f, "Invalid PPU state in the save state: {}", problem)
+            }
+        }
+    }
+}
+
+impl Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+impl Snapshot {
+    /// Reads the save state at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StateError> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Validates and reads a save state from its bytes
+    pub fn parse(bytes: &[u8]) -> Result<Self, StateError> {
+        let mut reader = Reader(bytes);
+
+        let mut magic = [0; 4];
+        magic.copy_from_slice(reader.take(4)?);
+        if &magic != MAGIC {
+            return Err(StateError::BadMagic(magic));
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let expected = reader.u32()?;
+        let actual = crc32(reader.0);
+        if expected != actual {
+            return Err(StateError::ChecksumMismatch { expected, actual });
+        }
+
+        let mut registers = [0; 4];
+        registers.copy_from_slice(reader.take(4)?);
+        let pc = reader.u16()?;
+        let instruction_pc = reader.u16()?;
+        let interrupt_return = match (reader.take(1)?[0], reader.u16()?) {
+            (0, _) => None,
+            (1, address) => Some(address),
+            (flag, _) => return Err(StateError::BadInterruptFlag(flag)),
+        };
+        let cycles = reader.u64()?;
+        let instructions = reader.u64()?;
+
+        let mut memory = Box::new([0; MEMORY_SIZE]);
+        memory.copy_from_slice(reader.take(MEMORY_SIZE)?);
+
+        let scanline = reader.u16()? as usize;
+        let ppu_cycles = reader.u32()?;
+        let frame = reader.u64()?;
+        let front_page = reader.take(1)?[0];
+        let pixels = reader.u32()? as usize;
+
+        // The PPU indexes its buffer and memory with these without checking them
+        let bad = |problem: String| Err(StateError::BadPpuState(problem));
+        if scanline >= SCREEN_HEIGHT + VBLANK_SCANLINES {
+            return bad(format!(
+                "scanline {} is past the end of the frame",
+                scanline
+            ));
+        }
+        if front_page > 1 {
+            return bad(format!("the displayed page is {}, not 0 or 1", front_page));
+        }
+        if pixels != SCREEN_WIDTH * SCREEN_HEIGHT {
+            return bad(format!(
+                "the buffer has {} pixels rather than {}",
+                pixels,
+                SCREEN_WIDTH * SCREEN_HEIGHT
+            ));
+        }
+
+        let buffer = (0..pixels)
+            .map(|_| reader.u32())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut apu = [0; ApuState::SIZE];
+        apu.copy_from_slice(reader.take(ApuState::SIZE)?);
+
+        if !reader.0.is_empty() {
+            return Err(StateError::TrailingBytes(reader.0.len()));
+        }
+
+        Ok(Self {
+            registers,
+            memory,
+            pc,
+            cycles,
+            instructions,
+            interrupt_return,
+            instruction_pc,
+            ppu: PpuState {
+                buffer,
+                scanline,
+                cycles: ppu_cycles,
+                frame,
+                front_page,
+            },
+            apu: ApuState::from_bytes(&apu),
+        })
+    }
+
+    /// The snapshot as a save state, header and all
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let state = self.state_bytes();
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + state.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&crc32(&state).to_be_bytes());
+        bytes.extend_from_slice(&state);
+
+        bytes
+    }
+
+    /// The CRC-32 of the machine's state, which is the same for two snapshots exactly when they
+    /// are (barring collisions)
+    pub fn checksum(&self) -> u32 {
+        crc32(&self.state_bytes())
+    }
+
+    /// Everything after the header in a save state
+    fn state_bytes(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(MEMORY_SIZE + self.ppu.buffer.len() * 4 + 128);
+        state.extend_from_slice(&self.registers);
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        state.extend_from_slice(&self.instruction_pc.to_be_bytes());
+        state.push(self.interrupt_return.is_some() as u8);
+        state.extend_from_slice(&self.interrupt_return.unwrap_or(0).to_be_bytes());
+        state.extend_from_slice(&self.cycles.to_be_bytes());
+        state.extend_from_slice(&self.instructions.to_be_bytes());
+        state.extend_from_slice(&self.memory[..]);
+
+        state.extend_from_slice(&(self.ppu.scanline as u16).to_be_bytes());
+        state.extend_from_slice(&self.ppu.cycles.to_be_bytes());
+        state.extend_from_slice(&self.ppu.frame.to_be_bytes());
+        state.push(self.ppu.front_page);
+        state.extend_from_slice(&(self.ppu.buffer.len() as u32).to_be_bytes());
+        for pixel in &self.ppu.buffer {
+            state.extend_from_slice(&pixel.to_be_bytes());
+        }
+
+        state.extend_from_slice(&self.apu.to_bytes());
+
+        state
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), StateError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+/// Reads a save state from the front, a field at a time
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        if self.0.len() < count {
+            return Err(StateError::Truncated);
+        }
+
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+
+    /// Plays a fading note and some noise, and keeps drawing into the framebuffer
+    const PROGRAM: &str = "\
+LOAD A, 0x01
+STORE [0x0110], A
+LOAD A, 0xB8
+STORE [0x0111], A
+LOAD A, 0x1F
+STORE [0x0112], A
+LOAD A, 0xC2
+STORE [0x0113], A
+LOAD A, 0x40
+STORE [0x011D], A
+LOAD A, 0x0F
+STORE [0x011E], A
+LOAD A, 0xC0
+STORE [0x011F], A
+LOAD A, 1
+loop: ADD B, 3, B
+STORE [0x0205], B
+JT A, loop";
+
+    fn running_cpu() -> CPU {
+        let mut cpu = CPU::headless();
+        cpu.load(assemble(PROGRAM).unwrap());
+        cpu.run_frames(3);
+        cpu
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cpu = running_cpu();
+        let snapshot = cpu.snapshot();
+
+        assert_eq!(Snapshot::parse(&snapshot.to_bytes()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_restore_into_fresh_machine() {
+        let mut cpu = running_cpu();
+        cpu.apu.take_samples();
+        let bytes = cpu.snapshot().to_bytes();
+
+        let mut fresh = CPU::headless();
+        fresh.restore(&Snapshot::parse(&bytes).unwrap());
+        assert_eq!(fresh.snapshot(), cpu.snapshot());
+
+        // Both carry on exactly the same way, sound and all
+        cpu.run_frames(5);
+        fresh.run_frames(5);
+        assert_eq!(fresh.snapshot(), cpu.snapshot());
+        let samples = cpu.apu.take_samples();
+        assert!(samples.iter().any(|&sample| sample != 0));
+        assert!(fresh.apu.take_samples() == samples);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = running_cpu().snapshot().to_bytes();
+        let parse = |bytes: &[u8]| Snapshot::parse(bytes).unwrap_err().to_string();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(
+            parse(&bad_magic),
+            "Not a save state: it starts with [58, 58, 53, 54] rather than [4D, 58, 53, 54]"
+        );
+
+        let mut bad_version = bytes.clone();
+        bad_version[5] = 2;
+        assert_eq!(
+            parse(&bad_version),
+            "Unsupported save state version 2 (only version 1 is supported)"
+        );
+
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_SIZE + 100] ^= 1;
+        assert!(parse(&corrupt).starts_with("Checksum mismatch"));
+
+        assert_eq!(parse(&bytes[..3]), "The save state ends too soon");
+
+        // These have valid checksums, but would make the PPU index out of bounds
+        let snapshot = running_cpu().snapshot();
+        let mut short_buffer = snapshot.clone();
+        short_buffer.ppu.buffer.truncate(10);
+        assert_eq!(
+            parse(&short_buffer.to_bytes()),
+            "Invalid PPU state in the save state: the buffer has 10 pixels rather than 1024"
+        );
+
+        let mut bad_page = snapshot.clone();
+        bad_page.ppu.front_page = 2;
+        assert_eq!(
+            parse(&bad_page.to_bytes()),
+            "Invalid PPU state in the save state: the displayed page is 2, not 0 or 1"
+        );
+
+        let mut bad_scanline = snapshot;
+        bad_scanline.ppu.scanline = 40;
+        assert_eq!(
+            parse(&bad_scanline.to_bytes()),
+            "Invalid PPU state in the save state: scanline 40 is past the end of the frame"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Symbol maps: where each label ended up after linking, saved next to the program as lines of
+//! `ADDR name`, with the address in hex.
+
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolMap {
+    /// Sorted by address, then name
+    symbols: Vec<(u16, String)>,
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        let symbol = (address, name.to_string());
+
+        if let Err(index) = self.symbols.binary_search(&symbol) {
+            self.symbols.insert(index, symbol);
+        }
+    }
+
+    /// Every symbol, in order of address
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.symbols
+            .iter()
+            .map(|(address, name)| (name.as_str(), *address))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The address of the symbol called `name`
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.iter()
+            .find(|(symbol, _)| *symbol == name)
+            .map(|(_, address)| address)
+    }
+
+    /// The closest symbol at or before `address`, and how far past it `address` is
+    pub fn lookup(&self, address: u16) -> Option<(&str, u16)> {
+        let index = self
+            .symbols
+            .partition_point(|(symbol, _)| *symbol <= address);
+
+        self.symbols[..index]
+            .last()
+            .map(|(symbol, name)| (name.as_str(), address - symbol))
+    }
+
+    /// `address` as the closest symbol at or before it, plus how far past it it is if it's not
+    /// right on it, e.g. `loop+0x3`
+    pub fn describe(&self, address: u16) -> Option<String> {
+        self.lookup(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            offset => format!("{}+{:#x}", name, offset),
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for (name, address) in self.iter() {
+            writeln!(out, "{:04X} {}", address, name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a map written by `write`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = Self::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (address, name) = line
+                .split_once(' ')
+                .and_then(|(address, name)| {
+                    Some((u16::from_str_radix(address, 16).ok()?, name.trim()))
+                })
+                .ok_or_else(|| format!("line {}: invalid symbol `{}`", i + 1, line))?;
+
+            map.insert(name, address);
+        }
+
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut map = SymbolMap::new();
+        map.insert("main", 0x8000);
+        map.insert("loop", 0x8010);
+        map.insert("data", 0x9000);
+
+        assert_eq!(map.lookup(0x7FFF), None);
+        assert_eq!(map.lookup(0x8000), Some(("main", 0)));
+        assert_eq!(map.lookup(0x8013), Some(("loop", 3)));
+        assert_eq!(map.lookup(0xFFFF), Some(("data", 0x6FFF)));
+        assert_eq!(map.get("loop"), Some(0x8010));
+        assert_eq!(map.describe(0x8010), Some("loop".to_string()));
+        assert_eq!(map.describe(0x801A), Some("loop+0xa".to_string()));
+        assert_eq!(map.describe(0x7FFF), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut map = SymbolMap::new();
+        map.insert("main", 0x8000);
+        map.insert("handler", 0x9000);
+
+        let mut text = Vec::new();
+        map.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+
+        assert_eq!(text, "8000 main\n9000 handler\n");
+        assert_eq!(SymbolMap::parse(&text), Ok(map));
+        assert_eq!(
+            SymbolMap::parse("8000 main\nnope"),
+            Err("line 2: invalid symbol `nope`".to_string())
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Execution traces: one line for every instruction executed, in a format that stays the same
+//! from run to run, so two traces can be diffed to find where they first diverge.
+//!
+//! Each line has the cycle count, the instruction (as `disasm` shows it) and the registers, all
+//! from just before the instruction runs, and then where the instruction is in the source if the
+//! program's symbol or line map is loaded:
+//!
+//! ```text
+//! 0000000003 8003: 51 00        DEC A                     A=03 B=00 C=00 D=00 ; loop at game.s:4
+//! ```
+
+use crate::debug_info::DebugInfo;
+use crate::disasm::Line;
+use crate::instruction::Instruction;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    /// Only instructions in these ranges (inclusive) are traced, or every instruction if there
+    /// aren't any
+    pub ranges: Vec<(u16, u16)>,
+    /// How many lines have been written so far
+    pub lines: u64,
+}
+
+impl Tracer {
+    /// Writes the trace to `out`
+    pub fn new<W: Write + 'static>(out: W) -> Self {
+        Self {
+            out: Box::new(out),
+            ranges: Vec::new(),
+            lines: 0,
+        }
+    }
+
+    /// Writes the trace to a file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Whether instructions at `pc` are traced
+    pub fn traces(&self, pc: u16) -> bool {
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|&(start, end)| (start..=end).contains(&pc))
+    }
+
+    /// Writes a line for `instruction`, which is at `pc` and is about to be executed.
+    /// `debug_info` says where it is in the source.
+    pub fn trace(
+        &mut self,
+        cycles: u64,
+        pc: u16,
+        instruction: &Instruction,
+        registers: &[u8; 4],
+        debug_info: &DebugInfo,
+    ) -> io::Result<()> {
+        if !self.traces(pc) {
+            return Ok(());
+        }
+
+        let line = Line {
+            address: pc,
+            bytes: instruction.encode(),
+            text: instruction.to_string(),
+        };
+
+        write!(
+            self.out,
+            "{:010} {:<44} A={:02X} B={:02X} C={:02X} D={:02X}",
+            cycles,
+            line.to_string(),
+            registers[0],
+            registers[1],
+            registers[2],
+            registers[3]
+        )?;
+        match debug_info.describe(pc) {
+            Some(description) => writeln!(self.out, " ; {}", description)?,
+            None => writeln!(self.out)?,
+        }
+        self.lines += 1;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("ranges", &self.ranges)
+            .field("lines", &self.lines)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_image;
+    use crate::cpu::CPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Somewhere to write a trace that the test can still read afterwards
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Traces a short loop, with its symbol and line maps loaded if `debug_info` is set
+    fn trace(ranges: Vec<(u16, u16)>, debug_info: bool) -> String {
+        let output = Shared::default();
+        let image =
+            assemble_image("LOAD A, 2\nloop: DEC A\nJT A, loop\nSTORE [0x0200], A").unwrap();
+
+        let mut cpu = CPU::headless();
+        cpu.load(image.data);
+        if debug_info {
+            cpu.debug_info = DebugInfo::new(image.symbols, image.lines);
+        }
+
+        let mut tracer = Tracer::new(output.clone());
+        tracer.ranges = ranges;
+        cpu.tracer = Some(tracer);
+        cpu.run();
+
+        let bytes = output.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_trace() {
+        assert_eq!(
+            trace(Vec::new(), false),
+            "\
+0000000000 8000: 10 00 02     LOAD A, 0x02              A=00 B=00 C=00 D=00
+0000000003 8003: 51 00        DEC A                     A=02 B=00 C=00 D=00
+0000000005 8005: 40 00 80 03  JT A, 0x8003              A=01 B=00 C=00 D=00
+0000000009 8003: 51 00        DEC A                     A=01 B=00 C=00 D=00
+0000000011 8005: 40 00 80 03  JT A, 0x8003              A=00 B=00 C=00 D=00
+0000000015 8009: 20 02 00 00  STORE 0x0200, A           A=00 B=00 C=00 D=00
+0000000020 800D: 00           HALT                      A=00 B=00 C=00 D=00
+"
+        );
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(
+            trace(vec![(0x8003, 0x8004), (0x800D, 0x800D)], false),
+            "\
+0000000003 8003: 51 00        DEC A                     A=02 B=00 C=00 D=00
+0000000009 8003: 51 00        DEC A                     A=01 B=00 C=00 D=00
+0000000020 800D: 00           HALT                      A=00 B=00 C=00 D=00
+"
+        );
+    }
+
+    #[test]
+    fn test_debug_info() {
+        assert_eq!(
+            trace(vec![(0x8003, 0x8005)], true),
+            "\
+0000000003 8003: 51 00        DEC A                     A=02 B=00 C=00 D=00 ; loop at line 2
+0000000005 8005: 40 00 80 03  JT A, 0x8003              A=01 B=00 C=00 D=00 ; loop+0x2 at line 3
+0000000009 8003: 51 00        DEC A                     A=01 B=00 C=00 D=00 ; loop at line 2
+0000000011 8005: 40 00 80 03  JT A, 0x8003              A=00 B=00 C=00 D=00 ; loop+0x2 at line 3
+"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Watchpoints, which notice when instructions touch part of memory. They're checked in
+//! `CPU::mem_read` and `CPU::mem_write`, so they see every access an instruction makes, but not
+//! instruction fetches or the registers the PPU and APU update themselves.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// A write that changes the value
+    Change,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub start: u16,
+    /// The last address watched, so a range can reach the end of memory
+    pub end: u16,
+}
+
+/// An access that a watchpoint noticed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The index of the watchpoint in `CPU::watchpoints`
+    pub watchpoint: usize,
+    pub kind: WatchKind,
+    /// The address the instruction used. With double buffering on, this can be different from
+    /// where the value actually is.
+    pub address: u16,
+    /// The value before the access, which is the value read for a read
+    pub old: u8,
+    /// The value after the access
+    pub new: u8,
+    /// The address of the instruction that made the access
+    pub pc: u16,
+}
+
+impl WatchKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(WatchKind::Read),
+            "write" => Some(WatchKind::Write),
+            "change" => Some(WatchKind::Change),
+            _ => None,
+        }
+    }
+}
+
+impl Watchpoint {
+    pub fn new(kind: WatchKind, start: u16, end: u16) -> Self {
+        Self { kind, start, end }
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    /// Whether an access of `kind` to `address` (or `physical`, where it really went), going from
+    /// `old` to `new`, should be noticed
+    pub fn matches(&self, kind: WatchKind, address: u16, physical: u16, old: u8, new: u8) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => kind == WatchKind::Read,
+            WatchKind::Write => kind == WatchKind::Write,
+            WatchKind::Change => kind == WatchKind::Write && old != new,
+        };
+
+        kind_matches && (self.contains(address) || self.contains(physical))
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{} {:#06x}", self.kind.name(), self.start)
+        } else {
+            write!(
+                f,
+                "{} {:#06x}-{:#06x}",
+                self.kind.name(),
+                self.start,
+                self.end
+            )
+        }
+    }
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Watchpoint {}: ", self.watchpoint)?;
+
+        match self.kind {
+            WatchKind::Read => write!(f, "read {:#04x} from {:#06x}", self.old, self.address)?,
+            WatchKind::Write => write!(
+                f,
+                "wrote {:#04x} to {:#06x} (was {:#04x})",
+                self.new, self.address, self.old
+            )?,
+            WatchKind::Change => write!(
+                f,
+                "{:#06x} changed from {:#04x} to {:#04x}",
+                self.address, self.old, self.new
+            )?,
+        }
+
+        write!(f, " at {:#06x}", self.pc)
+    }
+}